@@ -0,0 +1,183 @@
+use oasysdb::prelude::*;
+use ollama_rs::history::{MessagesHistory, WrappedMessageHistory};
+use std::io::{BufRead, Write};
+
+use crate::filter::Filter;
+use crate::generator::{Generator, GenOptions, Message, Role};
+use crate::manifest::Manifest;
+use crate::ollamagen::{from_chat_message, to_chat_message};
+use crate::rag::{assemble_prompt, rewrite_messages, standalone_query};
+use crate::reranker::{num_candidates, rerank};
+use crate::retriever::{expand_window, hybrid_search, vector_search, RetrievedChunk};
+
+// =====================================================================
+// Interactive chat. The model stays loaded between the questions, and
+// the earlier questions and answers are sent along with every new
+// question. The history is an ollama-rs chat history, which Minerva
+// fills itself (with the questions, not the contexts) and converts to
+// messages, so it works the same for all backends.
+// =====================================================================
+
+const HISTORY_ID: &str = "chat";
+// The last ten questions and answers.
+const HISTORY_LIMIT: u16 = 20;
+
+const HELP: &str = "Commands:
+  /sources   Show the chunks retrieved for the last question.
+  /context   Show the context sent with the last question.
+  /history   Show the conversation so far.
+  /reset     Forget the conversation.
+  /help      Show this text.
+  /quit      Stop.";
+
+pub struct ChatConfig {
+    pub nearest: usize,
+    pub window: usize,
+    pub rerank: bool,
+    pub candidates: usize,
+    pub filter: Filter,
+    pub minsim: f32,
+    pub hybrid: bool,
+    pub showprompt: bool,
+    pub opts: GenOptions,
+}
+
+// The history starts with a question, the templates expect the turns to
+// alternate, and assemble_prompt() drops them two at a time.
+fn history_messages(history: &WrappedMessageHistory) -> Vec<Message> {
+    let history = history.read().unwrap_or_else(|e| e.into_inner());
+    let messages: Vec<Message> = history.get_messages(HISTORY_ID).map(|messages| messages.iter().map(from_chat_message).collect()).unwrap_or_default();
+    let start = messages.iter().position(|m| m.role == Role::User).unwrap_or(messages.len());
+    messages[start..].to_vec()
+}
+
+fn add_to_history(history: &WrappedMessageHistory, message: &Message) {
+    history.write().unwrap_or_else(|e| e.into_inner()).add_message(HISTORY_ID, to_chat_message(message));
+}
+
+// What was retrieved for the last question.
+#[derive(Default)]
+struct LastTurn {
+    query: String,
+    chunks: Vec<RetrievedChunk>,
+    context: String,
+}
+
+pub fn chat(cfg: &ChatConfig, collection: &Collection, manifest: &Manifest, generator: &mut dyn Generator) -> anyhow::Result<()> {
+    println!("Chatting with {}, type /help for help.", generator.name());
+
+    let history = MessagesHistory::new(HISTORY_LIMIT);
+    let mut last = LastTurn::default();
+    let stdin = std::io::stdin();
+
+    loop {
+        print!("\n> ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break; // EOF
+        }
+        let question = line.trim();
+        if question.is_empty() {
+            continue;
+        }
+
+        match question {
+            "/quit" | "/exit" => break,
+            "/help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            "/reset" => {
+                history.write().unwrap_or_else(|e| e.into_inner()).clear_messages_for_id(HISTORY_ID);
+                last = LastTurn::default();
+                println!("Conversation reset.");
+                continue;
+            }
+            "/sources" => {
+                println!("Query: {}", last.query);
+                for res in &last.chunks {
+                    let sim = res.similarity.map_or("-".to_string(), |d| format!("{d:.4}"));
+                    let bm25 = res.bm25.map_or("-".to_string(), |s| format!("{s:.4}"));
+                    let rerank = res.rerank.map_or("-".to_string(), |s| format!("{s:.4}"));
+                    println!("{} | {:?} sim:{} bm25:{} rerank:{}", res.label(), res.source, sim, bm25, rerank);
+                }
+                continue;
+            }
+            "/context" => {
+                println!("{}", last.context);
+                continue;
+            }
+            "/history" => {
+                for message in history_messages(&history) {
+                    println!("{:?}: {}", message.role, message.content);
+                }
+                continue;
+            }
+            _ if question.starts_with('/') => {
+                println!("Unknown command \"{}\".\n{}", question, HELP);
+                continue;
+            }
+            _ => {}
+        }
+
+        // A failed search or answer (a backend which is down, say) ends
+        // the question, not the session.
+        match answer(cfg, collection, manifest, generator, &history_messages(&history), question) {
+            Ok((answer, turn)) => {
+                // Only the question and the answer are remembered, not the
+                // context. The history drops empty messages, so a question
+                // without an answer is not remembered at all.
+                if !answer.trim().is_empty() {
+                    add_to_history(&history, &Message::user(&format!("Question: {}", question)));
+                    add_to_history(&history, &Message::assistant(&answer));
+                }
+                last = turn;
+            }
+            Err(e) => println!("\nError: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+// Retrieves the context for a question and streams the answer.
+fn answer(cfg: &ChatConfig, collection: &Collection, manifest: &Manifest, generator: &mut dyn Generator,
+          history: &[Message], question: &str) -> anyhow::Result<(String, LastTurn)> {
+    // A follow-up like "and how old is she?" is useless as a query,
+    // let the model rewrite it with the help of the conversation.
+    let query = if history.is_empty() {
+        question.to_string()
+    } else {
+        let rewrite_opts = GenOptions { max_tokens: Some(100), temperature: Some(0.0), ..cfg.opts.clone() };
+        let rewritten = generator.generate(&rewrite_messages(history, question), &rewrite_opts, &mut |_| {})?;
+        let query = standalone_query(&rewritten, question);
+        println!("Searching for \"{}\"", query);
+        query
+    };
+
+    let k = num_candidates(cfg.nearest, cfg.rerank, cfg.candidates);
+    let chunks = if cfg.hybrid {
+        hybrid_search(collection, manifest, &query, k, cfg.minsim, &cfg.filter)?
+    } else {
+        vector_search(collection, manifest, &query, k, cfg.minsim, &cfg.filter)?
+    };
+    let chunks = if cfg.rerank { rerank(&query, chunks, cfg.nearest)? } else { chunks };
+    let chunks = expand_window(collection, manifest, chunks, cfg.window)?;
+    let prompt = assemble_prompt(generator, cfg.opts.max_tokens, chunks, "", history, question);
+    let messages = prompt.messages;
+    if cfg.showprompt {
+        for message in &messages {
+            println!("\n{:?}: {}", message.role, message.content);
+        }
+        println!();
+    }
+
+    let answer = generator.generate(&messages, &cfg.opts, &mut |token| {
+        print!("{}", token);
+        let _ = std::io::stdout().flush();
+    })?;
+    println!();
+
+    Ok((answer, LastTurn { query, chunks: prompt.sources, context: prompt.context }))
+}
@@ -0,0 +1,238 @@
+use std::fmt;
+use std::str::FromStr;
+use text_splitter::{ChunkConfig, TextSplitter};
+use tokenizers::Tokenizer;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::embedder::embedding_tokenizer;
+use crate::extract::{markdown_sections, Part};
+
+// =====================================================================
+// Chunking strategies. The size (and the overlap) is counted in
+// characters, except for the tokens strategy, which counts the tokens
+// of the tokenizer of the embedding model, so a chunk can be made to
+// fit the model exactly.
+//
+//   chars      text-splitter, at the largest boundary (paragraph,
+//              sentence, word) which fits.
+//   tokens     as chars, but measured in tokens.
+//   markdown   split at the headings first, a chunk never crosses one.
+//   sentences  whole sentences, the overlap repeats whole sentences.
+// =====================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Chars,
+    Tokens,
+    Markdown,
+    Sentences,
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "chars" | "characters" => Ok(Strategy::Chars),
+            "tokens" => Ok(Strategy::Tokens),
+            "markdown" => Ok(Strategy::Markdown),
+            "sentences" => Ok(Strategy::Sentences),
+            _ => anyhow::bail!("Unknown chunk strategy \"{}\", choose from chars, tokens, markdown or sentences.", s),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Strategy::Chars => "chars",
+            Strategy::Tokens => "tokens",
+            Strategy::Markdown => "markdown",
+            Strategy::Sentences => "sentences",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// The splitter may make a chunk this much smaller or larger than the
+// chunk size, to end it at a better boundary.
+const SLACK: usize = 25;
+
+#[derive(Clone)]
+pub struct Chunker {
+    pub strategy: Strategy,
+    pub size: usize,
+    pub overlap: usize,
+    tokenizer: Option<&'static Tokenizer>,
+}
+
+impl Chunker {
+    /// The tokens strategy loads the embedding model, for its tokenizer.
+    pub fn new(strategy: Strategy, size: usize, overlap: usize) -> anyhow::Result<Self> {
+        if size == 0 {
+            anyhow::bail!("The chunk size must be larger than 0.");
+        }
+        if overlap >= size {
+            anyhow::bail!("The overlap ({}) must be smaller than the chunk size ({}).", overlap, size);
+        }
+        let tokenizer = match strategy {
+            Strategy::Tokens => Some(embedding_tokenizer()?),
+            _ => None,
+        };
+        Ok(Chunker { strategy, size, overlap, tokenizer })
+    }
+
+    // Characters: size-SLACK..size+SLACK like before, but never below 1
+    // (small chunk sizes used to underflow), and never smaller than the
+    // overlap. Tokens: at most size, the limit of the model is hard.
+    fn config(&self) -> ChunkConfig<text_splitter::Characters> {
+        let min = self.size.saturating_sub(SLACK).max(self.overlap + 1);
+        ChunkConfig::new(min..self.size + SLACK)
+            .with_overlap(self.overlap)
+            .expect("overlap is checked in Chunker::new")
+    }
+
+    /// Splits a text into chunks.
+    pub fn chunks(&self, text: &str) -> Vec<String> {
+        match (self.strategy, self.tokenizer) {
+            (Strategy::Tokens, Some(tokenizer)) => {
+                let config = ChunkConfig::new(self.size)
+                    .with_overlap(self.overlap)
+                    .expect("overlap is checked in Chunker::new")
+                    .with_sizer(tokenizer);
+                TextSplitter::new(config).chunks(text).map(|v| v.to_string()).collect()
+            }
+            (Strategy::Sentences, _) => self.sentence_chunks(text),
+            _ => TextSplitter::new(self.config()).chunks(text).map(|v| v.to_string()).collect(),
+        }
+    }
+
+    /// Chunks the parts of an extracted file, every chunk keeps the
+    /// page and section of its part. The markdown strategy first splits
+    /// the parts which have no section yet at their headings.
+    pub fn chunk_parts(&self, parts: Vec<Part>) -> Vec<Part> {
+        let parts = if self.strategy == Strategy::Markdown {
+            parts.into_iter()
+                .flat_map(|part| {
+                    if part.section.is_some() {
+                        vec![part]
+                    } else {
+                        markdown_sections(&part.text).into_iter().map(|s| Part { page: part.page, ..s }).collect()
+                    }
+                })
+                .collect()
+        } else {
+            parts
+        };
+        parts.into_iter()
+            .flat_map(|part| {
+                self.chunks(&part.text)
+                    .into_iter()
+                    .map(move |text| Part { text, ..part.clone() })
+            })
+            .collect()
+    }
+
+    // Whole sentences up to the chunk size. The next chunk starts with
+    // the last sentences of the previous one, as many as fit in the
+    // overlap. A sentence longer than the chunk size is split on its own.
+    fn sentence_chunks(&self, text: &str) -> Vec<String> {
+        let mut sentences = vec![];
+        for sentence in text.unicode_sentences() {
+            let sentence = sentence.trim();
+            if sentence.is_empty() {
+                continue;
+            }
+            if sentence.chars().count() > self.size {
+                sentences.extend(TextSplitter::new(self.config()).chunks(sentence).map(|v| v.to_string()));
+            } else {
+                sentences.push(sentence.to_string());
+            }
+        }
+
+        let mut chunks = vec![];
+        let mut current: Vec<&str> = vec![];
+        let mut len = 0;
+        let mut fresh = 0; // Sentences not in the previous chunk.
+        for sentence in &sentences {
+            let n = sentence.chars().count();
+            if !current.is_empty() && len + 1 + n > self.size {
+                chunks.push(current.join(" "));
+                let mut kept = vec![];
+                let mut kept_len = 0;
+                for s in current.iter().rev() {
+                    let m = s.chars().count();
+                    if kept_len + m + 1 > self.overlap || kept_len + m + 1 + n > self.size {
+                        break;
+                    }
+                    kept.insert(0, *s);
+                    kept_len += m + 1;
+                }
+                len = kept_len.saturating_sub(1);
+                current = kept;
+                fresh = 0;
+            }
+            len += if current.is_empty() { n } else { n + 1 };
+            current.push(sentence);
+            fresh += 1;
+        }
+        if fresh > 0 {
+            chunks.push(current.join(" "));
+        }
+        chunks
+    }
+}
+
+// =====================================================================
+// Tests.
+// =====================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "the quick brown fox jumps over the lazy dog. And another sentence. Seven!";
+
+    #[test]
+    fn parse_strategy() {
+        assert_eq!("Markdown".parse::<Strategy>().unwrap(), Strategy::Markdown);
+        assert_eq!("sentences".parse::<Strategy>().unwrap().to_string(), "sentences");
+        assert!("words".parse::<Strategy>().is_err());
+        assert!(Chunker::new(Strategy::Chars, 100, 100).is_err());
+        assert!(Chunker::new(Strategy::Chars, 0, 0).is_err());
+    }
+
+    #[test]
+    fn small_chunk_size() {
+        let chunker = Chunker::new(Strategy::Chars, 10, 0).unwrap();
+        let chunks = chunker.chunks(TEXT);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.len() < 10 + SLACK));
+    }
+
+    #[test]
+    fn chars_overlap() {
+        let text = "one two three four five six seven eight nine ten eleven twelve thirteen fourteen";
+        let chunker = Chunker::new(Strategy::Chars, 30, 10).unwrap();
+        let chunks = chunker.chunks(text);
+        assert!(chunks.windows(2).any(|w| w[0].split_whitespace().last() == w[1].split_whitespace().next()));
+    }
+
+    #[test]
+    fn sentence_windows() {
+        let chunker = Chunker::new(Strategy::Sentences, 70, 0).unwrap();
+        assert_eq!(chunker.chunks(TEXT), vec!["the quick brown fox jumps over the lazy dog. And another sentence.", "Seven!"]);
+        let chunker = Chunker::new(Strategy::Sentences, 70, 30).unwrap();
+        assert_eq!(chunker.chunks(TEXT), vec!["the quick brown fox jumps over the lazy dog. And another sentence.", "And another sentence. Seven!"]);
+    }
+
+    #[test]
+    fn markdown_headings() {
+        let parts = vec![Part { page: 0, section: None, text: "# Cats\nSirius.\n## Names\nSirius is black.\n# Dogs\nNone.\n".to_string() }];
+        let chunker = Chunker::new(Strategy::Markdown, 1024, 0).unwrap();
+        let sections: Vec<_> = chunker.chunk_parts(parts.clone()).into_iter().map(|p| p.section.unwrap()).collect();
+        assert_eq!(sections, vec!["Cats", "Cats > Names", "Dogs"]);
+        let chunker = Chunker::new(Strategy::Chars, 1024, 0).unwrap();
+        assert_eq!(chunker.chunk_parts(parts).len(), 1);
+    }
+}
@@ -1,5 +1,9 @@
 use oasysdb::prelude::*;
 use fastembed::{Embedding};
+use crate::embedder::embeddings;
+use crate::chunker::Chunker;
+use crate::ingest::Chunk;
+use crate::kb::oasys_path;
 use std::collections::HashMap;
 use ulid::Ulid;
 
@@ -19,30 +23,120 @@ let record = Record::new(&v, &m0);
 */
 
 /// Takes an embedding (for a chunk) and the chunk text.
-pub fn data_to_record(emb: &Embedding, filename: &str, txt: &str, cnt: usize) -> Record {
+pub fn data_to_record(emb: &Embedding, filename: &str, txt: &str, cnt: usize, page: Option<u64>, section: Option<&str>) -> Record {
     let vector = Vector((emb).to_vec());
     let md_ulid = Metadata::Text(Ulid::new().to_string());
     let md_ccnt = Metadata::Integer(cnt);
     let md_date = Metadata::Text(chrono::Local::now().format("%Y%m%dT%H%M").to_string());
     let md_text = Metadata::Text(txt.to_string());
     let md_file = Metadata::Text(filename.to_string());
-    let hm = HashMap::from([
+    let mut hm = HashMap::from([
         ("ulid".to_string(), md_ulid),
         ("ccnt".to_string(), md_ccnt),
         ("date".to_string(), md_date),
         ("text".to_string(), md_text),
         ("filename".to_string(), md_file)
     ]);
+    if let Some(page) = page {
+        hm.insert("page".to_string(), Metadata::Integer(page as usize));
+    }
+    if let Some(section) = section {
+        hm.insert("section".to_string(), Metadata::Text(section.to_string()));
+    }
     let metadata = Metadata::Object(hm);
     Record::new(&vector, &metadata)
 }
 
-pub fn get_db() -> Database {
-    //let args = Args::parse(); // Should not be here, have function args instead.
-    let db = Database::open("db/oasys").unwrap();
+/// Embeds the chunks of a file and turns them into records, with the
+/// same chunk numbers as in the text database. The records remember
+/// how they were chunked.
+pub fn chunks_to_records(chunks: &[Chunk], chunker: &Chunker) -> anyhow::Result<Vec<Record>> {
+    let vectors = embeddings(chunks.iter().map(|c| c.text.as_str()).collect())?;
+    Ok(chunks.iter().zip(vectors.iter())
+       .map(|(chunk, vector)| {
+           let mut record = data_to_record(vector, &chunk.filename, &chunk.text, chunk.number as usize, chunk.page, chunk.section.as_deref());
+           if let Metadata::Object(hm) = &mut record.data {
+               hm.insert("strategy".to_string(), Metadata::Text(chunker.strategy.to_string()));
+               hm.insert("chunk_size".to_string(), Metadata::Integer(chunker.size));
+               hm.insert("overlap".to_string(), Metadata::Integer(chunker.overlap));
+           }
+           record
+       })
+       .collect())
+}
+
+// Function to convert Metadata of type Object back into a HashMap
+pub fn md_to_hashmap(metadata: &Metadata) -> Option<HashMap<String, Metadata>> {
+    match metadata {
+        Metadata::Object(hm) => Some(hm.clone()),
+        _ => None
+    }
+}
+
+pub fn md_to_str(metadata: &Metadata) -> Option<String> {
+    match metadata {
+        Metadata::Text(txt) => Some(txt.to_string()),
+        Metadata::Integer(i) => Some(i.to_string()),
+        _ => None,
+    }
+}
+
+/// Metadata as JSON, with the Object maps as JSON objects.
+pub fn md_to_json(metadata: &Metadata) -> serde_json::Value {
+    match metadata {
+        Metadata::Text(txt) => serde_json::Value::from(txt.as_str()),
+        Metadata::Integer(i) => serde_json::Value::from(*i),
+        Metadata::Float(f) => serde_json::Value::from(*f),
+        Metadata::Boolean(b) => serde_json::Value::from(*b),
+        Metadata::Array(array) => serde_json::Value::Array(array.iter().map(md_to_json).collect()),
+        Metadata::Object(hm) => serde_json::Value::Object(hm.iter().map(|(k, v)| (k.clone(), md_to_json(v))).collect()),
+    }
+}
+
+/// The inverse of md_to_json(); whole numbers become integers.
+pub fn json_to_md(value: &serde_json::Value) -> anyhow::Result<Metadata> {
+    Ok(match value {
+        serde_json::Value::String(txt) => Metadata::Text(txt.clone()),
+        serde_json::Value::Number(n) => match n.as_u64() {
+            Some(i) => Metadata::Integer(i as usize),
+            None => Metadata::Float(n.as_f64().unwrap_or(0.0) as f32),
+        },
+        serde_json::Value::Bool(b) => Metadata::Boolean(*b),
+        serde_json::Value::Array(array) => Metadata::Array(array.iter().map(json_to_md).collect::<anyhow::Result<_>>()?),
+        serde_json::Value::Object(map) => Metadata::Object(map.iter().map(|(k, v)| Ok((k.clone(), json_to_md(v)?))).collect::<anyhow::Result<_>>()?),
+        serde_json::Value::Null => anyhow::bail!("Metadata cannot be null."),
+    })
+}
+
+/// Parses the name of a distance metric, "euclidean" or "cosine".
+pub fn parse_distance(name: &str) -> anyhow::Result<Distance> {
+    Distance::from(&name.to_lowercase())
+        .map_err(|_| anyhow::anyhow!("Unknown distance \"{}\", choose from euclidean or cosine.", name))
+}
+
+pub fn distance_name(distance: &Distance) -> String {
+    match distance {
+        Distance::Euclidean => "euclidean".to_string(),
+        Distance::Cosine => "cosine".to_string(),
+    }
+}
+
+/// Converts a distance to a similarity between 0 (unrelated) and 1
+/// (identical), so one cutoff works for both metrics. The fastembed
+/// embeddings are normalised, which gives euclidean^2 = 2 - 2 * cosine.
+pub fn similarity(distance: f32, metric: &Distance) -> f32 {
+    let cosine = match metric {
+        Distance::Euclidean => 1.0 - distance * distance / 2.0,
+        Distance::Cosine => 1.0 - distance,
+    };
+    cosine.clamp(0.0, 1.0)
+}
+
+pub fn get_db() -> anyhow::Result<Database> {
+    let db = Database::open(&oasys_path().to_string_lossy())?;
     // let collection = db.get_collection("vectors").unwrap();
     println!("DB contains {} collections.", db.len());
-    db
+    Ok(db)
 }
 
 pub fn _save_db(db: &mut Database) {
@@ -74,3 +168,24 @@ pub fn _delete_collection(db: &mut Database, name: &str) {
     //db.save_collection("vectors", &collection).unwrap();
     let collection = db.get_collection(&args.collection).unwrap();
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similarity_same_for_both_metrics() {
+        // Two unit vectors at 60 degrees, cosine 0.5.
+        let euclidean = 1.0f32; // sqrt(2 - 2 * 0.5)
+        let cosine = 0.5f32;
+        assert!((similarity(euclidean, &Distance::Euclidean) - 0.5).abs() < 1e-6);
+        assert!((similarity(cosine, &Distance::Cosine) - 0.5).abs() < 1e-6);
+        assert_eq!(similarity(2.0, &Distance::Euclidean), 0.0);
+    }
+
+    #[test]
+    fn parse_distance_names() {
+        assert_eq!(parse_distance("Cosine").unwrap(), Distance::Cosine);
+        assert!(parse_distance("manhattan").is_err());
+    }
+}
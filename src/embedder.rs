@@ -4,6 +4,12 @@ use text_splitter::{TextSplitter};
 use std::path::PathBuf;
 use std::fs::read_dir;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use once_cell::sync::OnceCell;
+use tokenizers::Tokenizer;
+use crate::chunker::Chunker;
+use crate::extract::{extract, find_extractor, Part};
 
 // Chunk around whitespace, try to get the number of characters close
 // to the suggested chunk_size.
@@ -46,14 +52,14 @@ text_splitter = RecursiveCharacterTextSplitter(
 )
 */
 pub fn chunk_string(text: &str, max_len: usize) -> Vec<String> {
-    // Maximum number of characters in a chunk
-    let max_characters = max_len-25..max_len+25; //225..275;
+    // Maximum number of characters in a chunk, at least 1 for small max_len.
+    let max_characters = max_len.saturating_sub(25).max(1)..max_len+25; //225..275;
     let splitter = TextSplitter::new(max_characters);
 
     splitter.chunks(text).map(|v| v.to_string()).collect()
 }
 
-// Return a vector with filenames with correct extension.
+// Return a vector with the filenames of the files we can read.
 pub fn read_dir_contents<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<PathBuf>> {
     // Read the directory
     let mut file_paths = Vec::new();
@@ -62,10 +68,8 @@ pub fn read_dir_contents<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<PathBuf>
         let entry = entry?;
         let path = entry.path();
         if path.is_file() {
-            if let Some(ext) = path.extension() {
-                if ext == "xml" || ext == "txt" || ext == "md" {
-                    file_paths.push(path);
-                }
+            if find_extractor(&path).is_some() {
+                file_paths.push(path);
             }
         } else if path.is_dir() { // Meander down into sub-directories.
             println!("Dir {:?}", path);
@@ -82,27 +86,112 @@ pub fn embed_file_txt<P: AsRef<Path>>(path: P, chunk_size: usize) -> anyhow::Res
     Ok(chunk_string(&contents, chunk_size))
 }
 
-pub fn embed_file_pdf<P: AsRef<Path>>(path: P, chunk_size: usize) -> anyhow::Result<Vec<String>> {
-    let bytes = std::fs::read(path).unwrap();
-    let out = pdf_extract::extract_text_from_mem(&bytes).unwrap();
-    Ok(chunk_string(&out, chunk_size))
+/// Extracts the text of a file, with the extractor for its format, and
+/// chunks every part (page, section, row) of it separately. The chunks
+/// keep the page number and section of their part.
+pub fn chunk_file<P: AsRef<Path>>(path: P, chunker: &Chunker) -> anyhow::Result<Vec<Part>> {
+    Ok(chunker.chunk_parts(extract(path.as_ref())?))
 }
 
-pub fn embeddings<S: AsRef<str> + Send + Sync>(texts: Vec<S>) -> anyhow::Result<Vec<Embedding>> {
-    // Instantiate the model.
-    let model = TextEmbedding::try_new(InitOptions {
-        model_name: EmbeddingModel::AllMiniLML6V2,
+// The embedding model, chosen once before the first embedding is made.
+static EMBEDDING_MODEL: OnceCell<EmbeddingModel> = OnceCell::new();
+
+/// Selects the embedding model, by fastembed name ("MultilingualE5Small")
+/// or model code ("intfloat/multilingual-e5-small"). Must be called
+/// before the first call to embeddings().
+pub fn set_embedding_model(name: &str) -> anyhow::Result<()> {
+    let model = parse_embedding_model(name)?;
+    if EMBEDDING_MODEL.set(model.clone()).is_err() && EMBEDDING_MODEL.get() != Some(&model) {
+        anyhow::bail!("The embedding model has already been chosen.");
+    }
+    Ok(())
+}
+
+pub fn parse_embedding_model(name: &str) -> anyhow::Result<EmbeddingModel> {
+    let wanted = name.to_lowercase();
+    TextEmbedding::list_supported_models()
+        .into_iter()
+        .find(|info| format!("{:?}", info.model).to_lowercase() == wanted || info.model_code.to_lowercase() == wanted)
+        .map(|info| info.model)
+        .ok_or_else(|| anyhow::anyhow!("Unknown embedding model \"{}\", choose from {:?}.", name, embedding_model_names()))
+}
+
+pub fn embedding_model_names() -> Vec<String> {
+    TextEmbedding::list_supported_models().iter().map(|info| format!("{:?}", info.model)).collect()
+}
+
+fn embedding_model() -> EmbeddingModel {
+    EMBEDDING_MODEL.get().cloned().unwrap_or(EmbeddingModel::AllMiniLML6V2)
+}
+
+/// Name of the current embedding model, as stored in the collection settings.
+pub fn get_embedding_model_name() -> String {
+    format!("{:?}", embedding_model())
+}
+
+// The model is loaded once, the first time it is used, and then shared.
+lazy_static! {
+    pub static ref EMBEDDER: TextEmbedding = TextEmbedding::try_new(InitOptions {
+        model_name: embedding_model(),
         show_download_progress: true,
         ..Default::default()
     }).expect("Cannot Initialise model.");
+}
+
+// Number of texts sent through the model at the same time, 0 means
+// the fastembed default (256).
+static BATCH_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_batch_size(batch_size: usize) {
+    BATCH_SIZE.store(batch_size, Ordering::Relaxed);
+}
+
+fn batch_size() -> Option<usize> {
+    match BATCH_SIZE.load(Ordering::Relaxed) {
+        0 => None,
+        n => Some(n),
+    }
+}
 
+pub fn embeddings<S: AsRef<str> + Send + Sync>(texts: Vec<S>) -> anyhow::Result<Vec<Embedding>> {
     // Generate embeddings.
-    let embeddings = model.embed(texts, None).expect("Cannot create embeddings.");
+    let embeddings = EMBEDDER.embed(texts, batch_size())?;
     Ok(embeddings)
 }
 
+// The tokenizer of the embedding model, without the truncation and the
+// padding, so it counts all the tokens of a text.
+static TOKENIZER: OnceCell<Tokenizer> = OnceCell::new();
+
+pub fn embedding_tokenizer() -> anyhow::Result<&'static Tokenizer> {
+    TOKENIZER.get_or_try_init(|| {
+        let mut tokenizer = EMBEDDER.tokenizer.clone();
+        tokenizer.with_truncation(None).map_err(anyhow::Error::msg)?;
+        tokenizer.with_padding(None);
+        Ok(tokenizer)
+    })
+}
+
+pub fn count_tokens(text: &str) -> anyhow::Result<usize> {
+    let encoding = embedding_tokenizer()?.encode(text, true).map_err(anyhow::Error::msg)?;
+    Ok(encoding.len())
+}
+
+/// The number of tokens the embedding model looks at, the rest of a
+/// chunk is silently cut off. The MiniLM models were trained on shorter
+/// texts than their tokenizers allow.
+pub fn max_embedding_tokens() -> usize {
+    let truncation = EMBEDDER.tokenizer.get_truncation().map(|t| t.max_length).unwrap_or(512);
+    match embedding_model() {
+        EmbeddingModel::AllMiniLML6V2 | EmbeddingModel::AllMiniLML6V2Q |
+        EmbeddingModel::AllMiniLML12V2 | EmbeddingModel::AllMiniLML12V2Q => truncation.min(256),
+        EmbeddingModel::ParaphraseMLMiniLML12V2 | EmbeddingModel::ParaphraseMLMiniLML12V2Q => truncation.min(128),
+        _ => truncation,
+    }
+}
+
 pub fn get_embedding_dim() -> anyhow::Result<usize> {
-    let test_model_info = TextEmbedding::get_model_info(&EmbeddingModel::AllMiniLML6V2);
+    let test_model_info = TextEmbedding::get_model_info(&embedding_model());
     Ok(test_model_info.dim)
 }
     
@@ -177,4 +266,29 @@ mod tests {
         assert!(result[1] == "And another sentence.");
         assert!(result[2] == "Seven!");
     }
+
+    #[test]
+    fn chunk_small_sizes() {
+        let text = "the quick brown fox jumps over the lazy dog. And another sentence. Seven!".to_string();
+        for max_len in [1, 10, 24, 25] {
+            assert!(!chunk_string(&text, max_len).is_empty());
+        }
+    }
+
+    #[test]
+    fn parse_model_names() {
+        assert_eq!(parse_embedding_model("MultilingualE5Small").unwrap(), EmbeddingModel::MultilingualE5Small);
+        assert_eq!(parse_embedding_model("intfloat/multilingual-e5-small").unwrap(), EmbeddingModel::MultilingualE5Small);
+        assert_eq!(parse_embedding_model("allminilml6v2").unwrap(), EmbeddingModel::AllMiniLML6V2);
+        assert!(parse_embedding_model("word2vec").is_err());
+    }
+
+    #[test]
+    fn batch_size_default() {
+        set_batch_size(0);
+        assert_eq!(batch_size(), None);
+        set_batch_size(32);
+        assert_eq!(batch_size(), Some(32));
+        set_batch_size(0);
+    }
 }
@@ -0,0 +1,605 @@
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+// =====================================================================
+// Text extraction. Every supported format has an extractor, found by
+// the extension of the file, or by looking at the first bytes if the
+// extension is missing or unknown. An extractor returns the text in
+// parts: the pages of a PDF, the sections of a Markdown file, the rows
+// of a CSV file, or just one part with all the text.
+// =====================================================================
+
+/// A piece of the text of a file, before chunking.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    /// Page number counting from 1, or 0 if the format has no pages.
+    pub page: u64,
+    /// Headings above the text, "Install > Linux".
+    pub section: Option<String>,
+    pub text: String,
+}
+
+impl Part {
+    fn text(text: String) -> Self {
+        Part { page: 0, section: None, text }
+    }
+}
+
+pub struct Extractor {
+    pub name: &'static str,
+    pub extensions: &'static [&'static str],
+    extract: fn(&Path) -> anyhow::Result<Vec<Part>>,
+}
+
+pub static EXTRACTORS: &[Extractor] = &[
+    Extractor { name: "text", extensions: &["txt", "text"], extract: extract_text },
+    Extractor { name: "markdown", extensions: &["md", "markdown"], extract: extract_markdown },
+    Extractor { name: "html", extensions: &["html", "htm", "xhtml"], extract: extract_html },
+    Extractor { name: "xml", extensions: &["xml", "tei"], extract: extract_xml },
+    Extractor { name: "pdf", extensions: &["pdf"], extract: extract_pdf },
+    Extractor { name: "docx", extensions: &["docx"], extract: extract_docx },
+    Extractor { name: "odt", extensions: &["odt"], extract: extract_odt },
+    Extractor { name: "epub", extensions: &["epub"], extract: extract_epub },
+    Extractor { name: "csv", extensions: &["csv", "tsv"], extract: extract_csv },
+];
+
+fn extractor_named(name: &str) -> Option<&'static Extractor> {
+    EXTRACTORS.iter().find(|e| e.name == name)
+}
+
+/// The extractor for a file, by extension, or else by contents.
+pub fn find_extractor(path: &Path) -> Option<&'static Extractor> {
+    let by_extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .and_then(|ext| EXTRACTORS.iter().find(|x| x.extensions.contains(&ext.as_str())));
+    by_extension.or_else(|| sniff(path).and_then(extractor_named))
+}
+
+/// Reads the text of a file with the extractor for its format.
+pub fn extract(path: &Path) -> anyhow::Result<Vec<Part>> {
+    let extractor = find_extractor(path)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported file format: {}", path.display()))?;
+    (extractor.extract)(path)
+}
+
+// Guesses the format from the first bytes of the file.
+fn sniff(path: &Path) -> Option<&'static str> {
+    let mut head = vec![0u8; 512];
+    let len = fs::File::open(path).and_then(|mut f| f.read(&mut head)).ok()?;
+    let head = &head[..len];
+    if head.starts_with(b"%PDF") {
+        return Some("pdf");
+    }
+    if head.starts_with(b"PK\x03\x04") {
+        // DOCX has no mimetype file, ODT and EPUB start with one.
+        let start = String::from_utf8_lossy(head);
+        return if start.contains("application/vnd.oasis.opendocument.text") {
+            Some("odt")
+        } else if start.contains("application/epub+zip") {
+            Some("epub")
+        } else if start.contains("word/") || start.contains("[Content_Types].xml") {
+            Some("docx")
+        } else {
+            None
+        };
+    }
+    let start = match std::str::from_utf8(head) {
+        Ok(start) => start,
+        // The 512 bytes may end in the middle of a character.
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    let lower = start.trim_start().to_lowercase();
+    if lower.starts_with("<!doctype html") || lower.starts_with("<html") {
+        Some("html")
+    } else if lower.starts_with("<?xml") || lower.starts_with('<') {
+        Some("xml")
+    } else if start.contains('\0') {
+        None
+    } else {
+        Some("text")
+    }
+}
+
+// ---------------------------------------------------------------------
+
+fn extract_text(path: &Path) -> anyhow::Result<Vec<Part>> {
+    Ok(vec![Part::text(fs::read_to_string(path)?)])
+}
+
+fn extract_pdf(path: &Path) -> anyhow::Result<Vec<Part>> {
+    let bytes = fs::read(path)?;
+    // pdf-extract panics on some broken PDFs, that should not stop the
+    // other files from being read.
+    let pages = match std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(&bytes)) {
+        Ok(Ok(pages)) => pages,
+        Ok(Err(e)) => anyhow::bail!("Cannot extract text from {}: {}", path.display(), e),
+        Err(_) => anyhow::bail!("Cannot extract text from {}: pdf-extract panicked.", path.display()),
+    };
+    Ok(pages.into_iter()
+       .enumerate()
+       .map(|(i, text)| Part { page: i as u64 + 1, section: None, text })
+       .collect())
+}
+
+fn extract_markdown(path: &Path) -> anyhow::Result<Vec<Part>> {
+    Ok(markdown_sections(&fs::read_to_string(path)?))
+}
+
+/// Splits Markdown at the (ATX, "## Title") headings. The heading line
+/// stays in the text, and the headings above it become the section.
+pub fn markdown_sections(text: &str) -> Vec<Part> {
+    let mut parts = vec![];
+    let mut headings: Vec<(usize, String)> = vec![];
+    let mut current = String::new();
+    let mut in_code = false;
+
+    let section = |headings: &[(usize, String)]| {
+        if headings.is_empty() {
+            None
+        } else {
+            Some(headings.iter().map(|(_, h)| h.as_str()).collect::<Vec<_>>().join(" > "))
+        }
+    };
+
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+        }
+        let level = trimmed.chars().take_while(|c| *c == '#').count();
+        let is_heading = !in_code && (1..=6).contains(&level)
+            && trimmed[level..].starts_with([' ', '\t']);
+        if is_heading {
+            if !current.trim().is_empty() {
+                parts.push(Part { page: 0, section: section(&headings), text: current });
+            }
+            current = String::new();
+            let title = trimmed[level..].trim().trim_end_matches('#').trim().to_string();
+            headings.retain(|(l, _)| *l < level);
+            headings.push((level, title));
+        }
+        current.push_str(line);
+        current.push('\n');
+    }
+    if !current.trim().is_empty() {
+        parts.push(Part { page: 0, section: section(&headings), text: current });
+    }
+    parts
+}
+
+// ---------------------------------------------------------------------
+
+// How to turn the tags of a markup language into plain text.
+struct Markup {
+    /// Elements whose contents are left out.
+    skip: &'static [&'static str],
+    /// Elements which start on a new line.
+    breaks: &'static [&'static str],
+    /// Elements which become a space.
+    spaces: &'static [&'static str],
+    /// If true, all tags except the inline ones become a space, so the
+    /// words in unknown elements are not glued together.
+    space_others: bool,
+    inline: &'static [&'static str],
+}
+
+static HTML: Markup = Markup {
+    skip: &["head", "script", "style", "noscript", "template", "svg"],
+    breaks: &["p", "div", "br", "li", "h1", "h2", "h3", "h4", "h5", "h6", "tr", "table", "ul", "ol",
+              "section", "article", "header", "footer", "blockquote", "pre", "hr", "dt", "dd", "figcaption"],
+    spaces: &["td", "th"],
+    space_others: true,
+    inline: &["a", "b", "i", "em", "strong", "span", "small", "sub", "sup", "code", "abbr", "mark",
+              "u", "s", "q", "cite", "time", "label", "bdi", "bdo", "wbr"],
+};
+
+// Generic XML, with the common TEI elements. Of the alternatives in a
+// <choice> the corrected, regularised and expanded ones are kept.
+static XML: Markup = Markup {
+    skip: &["teiHeader", "sic", "orig", "abbr", "fw"],
+    breaks: &["p", "l", "lb", "head", "div", "ab", "item", "row", "pb", "sp", "speaker", "table", "list"],
+    spaces: &["cell"],
+    space_others: true,
+    inline: &["hi", "emph", "persName", "placeName", "orgName", "name", "rs", "choice", "corr", "reg",
+              "expan", "ex", "supplied", "unclear", "add", "del", "date", "foreign", "term", "title", "seg", "c", "g"],
+};
+
+static DOCX: Markup = Markup {
+    skip: &["w:delText", "w:instrText"],
+    breaks: &["w:p", "w:br", "w:cr"],
+    spaces: &["w:tab"],
+    space_others: false,
+    inline: &[],
+};
+
+static ODT: Markup = Markup {
+    skip: &["office:automatic-styles", "office:font-face-decls", "text:tracked-changes"],
+    breaks: &["text:p", "text:h", "text:line-break", "text:list-item", "table:table-row"],
+    spaces: &["text:s", "text:tab", "table:table-cell"],
+    space_others: false,
+    inline: &[],
+};
+
+fn contains(names: &[&str], name: &str) -> bool {
+    names.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+/// Removes the tags from HTML or XML and decodes the entities. Line
+/// breaks come from the tags, not from the newlines in the source, and
+/// runs of whitespace and blank lines are collapsed.
+fn strip_markup(input: &str, markup: &Markup) -> String {
+    let mut out = String::new();
+    let mut skip_depth = 0usize;
+    let mut rest = input;
+    let push_text = |out: &mut String, text: &str| {
+        out.extend(decode_entities(text).chars().map(|c| if c.is_whitespace() { ' ' } else { c }));
+    };
+
+    while let Some(start) = rest.find('<') {
+        if skip_depth == 0 {
+            push_text(&mut out, &rest[..start]);
+        }
+        rest = &rest[start..];
+
+        // Comments, CDATA, processing instructions and the doctype.
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("<![CDATA[") {
+            let end = after.find("]]>").unwrap_or(after.len());
+            if skip_depth == 0 {
+                out.push_str(&after[..end].replace(char::is_whitespace, " "));
+            }
+            rest = after.get(end + 3..).unwrap_or("");
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+
+        let closing = tag.starts_with('/');
+        let self_closing = tag.ends_with('/');
+        let name = tag.trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+
+        if contains(markup.skip, name) {
+            if closing {
+                skip_depth = skip_depth.saturating_sub(1);
+            } else if !self_closing {
+                skip_depth += 1;
+            }
+            continue;
+        }
+        if skip_depth > 0 {
+            continue;
+        }
+        if contains(markup.breaks, name) {
+            out.push('\n');
+        } else if contains(markup.spaces, name) || (markup.space_others && !contains(markup.inline, name)) {
+            out.push(' ');
+        }
+    }
+    if skip_depth == 0 {
+        push_text(&mut out, rest);
+    }
+
+    collapse_whitespace(&out)
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').filter(|end| *end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                    u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32)
+                }
+                _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+                _ => None,
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() && lines.last().is_none_or(|l| l.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+fn extract_html(path: &Path) -> anyhow::Result<Vec<Part>> {
+    Ok(vec![Part::text(strip_markup(&fs::read_to_string(path)?, &HTML))])
+}
+
+fn extract_xml(path: &Path) -> anyhow::Result<Vec<Part>> {
+    Ok(vec![Part::text(strip_markup(&fs::read_to_string(path)?, &XML))])
+}
+
+// ---------------------------------------------------------------------
+// DOCX, ODT and EPUB are zip files with XML (or XHTML) inside.
+
+fn zip_entry(archive: &mut zip::ZipArchive<fs::File>, name: &str) -> anyhow::Result<String> {
+    let mut contents = String::new();
+    archive.by_name(name)
+        .map_err(|e| anyhow::anyhow!("No {} in the archive: {}", name, e))?
+        .read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+fn open_zip(path: &Path) -> anyhow::Result<zip::ZipArchive<fs::File>> {
+    zip::ZipArchive::new(fs::File::open(path)?)
+        .map_err(|e| anyhow::anyhow!("Cannot open {}: {}", path.display(), e))
+}
+
+fn extract_docx(path: &Path) -> anyhow::Result<Vec<Part>> {
+    let mut archive = open_zip(path)?;
+    Ok(vec![Part::text(strip_markup(&zip_entry(&mut archive, "word/document.xml")?, &DOCX))])
+}
+
+fn extract_odt(path: &Path) -> anyhow::Result<Vec<Part>> {
+    let mut archive = open_zip(path)?;
+    Ok(vec![Part::text(strip_markup(&zip_entry(&mut archive, "content.xml")?, &ODT))])
+}
+
+// The chapters are read in the order of the spine in the package file.
+fn extract_epub(path: &Path) -> anyhow::Result<Vec<Part>> {
+    let mut archive = open_zip(path)?;
+    let container = zip_entry(&mut archive, "META-INF/container.xml")?;
+    let opf_path = tags(&container, "rootfile")
+        .find_map(|t| attribute(t, "full-path"))
+        .ok_or_else(|| anyhow::anyhow!("No package file in {}", path.display()))?;
+    let opf = zip_entry(&mut archive, &opf_path)?;
+    let base = opf_path.rsplit_once('/').map_or("", |(dir, _)| dir);
+
+    let items: Vec<(String, String)> = tags(&opf, "item")
+        .filter_map(|t| Some((attribute(t, "id")?, attribute(t, "href")?)))
+        .collect();
+    let mut parts = vec![];
+    for idref in tags(&opf, "itemref").filter_map(|t| attribute(t, "idref")) {
+        let Some((_, href)) = items.iter().find(|(id, _)| *id == idref) else {
+            continue;
+        };
+        let href = decode_entities(href.split('#').next().unwrap_or(""));
+        let name = if base.is_empty() { href } else { format!("{}/{}", base, href) };
+        let text = strip_markup(&zip_entry(&mut archive, &name)?, &HTML);
+        if !text.is_empty() {
+            parts.push(Part::text(text));
+        }
+    }
+    Ok(parts)
+}
+
+// The start tags with the given name, "<item id=... />" as "item id=... /".
+fn tags<'a>(xml: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    xml.split('<').filter_map(move |s| {
+        let tag = &s[..s.find('>')?];
+        let tag_name = tag.split(|c: char| c.is_whitespace() || c == '/').next()?;
+        // Also with a namespace prefix, "opf:item".
+        let local = tag_name.rsplit(':').next()?;
+        (local == name).then_some(tag)
+    })
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(pos) = rest.find(name) {
+        let before = rest[..pos].chars().last();
+        let after = rest[pos + name.len()..].trim_start();
+        rest = &rest[pos + name.len()..];
+        if !before.is_some_and(|c| c.is_whitespace()) {
+            continue;
+        }
+        let Some(value) = after.strip_prefix('=') else { continue };
+        let value = value.trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            continue;
+        }
+        let value = &value[1..];
+        return value.find(quote).map(|end| value[..end].to_string());
+    }
+    None
+}
+
+// ---------------------------------------------------------------------
+
+// Every row of a CSV file becomes a part, with the column names from
+// the first row: "name: Sirius; animal: cat".
+fn extract_csv(path: &Path) -> anyhow::Result<Vec<Part>> {
+    let contents = fs::read_to_string(path)?;
+    let separator = if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("tsv")) {
+        '\t'
+    } else {
+        guess_separator(contents.lines().next().unwrap_or(""))
+    };
+    Ok(csv_rows(&contents, separator))
+}
+
+fn guess_separator(header: &str) -> char {
+    [',', ';', '\t']
+        .into_iter()
+        .max_by_key(|sep| header.matches(*sep).count())
+        .unwrap_or(',')
+}
+
+pub fn csv_rows(contents: &str, separator: char) -> Vec<Part> {
+    let mut rows = parse_csv(contents, separator).into_iter();
+    let Some(header) = rows.next() else {
+        return vec![];
+    };
+    rows.filter_map(|row| {
+        let fields: Vec<String> = header.iter()
+            .zip(row.iter())
+            .filter(|(_, value)| !value.trim().is_empty())
+            .map(|(name, value)| format!("{}: {}", name.trim(), value.trim()))
+            .collect();
+        if fields.is_empty() {
+            None
+        } else {
+            Some(Part::text(fields.join("; ")))
+        }
+    }).collect()
+}
+
+// Fields can be quoted, with "" for a quote, and contain newlines.
+fn parse_csv(contents: &str, separator: char) -> Vec<Vec<String>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            } else {
+                field.push(c);
+            }
+        } else if c == '"' && field.is_empty() {
+            quoted = true;
+        } else if c == separator {
+            row.push(std::mem::take(&mut field));
+        } else if c == '\n' || c == '\r' {
+            if c == '\r' && chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            row.push(std::mem::take(&mut field));
+            if row.iter().any(|f| !f.is_empty()) {
+                rows.push(std::mem::take(&mut row));
+            }
+            row.clear();
+        } else {
+            field.push(c);
+        }
+    }
+    row.push(field);
+    if row.iter().any(|f| !f.is_empty()) {
+        rows.push(row);
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_to_text() {
+        let html = "<html><head><title>T</title><style>p {}</style></head>\
+                    <body><h1>Cats</h1><p>Sirius &amp; <b>Maja</b>&#33;</p><p>Two<br/>lines</p></body></html>";
+        assert_eq!(strip_markup(html, &HTML), "Cats\n\nSirius & Maja!\n\nTwo\nlines");
+    }
+
+    #[test]
+    fn tei_to_text() {
+        let tei = r#"<?xml version="1.0"?><TEI><teiHeader><title>Header</title></teiHeader>
+            <text><body><p>Our <choice><sic>kat</sic><corr>cat</corr></choice> is
+            <persName>Sirius</persName>.</p><p>Second<lb/>line</p></body></text></TEI>"#;
+        assert_eq!(strip_markup(tei, &XML), "Our cat is Sirius.\n\nSecond\nline");
+    }
+
+    #[test]
+    fn docx_runs_are_joined() {
+        let xml = "<w:document><w:body><w:p><w:r><w:t>Hel</w:t></w:r><w:r><w:t>lo</w:t></w:r>\
+                   <w:r><w:tab/><w:t>world</w:t></w:r></w:p><w:p><w:r><w:delText>gone</w:delText><w:t>Next</w:t></w:r></w:p></w:body></w:document>";
+        assert_eq!(strip_markup(xml, &DOCX), "Hello world\n\nNext");
+    }
+
+    #[test]
+    fn markdown_headings_are_sections() {
+        let md = "Intro text.\n# Install\nSome.\n## Linux\n```\n# not a heading\n```\n## macOS\nBrew.\n# Use\nRun.\n";
+        let parts = markdown_sections(md);
+        let sections: Vec<Option<&str>> = parts.iter().map(|p| p.section.as_deref()).collect();
+        assert_eq!(sections, vec![None, Some("Install"), Some("Install > Linux"), Some("Install > macOS"), Some("Use")]);
+        assert!(parts[2].text.contains("# not a heading"));
+    }
+
+    #[test]
+    fn csv_rows_become_parts() {
+        let csv = "name,animal,note\nSirius,cat,\"old, grey\"\n\nMaja,cat,\"says \"\"miau\"\"\"\n";
+        let parts = csv_rows(csv, guess_separator("name,animal,note"));
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].text, "name: Sirius; animal: cat; note: old, grey");
+        assert_eq!(parts[1].text, "name: Maja; animal: cat; note: says \"miau\"");
+    }
+
+    #[test]
+    fn epub_attributes() {
+        let opf = r#"<manifest><item id="c1" href="ch1.xhtml" media-type="application/xhtml+xml"/></manifest>
+                     <spine><itemref idref="c1"/></spine>"#;
+        let item = tags(opf, "item").next().unwrap();
+        assert_eq!(attribute(item, "id").as_deref(), Some("c1"));
+        assert_eq!(attribute(item, "href").as_deref(), Some("ch1.xhtml"));
+        assert_eq!(tags(opf, "itemref").filter_map(|t| attribute(t, "idref")).count(), 1);
+    }
+
+    #[test]
+    fn find_by_extension() {
+        assert_eq!(find_extractor(Path::new("report.DOCX")).map(|e| e.name), Some("docx"));
+        assert_eq!(find_extractor(Path::new("/no/such/file.xyz")).map(|e| e.name), None);
+    }
+
+    #[test]
+    fn sniff_cut_character() {
+        // The first 512 bytes end in "ö" and two of the three bytes of "€".
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, format!("{}ö€ och mer", "a".repeat(508)).as_bytes()).unwrap();
+        assert_eq!(sniff(file.path()), Some("text"));
+        let mut binary = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut binary, &[b'a', 0xff, b'b']).unwrap();
+        assert_eq!(sniff(binary.path()), None);
+    }
+}
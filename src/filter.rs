@@ -0,0 +1,153 @@
+use chrono::NaiveDate;
+use glob::{MatchOptions, Pattern};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+
+use crate::manifest::FileEntry;
+
+// =====================================================================
+// Query filters. They work per file: the path is the filename as
+// stored in the databases, the date (of ingestion) and the tags are
+// kept in the manifest of the vector database, and in every chunk in
+// the text database (see tant.rs).
+// =====================================================================
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    /// Directory or file prefixes, or globs ("texts/**/*.pdf"). A file
+    /// matching one of them passes.
+    pub paths: Vec<String>,
+    /// Ingested on or after this day, YYYYMMDD.
+    pub since: Option<String>,
+    /// Ingested on or before this day, YYYYMMDD.
+    pub until: Option<String>,
+    /// All of these must be set on the file.
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Parses "key=value".
+pub fn parse_tag(tag: &str) -> anyhow::Result<(String, String)> {
+    match tag.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.trim().to_string())),
+        _ => anyhow::bail!("Tag \"{}\" should look like key=value.", tag),
+    }
+}
+
+pub fn parse_tags(tags: &[String]) -> anyhow::Result<BTreeMap<String, String>> {
+    tags.iter().map(|tag| parse_tag(tag)).collect()
+}
+
+// "2024-05-01" or "20240501" to "20240501", the start of the dates in
+// the records and the manifest ("20240501T1412").
+fn parse_date(date: &str) -> anyhow::Result<String> {
+    let digits: String = date.chars().filter(|c| *c != '-').collect();
+    if digits.len() != 8 || NaiveDate::parse_from_str(&digits, "%Y%m%d").is_err() {
+        anyhow::bail!("Date \"{}\" should look like 2024-05-01.", date);
+    }
+    Ok(digits)
+}
+
+pub fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+impl Filter {
+    pub fn new(paths: &[String], since: Option<&str>, until: Option<&str>, tags: &[String]) -> anyhow::Result<Self> {
+        for path in paths.iter().filter(|p| is_glob(p)) {
+            Pattern::new(path)?;
+        }
+        Ok(Filter {
+            paths: paths.to_vec(),
+            since: since.map(parse_date).transpose()?,
+            until: until.map(parse_date).transpose()?,
+            tags: parse_tags(tags)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.since.is_none() && self.until.is_none() && self.tags.is_empty()
+    }
+
+    pub fn matches_path(&self, filename: &str) -> bool {
+        if self.paths.is_empty() {
+            return true;
+        }
+        let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+        self.paths.iter().any(|path| {
+            if is_glob(path) {
+                Pattern::new(path).map(|p| p.matches_with(filename, options)).unwrap_or(false)
+            } else {
+                Path::new(filename).starts_with(path)
+            }
+        })
+    }
+
+    /// The range of the dates of ingestion: from since, up to (not
+    /// including) the day after until, to compare with "20240512T1003".
+    pub fn date_bounds(&self) -> (Bound<String>, Bound<String>) {
+        let since = match &self.since {
+            Some(since) => Bound::Included(since.clone()),
+            None => Bound::Unbounded,
+        };
+        let until = self.until.as_deref()
+            .and_then(|until| NaiveDate::parse_from_str(until, "%Y%m%d").ok())
+            .and_then(|until| until.succ_opt())
+            .map_or(Bound::Unbounded, |next| Bound::Excluded(next.format("%Y%m%d").to_string()));
+        (since, until)
+    }
+
+    /// Whether a file passes, entry is its manifest entry if it has one.
+    pub fn matches(&self, filename: &str, entry: Option<&FileEntry>) -> bool {
+        if !self.matches_path(filename) {
+            return false;
+        }
+        if self.since.is_none() && self.until.is_none() && self.tags.is_empty() {
+            return true;
+        }
+        let Some(entry) = entry else {
+            return false;
+        };
+        let day = entry.date.get(..8).unwrap_or("");
+        if day.is_empty() && (self.since.is_some() || self.until.is_some()) {
+            return false;
+        }
+        if self.since.as_deref().is_some_and(|since| day < since) || self.until.as_deref().is_some_and(|until| day > until) {
+            return false;
+        }
+        self.tags.iter().all(|(key, value)| entry.tags.get(key) == Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(date: &str, tags: &[&str]) -> FileEntry {
+        let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+        FileEntry { date: date.to_string(), tags: parse_tags(&tags).unwrap(), ..Default::default() }
+    }
+
+    #[test]
+    fn paths_and_globs() {
+        let filter = Filter::new(&["texts/team".to_string(), "docs/**/*.pdf".to_string()], None, None, &[]).unwrap();
+        assert!(filter.matches("texts/team/a.txt", None));
+        assert!(!filter.matches("texts/teams/a.txt", None));
+        assert!(filter.matches("docs/2024/may/report.pdf", None));
+        assert!(!filter.matches("docs/report.txt", None));
+        assert!(Filter::new(&[], None, None, &[]).unwrap().matches("anything", None));
+    }
+
+    #[test]
+    fn dates_and_tags() {
+        let filter = Filter::new(&[], Some("2024-05-01"), Some("20240531"), &["project=x".to_string()]).unwrap();
+        assert!(filter.matches("a.txt", Some(&entry("20240512T1003", &["project=x", "team=y"]))));
+        assert!(!filter.matches("a.txt", Some(&entry("20240601T0000", &["project=x"]))));
+        assert!(!filter.matches("a.txt", Some(&entry("20240512T1003", &["project=z"]))));
+        assert!(!filter.matches("a.txt", None));
+        assert!(Filter::new(&[], Some("May"), None, &[]).is_err());
+        assert!(Filter::new(&[], Some("2024-13-01"), None, &[]).is_err());
+        assert_eq!(filter.date_bounds(), (Bound::Included("20240501".to_string()), Bound::Excluded("20240601".to_string())));
+        assert!(parse_tag("project").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::genaigen::GenaiGenerator;
+use crate::mistral;
+use crate::ollamagen::OllamaGenerator;
+use crate::qmistral::{QModel, QModelConfig};
+use crate::textgen::PhiGenerator;
+
+// =====================================================================
+// Common interface for the different text generation backends.
+// =====================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+impl Message {
+    pub fn system(content: &str) -> Self {
+        Message { role: Role::System, content: content.to_string() }
+    }
+
+    pub fn user(content: &str) -> Self {
+        Message { role: Role::User, content: content.to_string() }
+    }
+
+    pub fn assistant(content: &str) -> Self {
+        Message { role: Role::Assistant, content: content.to_string() }
+    }
+}
+
+/// Sampling options. Values which are None fall back on the defaults
+/// of the backend.
+#[derive(Debug, Clone, Default)]
+pub struct GenOptions {
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub repeat_penalty: Option<f32>,
+}
+
+pub trait Generator: Send {
+    /// Name of the backend and model, for display.
+    fn name(&self) -> String;
+
+    /// Generates the answer to the messages. The on_token callback is
+    /// called with every new piece of text, the whole answer is returned
+    /// at the end.
+    fn generate(&mut self, messages: &[Message], opts: &GenOptions, on_token: &mut dyn FnMut(&str)) -> anyhow::Result<String>;
+
+    /// Number of tokens in the prompt made from the messages, if the
+    /// backend has a tokenizer.
+    fn count_tokens(&self, _messages: &[Message]) -> Option<usize> {
+        None
+    }
+
+    /// Maximum number of tokens (prompt and answer) the model can handle,
+    /// None if there is no (known) limit.
+    fn context_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Length of the answer when GenOptions.max_tokens is not set.
+    fn default_max_tokens(&self) -> usize {
+        DEFAULT_MAX_TOKENS
+    }
+}
+
+pub const DEFAULT_MAX_TOKENS: usize = 1200;
+
+/// Tokens of the context which the local backends keep free, besides the
+/// prompt and the answer.
+pub const CONTEXT_RESERVE: usize = 10;
+
+pub const BACKENDS: [&str; 5] = ["qmistral", "mistral", "phi", "ollama", "genai"];
+
+/// Creates (and loads) a generator by name. The model is only used by the
+/// backends which run through a server (ollama, genai), the gguf config
+/// only by the qmistral backend.
+pub fn get_generator(backend: &str, model: &str, gguf: &QModelConfig) -> anyhow::Result<Box<dyn Generator>> {
+    match backend {
+        "qmistral" | "gguf" => Ok(Box::new(QModel::load(gguf)?)),
+        "mistral" => Ok(Box::new(mistral::init()?)),
+        "phi" => Ok(Box::new(PhiGenerator::new())),
+        "ollama" => Ok(Box::new(OllamaGenerator::new(model))),
+        "genai" => Ok(Box::new(GenaiGenerator::new(model))),
+        _ => anyhow::bail!("Unknown backend \"{}\", choose from {:?}.", backend, BACKENDS),
+    }
+}
+
+// =====================================================================
+// Prompt templates for the local models, which need the messages
+// as one string.
+// =====================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PromptTemplate {
+    Mistral,
+    ChatML,
+    Llama3,
+    GptSw3,
+}
+
+pub const TEMPLATES: [&str; 4] = ["mistral", "chatml", "llama3", "gpt-sw3"];
+
+impl fmt::Display for PromptTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PromptTemplate::Mistral => write!(f, "mistral"),
+            PromptTemplate::ChatML => write!(f, "chatml"),
+            PromptTemplate::Llama3 => write!(f, "llama3"),
+            PromptTemplate::GptSw3 => write!(f, "gpt-sw3"),
+        }
+    }
+}
+
+impl FromStr for PromptTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mistral" | "inst" => Ok(PromptTemplate::Mistral),
+            "chatml" => Ok(PromptTemplate::ChatML),
+            "llama3" | "llama-3" => Ok(PromptTemplate::Llama3),
+            "gpt-sw3" | "gptsw3" => Ok(PromptTemplate::GptSw3),
+            _ => anyhow::bail!("Unknown prompt template \"{}\", choose from {:?}.", s, TEMPLATES),
+        }
+    }
+}
+
+impl PromptTemplate {
+    pub fn apply(&self, messages: &[Message]) -> String {
+        match self {
+            PromptTemplate::Mistral => mistral_prompt(messages),
+            PromptTemplate::ChatML => chatml_prompt(messages),
+            PromptTemplate::Llama3 => llama3_prompt(messages),
+            PromptTemplate::GptSw3 => gptsw3_prompt(messages),
+        }
+    }
+
+    /// Tokens which end the answer of the model.
+    pub fn stop_tokens(&self) -> &'static [&'static str] {
+        match self {
+            PromptTemplate::Mistral => &["</s>"],
+            PromptTemplate::ChatML => &["<|im_end|>", "<|endoftext|>"],
+            PromptTemplate::Llama3 => &["<|eot_id|>", "<|end_of_text|>"],
+            PromptTemplate::GptSw3 => &["<s>", "<|endoftext|>"],
+        }
+    }
+}
+
+// Mistral has no system role, the system message is put in front of
+// the first user message. The tokenizer adds the initial <s>.
+fn mistral_prompt(messages: &[Message]) -> String {
+    let mut prompt = String::new();
+    let mut system = String::new();
+    for message in messages {
+        match message.role {
+            Role::System => system = message.content.clone() + " \n",
+            Role::User => {
+                prompt += &format!("[INST] {}{} [/INST]", system, message.content);
+                system.clear();
+            }
+            Role::Assistant => prompt += &format!(" {}</s>", message.content),
+        }
+    }
+    prompt
+}
+
+fn chatml_prompt(messages: &[Message]) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        let role = match message.role {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        };
+        prompt += &format!("<|im_start|>{}\n{}<|im_end|>\n", role, message.content);
+    }
+    prompt + "<|im_start|>assistant\n"
+}
+
+// The tokenizer adds the initial <|begin_of_text|>.
+fn llama3_prompt(messages: &[Message]) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        let role = match message.role {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        };
+        prompt += &format!("<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>", role, message.content);
+    }
+    prompt + "<|start_header_id|>assistant<|end_header_id|>\n\n"
+}
+
+// GPT-SW3 instruct has no system role either, see
+// https://huggingface.co/AI-Sweden-Models/gpt-sw3-6.7b-v2-instruct
+fn gptsw3_prompt(messages: &[Message]) -> String {
+    let mut prompt = "<|endoftext|><s>\n".to_string();
+    let mut system = String::new();
+    for message in messages {
+        match message.role {
+            Role::System => system = message.content.clone() + "\n",
+            Role::User => {
+                prompt += &format!("User:\n{}{}\n<s>\n", system, message.content);
+                system.clear();
+            }
+            Role::Assistant => prompt += &format!("Bot:\n{}\n<s>\n", message.content),
+        }
+    }
+    prompt + "Bot:\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mistral_template() {
+        let messages = vec![Message::system("Be brief."), Message::user("Question: Why?")];
+        assert_eq!(PromptTemplate::Mistral.apply(&messages), "[INST] Be brief. \nQuestion: Why? [/INST]");
+    }
+
+    #[test]
+    fn mistral_template_turns() {
+        let messages = vec![Message::user("Hi"), Message::assistant("Hello"), Message::user("Bye")];
+        assert_eq!(PromptTemplate::Mistral.apply(&messages), "[INST] Hi [/INST] Hello</s>[INST] Bye [/INST]");
+    }
+
+    #[test]
+    fn chatml_template() {
+        let messages = vec![Message::system("Be brief."), Message::user("Why?")];
+        assert_eq!(
+            PromptTemplate::ChatML.apply(&messages),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nWhy?<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn llama3_template() {
+        let messages = vec![Message::system("Be brief."), Message::user("Why?")];
+        assert_eq!(
+            PromptTemplate::Llama3.apply(&messages),
+            "<|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nWhy?<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+
+    #[test]
+    fn template_from_str() {
+        assert_eq!("ChatML".parse::<PromptTemplate>().unwrap(), PromptTemplate::ChatML);
+        assert_eq!("gpt-sw3".parse::<PromptTemplate>().unwrap(), PromptTemplate::GptSw3);
+        assert!("alpaca".parse::<PromptTemplate>().is_err());
+    }
+}
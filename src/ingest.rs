@@ -0,0 +1,500 @@
+use oasysdb::prelude::*;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tantivy::Index;
+
+use crate::chunker::Chunker;
+use crate::database::chunks_to_records;
+use crate::embedder::{chunk_file, count_tokens, max_embedding_tokens, read_dir_contents};
+use crate::manifest::{check_file, load_text_manifest, rebuild_without, remove_path_records, replace_file_records, save_text_manifest,
+                      FileEntry, FileStatus, Manifest};
+use crate::tant::{get_filenames, BulkWriter};
+
+// =====================================================================
+// Ingestion. A file is read and chunked once, and the same chunks go
+// to the vector database, the text database, or both. In both stores
+// a chunk is known by its filename and its number in the file, so a
+// hit in one store can be found in the other.
+// =====================================================================
+
+/// Which of the databases to write to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Store {
+    Vector,
+    Text,
+    Both,
+}
+
+impl Store {
+    pub fn vector(&self) -> bool {
+        matches!(self, Store::Vector | Store::Both)
+    }
+
+    pub fn text(&self) -> bool {
+        matches!(self, Store::Text | Store::Both)
+    }
+}
+
+impl FromStr for Store {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "vector" => Ok(Store::Vector),
+            "text" => Ok(Store::Text),
+            "both" => Ok(Store::Both),
+            _ => anyhow::bail!("Unknown store \"{}\", choose from vector, text or both.", s),
+        }
+    }
+}
+
+/// A piece of a file, as stored in the databases.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub filename: String,
+    pub number: u64,
+    /// Page of a PDF, counting from 1.
+    pub page: Option<u64>,
+    /// Headings above the chunk, in Markdown.
+    pub section: Option<String>,
+    pub text: String,
+}
+
+/// The ID of a chunk, the same in the vector and the text database.
+pub fn chunk_id(filename: &str, number: u64) -> String {
+    format!("{}#{}", filename, number)
+}
+
+/// Reads, extracts and chunks a file, numbering the chunks in order.
+pub fn read_chunks(path: &Path, chunker: &Chunker) -> anyhow::Result<Vec<Chunk>> {
+    let filename = path.to_string_lossy().to_string();
+    Ok(chunk_file(path, chunker)?
+       .into_iter()
+       .enumerate()
+       .map(|(number, part)| Chunk {
+           filename: filename.clone(),
+           number: number as u64,
+           page: if part.page > 0 { Some(part.page) } else { None },
+           section: part.section,
+           text: part.text,
+       })
+       .collect())
+}
+
+/// What happened to a file in the vector database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ingested {
+    Unchanged,
+    Added(usize),
+    Replaced(usize),
+}
+
+/// What happened to a file, None for a store which was not written to.
+#[derive(Debug, Clone, Default)]
+pub struct FileReport {
+    pub vector: Option<Ingested>,
+    pub text: Option<u64>,
+}
+
+/// The totals of add_files(), for the throughput.
+#[derive(Debug, Clone, Default)]
+pub struct IngestStats {
+    pub files: usize,
+    pub errors: usize,
+    pub chunks: usize,
+    /// Of the text of the chunks.
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl IngestStats {
+    pub fn summary(&self) -> String {
+        let seconds = self.elapsed.as_secs_f64().max(0.001);
+        format!("{} files ({} errors), {} chunks, {:.1} MB in {:.1}s: {:.1} files/s, {:.1} chunks/s, {:.2} MB/s",
+                self.files, self.errors, self.chunks, self.bytes as f64 / 1e6, seconds,
+                self.files as f64 / seconds, self.chunks as f64 / seconds, self.bytes as f64 / 1e6 / seconds)
+    }
+}
+
+// A file which has been checked against the manifests.
+struct Checked {
+    report: FileReport,
+    /// Status, modification time and hash, if the file goes to the
+    /// vector database.
+    vector: Option<(FileStatus, u64, String)>,
+    /// Status, modification time and hash, if the file goes to the text
+    /// database.
+    text: Option<(FileStatus, u64, String)>,
+}
+
+impl Checked {
+    // Whether the file has to be read at all.
+    fn read(&self) -> bool {
+        self.vector.is_some() || self.text.is_some()
+    }
+
+    // Whether the file is in one of the stores already.
+    fn replaces(&self) -> bool {
+        [&self.vector, &self.text].iter().any(|s| s.as_ref().is_some_and(|(status, _, _)| *status != FileStatus::New))
+    }
+}
+
+/// Feeds files to the stores it was given. The text database and its
+/// manifest are only committed in commit() (and every commit_every files
+/// in add_files()), the vector collection and its manifest must be saved
+/// by the caller.
+///
+/// Deleting records rebuilds the vector collection, so the old records
+/// of changed and missing files are collected and deleted together in
+/// commit(). The old records and chunks of a changed file are only
+/// given up when its new version has been read, a file which cannot be
+/// read keeps them.
+pub struct Pipeline<'a> {
+    chunker: Chunker,
+    tags: BTreeMap<String, String>,
+    commit_every: usize,
+    vector: Option<(&'a mut Collection, &'a mut Manifest)>,
+    text: Option<(BulkWriter, Manifest)>,
+    /// IDs of records to delete from the vector collection.
+    obsolete: HashSet<u32>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(chunker: Chunker) -> Self {
+        Pipeline {
+            chunker,
+            tags: BTreeMap::new(),
+            commit_every: 0,
+            vector: None,
+            text: None,
+            obsolete: HashSet::new(),
+        }
+    }
+
+    /// Commit the text database every n files in add_files(), 0 only
+    /// at the end.
+    pub fn with_commit_every(mut self, n: usize) -> Self {
+        self.commit_every = n;
+        self
+    }
+
+    /// Tags for the files, kept in the manifest. Without tags, files
+    /// keep the tags they had.
+    pub fn with_tags(mut self, tags: BTreeMap<String, String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn with_vector(mut self, collection: &'a mut Collection, manifest: &'a mut Manifest) -> Self {
+        self.vector = Some((collection, manifest));
+        self
+    }
+
+    /// An index filled before it had a manifest gets one with the files
+    /// of its chunks.
+    pub fn with_text(mut self, index: Index) -> anyhow::Result<Self> {
+        let manifest = match load_text_manifest()? {
+            Some(manifest) => manifest,
+            None => Manifest::from_filenames(get_filenames(&index)?),
+        };
+        self.text = Some((BulkWriter::new(index)?, manifest));
+        Ok(self)
+    }
+
+    /// Adds files to the stores, reading and chunking a batch of them in
+    /// parallel. A file which is unchanged since it was added to a store
+    /// is not read again for it, and a changed file has its old chunks
+    /// replaced. The result of every file is passed to report, in order.
+    pub fn add_files(&mut self, paths: &[PathBuf], report: &mut dyn FnMut(&Path, anyhow::Result<FileReport>)) -> anyhow::Result<IngestStats> {
+        let start = Instant::now();
+        let mut stats = IngestStats::default();
+        let mut since_commit = 0;
+        for batch in paths.chunks(rayon::current_num_threads() * 4) {
+            let checked: Vec<anyhow::Result<Checked>> = batch.iter().map(|path| self.check(path)).collect();
+            let chunker = &self.chunker;
+            let chunks: Vec<Option<anyhow::Result<Vec<Chunk>>>> = batch.par_iter()
+                .zip(checked.par_iter())
+                .map(|(path, checked)| match checked {
+                    Ok(checked) if checked.read() => Some(read_chunks(path, chunker)),
+                    _ => None,
+                })
+                .collect();
+            for ((path, checked), chunks) in batch.iter().zip(checked).zip(chunks) {
+                let result = match (checked, chunks) {
+                    (Err(e), _) => Err(e),
+                    (Ok(checked), Some(Err(e))) if checked.replaces() => Err(e.context("the previous version is kept")),
+                    (Ok(_), Some(Err(e))) => Err(e),
+                    (Ok(checked), None) => Ok(checked.report),
+                    (Ok(checked), Some(Ok(chunks))) => {
+                        stats.chunks += chunks.len();
+                        stats.bytes += chunks.iter().map(|c| c.text.len()).sum::<usize>();
+                        self.store(path, checked, &chunks)
+                    }
+                };
+                stats.files += 1;
+                if result.is_err() {
+                    stats.errors += 1;
+                }
+                report(path, result);
+                since_commit += 1;
+                if self.commit_every > 0 && since_commit >= self.commit_every {
+                    self.commit_text()?;
+                    since_commit = 0;
+                }
+            }
+        }
+        stats.elapsed = start.elapsed();
+        Ok(stats)
+    }
+
+    // Unchanged files get their new tags. Nothing else changes until the
+    // file has been read.
+    fn check(&mut self, path: &Path) -> anyhow::Result<Checked> {
+        let filename = path.to_string_lossy().to_string();
+        let mut report = FileReport::default();
+
+        let mut vector = None;
+        if let Some((_, manifest)) = &mut self.vector {
+            let (status, mtime, hash) = check_file(manifest, &filename, path)?;
+            if status == FileStatus::Unchanged {
+                report.vector = Some(Ingested::Unchanged);
+                set_tags(manifest, &filename, &self.tags);
+            } else {
+                vector = Some((status, mtime, hash));
+            }
+        }
+
+        // The tags are in the chunks of the text database, so a file
+        // which gets other tags is added again.
+        let mut text = None;
+        if let Some((_, manifest)) = &mut self.text {
+            let (status, mtime, hash) = check_file(manifest, &filename, path)?;
+            let retag = !self.tags.is_empty() && manifest.files.get(&filename).is_some_and(|entry| entry.tags != self.tags);
+            if status == FileStatus::Unchanged && !retag {
+                report.text = Some(0);
+            } else {
+                text = Some((status, mtime, hash));
+            }
+        }
+        Ok(Checked { report, vector, text })
+    }
+
+    // Writes the chunks of a checked file to the stores. The old records
+    // of a changed file become obsolete, its manifest entries keep the
+    // tags.
+    fn store(&mut self, path: &Path, checked: Checked, chunks: &[Chunk]) -> anyhow::Result<FileReport> {
+        let filename = path.to_string_lossy().to_string();
+        let Checked { mut report, vector, text } = checked;
+
+        if let (Some((collection, manifest)), Some((status, mtime, hash))) = (&mut self.vector, vector) {
+            warn_long_chunks(&filename, chunks)?;
+            let records = chunks_to_records(chunks, &self.chunker)?;
+            if let Some(entry) = manifest.files.get_mut(&filename) {
+                self.obsolete.extend(std::mem::take(&mut entry.ids));
+            }
+            let tags = file_tags(manifest, &filename, &self.tags);
+            let num = replace_file_records(collection, manifest, &filename, mtime, hash, tags, &records)?;
+            report.vector = Some(match status {
+                FileStatus::New => Ingested::Added(num),
+                _ => Ingested::Replaced(num),
+            });
+        }
+
+        if let (Some((writer, manifest)), Some((status, mtime, hash))) = (&mut self.text, text) {
+            if status != FileStatus::New {
+                writer.delete_path(path)?;
+            }
+            let entry = FileEntry::new(mtime, hash, vec![], file_tags(manifest, &filename, &self.tags));
+            report.text = Some(writer.insert_chunks(chunks, &entry)?);
+            manifest.files.insert(filename, entry);
+        }
+
+        Ok(report)
+    }
+
+    /// Removes a file, or all the files below a directory. Returns the
+    /// number of records removed per file from the vector database, and
+    /// the number of chunks removed from the text database.
+    pub fn remove_path(&mut self, path: &Path) -> anyhow::Result<(BTreeMap<String, usize>, u64)> {
+        let mut removed = BTreeMap::new();
+        if let Some((collection, manifest)) = &mut self.vector {
+            removed = remove_path_records(collection, manifest, path)?;
+        }
+        let mut num = 0;
+        if let Some((writer, manifest)) = &mut self.text {
+            num = writer.delete_path(path)?;
+            manifest.files.retain(|f, _| !Path::new(f).starts_with(path));
+        }
+        Ok((removed, num))
+    }
+
+    /// Removes the files below dir which are not in current (anymore),
+    /// returns the number of records removed per file from the vector
+    /// database. Their records are deleted in commit(), together with
+    /// those of the changed files.
+    pub fn remove_missing(&mut self, dir: &Path, current: &[PathBuf]) -> anyhow::Result<Vec<(String, usize)>> {
+        let mut removed = BTreeMap::new();
+        if let Some((_, manifest)) = &mut self.vector {
+            for missing in manifest.missing_files(dir, current) {
+                let entry = manifest.files.remove(&missing).expect("missing files are in the manifest");
+                removed.insert(missing, entry.ids.len());
+                self.obsolete.extend(entry.ids);
+            }
+        }
+        if let Some((writer, manifest)) = &mut self.text {
+            for missing in manifest.missing_files(dir, current) {
+                manifest.files.remove(&missing);
+                writer.delete_path(Path::new(&missing))?;
+                removed.entry(missing).or_insert(0);
+            }
+        }
+        Ok(removed.into_iter().collect())
+    }
+
+    // Deletes the obsolete records with one rebuild of the collection.
+    fn delete_obsolete(&mut self) -> anyhow::Result<()> {
+        if let Some((collection, manifest)) = &mut self.vector {
+            rebuild_without(collection, manifest, &self.obsolete)?;
+        }
+        self.obsolete.clear();
+        Ok(())
+    }
+
+    // The manifest is saved with the index, so they agree.
+    fn commit_text(&mut self) -> anyhow::Result<()> {
+        if let Some((writer, manifest)) = &mut self.text {
+            writer.commit()?;
+            save_text_manifest(manifest)?;
+        }
+        Ok(())
+    }
+
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        self.delete_obsolete()?;
+        self.commit_text()
+    }
+}
+
+// Tags given for unchanged files replace the ones they had.
+fn set_tags(manifest: &mut Manifest, filename: &str, tags: &BTreeMap<String, String>) {
+    if tags.is_empty() {
+        return;
+    }
+    if let Some(entry) = manifest.files.get_mut(filename) {
+        entry.tags = tags.clone();
+    }
+}
+
+// The tags given, or else the ones the file had.
+fn file_tags(manifest: &Manifest, filename: &str, tags: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    match manifest.files.get(filename) {
+        Some(entry) if tags.is_empty() => entry.tags.clone(),
+        _ => tags.clone(),
+    }
+}
+
+// The embedding model ignores everything after its maximum number of
+// tokens, so the end of a long chunk cannot be found by vector search.
+fn warn_long_chunks(filename: &str, chunks: &[Chunk]) -> anyhow::Result<()> {
+    let max_tokens = max_embedding_tokens();
+    let mut long = 0;
+    for chunk in chunks {
+        if count_tokens(&chunk.text)? > max_tokens {
+            long += 1;
+        }
+    }
+    if long > 0 {
+        println!("Warning: {} of {} chunks of {} are longer than the {} tokens of the embedding model, \
+                  use a smaller --chunksize or --chunk-strategy tokens.", long, chunks.len(), filename, max_tokens);
+    }
+    Ok(())
+}
+
+/// The files to ingest for a path: the file itself, or the files below
+/// a directory.
+pub fn path_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if path.is_dir() {
+        read_dir_contents(path)
+    } else if path.is_file() {
+        Ok(vec![path.to_path_buf()])
+    } else {
+        anyhow::bail!("\"{}\" does not exist.", path.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::Strategy;
+    use crate::database::data_to_record;
+    use crate::manifest::FileEntry;
+
+    #[test]
+    fn parse_store() {
+        assert_eq!("Both".parse::<Store>().unwrap(), Store::Both);
+        assert!("vector".parse::<Store>().unwrap().vector());
+        assert!(!"vector".parse::<Store>().unwrap().text());
+        assert!("index".parse::<Store>().is_err());
+    }
+
+    #[test]
+    fn chunks_are_numbered() {
+        let mut file = tempfile::Builder::new().suffix(".txt").tempfile().unwrap();
+        std::io::Write::write_all(&mut file, b"We have a cat called Sirius.").unwrap();
+        let chunker = Chunker::new(Strategy::Chars, 256, 0).unwrap();
+        let chunks = read_chunks(file.path(), &chunker).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].number, 0);
+        assert_eq!(chunks[0].page, None);
+        assert_eq!(chunk_id(&chunks[0].filename, chunks[0].number), format!("{}#0", file.path().display()));
+    }
+
+    #[test]
+    fn missing_files_deleted_on_commit() {
+        let emb = vec![1.0f32, 0.0];
+        let records: Vec<Record> = (0..3).map(|i| data_to_record(&emb, &format!("/no/such/dir/{}.txt", i), "text", 0, None, None)).collect();
+        let mut collection = Collection::new(&Config::default());
+        let ids = collection.insert_many(&records).unwrap();
+        let mut manifest = Manifest::default();
+        for (i, id) in ids.iter().enumerate() {
+            manifest.files.insert(format!("/no/such/dir/{}.txt", i), FileEntry { ids: vec![id.0], ..Default::default() });
+        }
+        let current = vec![PathBuf::from("/no/such/dir/2.txt")];
+        {
+            let chunker = Chunker::new(Strategy::Chars, 256, 0).unwrap();
+            let mut pipeline = Pipeline::new(chunker).with_vector(&mut collection, &mut manifest);
+            let removed = pipeline.remove_missing(Path::new("/no/such/dir"), &current).unwrap();
+            assert_eq!(removed.len(), 3); // They do not exist either.
+            pipeline.commit().unwrap();
+        }
+        assert!(collection.is_empty());
+        assert!(manifest.files.is_empty());
+    }
+
+    #[test]
+    fn unreadable_changed_file_keeps_its_records() {
+        let mut file = tempfile::Builder::new().suffix(".docx").tempfile().unwrap();
+        std::io::Write::write_all(&mut file, b"Saved half-way, not a zip archive.").unwrap();
+        let filename = file.path().to_string_lossy().to_string();
+        let mut collection = Collection::new(&Config::default());
+        let ids = collection.insert_many(&[data_to_record(&vec![1.0f32, 0.0], &filename, "Sirius.", 0, None, None)]).unwrap();
+        let mut manifest = Manifest::default();
+        manifest.files.insert(filename.clone(), FileEntry::new(0, "old".to_string(), vec![ids[0].0], BTreeMap::new()));
+        let mut errors = vec![];
+        {
+            let chunker = Chunker::new(Strategy::Chars, 256, 0).unwrap();
+            let mut pipeline = Pipeline::new(chunker).with_vector(&mut collection, &mut manifest);
+            let stats = pipeline.add_files(&[file.path().to_path_buf()], &mut |_, result| {
+                errors.extend(result.err().map(|e| format!("{:#}", e)));
+            }).unwrap();
+            assert_eq!(stats.errors, 1);
+            pipeline.commit().unwrap();
+        }
+        assert!(errors[0].starts_with("the previous version is kept"), "{}", errors[0]);
+        assert_eq!(collection.len(), 1);
+        assert_eq!(manifest.files[&filename].ids, vec![ids[0].0]);
+    }
+}
@@ -0,0 +1,137 @@
+use once_cell::sync::OnceCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// =====================================================================
+// Knowledge bases. Everything is stored below the data directory
+// (--data-dir, or MINERVA_DATA_DIR, "db" by default):
+//
+//   db/oasys db/tantivy db/settings db/manifest   the default one
+//   db/kb/<name>/oasys ...                        named ones (--kb)
+//
+// A knowledge base bundles a vector database, a tantivy index with its
+// languages, and the settings (embedding model, distance, chunking) and
+// manifests of its collections. The default one has the layout Minerva
+// always had, so existing databases keep working.
+// =====================================================================
+
+const DEFAULT_DATA_DIR: &str = "db";
+const KB_DIR: &str = "kb";
+
+// Chosen once, before the first database is opened.
+static DATA_DIR: OnceCell<PathBuf> = OnceCell::new();
+static KB_NAME: OnceCell<Option<String>> = OnceCell::new();
+
+/// Selects the data directory, and the named knowledge base in it (or
+/// the default one).
+pub fn set_knowledge_base(data_dir: &Path, name: Option<&str>) -> anyhow::Result<()> {
+    if let Some(name) = name {
+        check_name(name)?;
+    }
+    if DATA_DIR.set(data_dir.to_path_buf()).is_err() || KB_NAME.set(name.map(String::from)).is_err() {
+        anyhow::bail!("The knowledge base has already been chosen.");
+    }
+    Ok(())
+}
+
+// The name becomes a directory name.
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        anyhow::bail!("Knowledge base name \"{}\" should only contain letters, digits, - and _.", name);
+    }
+    Ok(())
+}
+
+pub fn data_dir() -> PathBuf {
+    DATA_DIR.get().cloned().unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR))
+}
+
+/// The name of the knowledge base, None for the default one.
+pub fn kb_name() -> Option<String> {
+    KB_NAME.get().cloned().flatten()
+}
+
+fn kb_path(data_dir: &Path, name: Option<&str>) -> PathBuf {
+    match name {
+        Some(name) => data_dir.join(KB_DIR).join(name),
+        None => data_dir.to_path_buf(),
+    }
+}
+
+/// The directory of the knowledge base.
+pub fn kb_dir() -> PathBuf {
+    kb_path(&data_dir(), kb_name().as_deref())
+}
+
+pub fn oasys_path() -> PathBuf {
+    kb_dir().join("oasys")
+}
+
+pub fn tantivy_path() -> PathBuf {
+    kb_dir().join("tantivy")
+}
+
+/// Schema version and languages of the tantivy index.
+pub fn tantivy_settings_path() -> PathBuf {
+    kb_dir().join("tantivy.json")
+}
+
+/// The files in the tantivy index, like the manifests of the collections.
+pub fn tantivy_manifest_path() -> PathBuf {
+    kb_dir().join("tantivy_manifest.json")
+}
+
+pub fn settings_dir() -> PathBuf {
+    kb_dir().join("settings")
+}
+
+pub fn manifest_dir() -> PathBuf {
+    kb_dir().join("manifest")
+}
+
+/// Whether anything has been stored in the knowledge base yet.
+pub fn kb_exists() -> bool {
+    oasys_path().exists() || tantivy_path().exists()
+}
+
+/// "knowledge base \"notes\" in /home/me/minerva/db/kb/notes", with an
+/// absolute path, so it is clear which database is used.
+pub fn kb_description() -> String {
+    let dir = kb_dir();
+    let dir = std::path::absolute(&dir).unwrap_or(dir);
+    match kb_name() {
+        Some(name) => format!("knowledge base \"{}\" in {}", name, dir.display()),
+        None => format!("default knowledge base in {}", dir.display()),
+    }
+}
+
+/// The named knowledge bases in the data directory, sorted.
+pub fn list_knowledge_bases() -> anyhow::Result<Vec<String>> {
+    let dir = data_dir().join(KB_DIR);
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn knowledge_base_paths() {
+        assert_eq!(kb_path(Path::new("db"), None), PathBuf::from("db"));
+        assert_eq!(kb_path(Path::new("/data"), Some("notes")), PathBuf::from("/data/kb/notes"));
+        assert!(check_name("team-notes_2").is_ok());
+        assert!(check_name("../notes").is_err());
+        assert!(check_name("").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use oasysdb::prelude::*;
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::database::md_to_json;
+use crate::filter::Filter;
+use crate::tant::{doc_to_json, get_documents, get_index_schema};
+
+// =====================================================================
+// Listing and exporting the chunks in the databases, a page at a time.
+// A chunk is a JSON object with all its fields: the metadata of a
+// vector record (plus its "id"), or the stored fields of a tantivy
+// document. Only the path filters of the Filter are used.
+// =====================================================================
+
+pub type Row = Map<String, Value>;
+
+/// The records of the vector collection, in the order of their IDs.
+/// The records before the offset are read to check the filter, but are
+/// not kept.
+pub fn vector_rows(collection: &Collection, filter: &Filter, offset: usize, limit: Option<usize>) -> anyhow::Result<Vec<Row>> {
+    let mut rows = vec![];
+    let mut seen = 0;
+    let mut skipped = 0;
+    let mut id = 0u32;
+    while seen < collection.len() && limit.is_none_or(|limit| rows.len() < limit) {
+        let vector_id = VectorID(id);
+        id += 1;
+        if !collection.contains(&vector_id) {
+            continue;
+        }
+        seen += 1;
+        let mut row = match md_to_json(&collection.get(&vector_id)?.data) {
+            Value::Object(row) => row,
+            value => Row::from_iter([("data".to_string(), value)]),
+        };
+        if !filter.matches(row.get("filename").and_then(|v| v.as_str()).unwrap_or(""), None) {
+            continue;
+        }
+        if skipped < offset {
+            skipped += 1;
+            continue;
+        }
+        row.insert("id".to_string(), Value::from(vector_id.0));
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// The documents of the text database, in the order they are stored.
+pub fn text_rows(filter: &Filter, offset: usize, limit: Option<usize>) -> anyhow::Result<Vec<Row>> {
+    let (_index, schema) = get_index_schema()?;
+    let documents = get_documents(offset, limit, &|title| filter.matches(title, None))?;
+    Ok(documents.iter().map(|d| doc_to_json(&schema, d)).collect())
+}
+
+fn field(row: &Row, name: &str) -> String {
+    match row.get(name) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Prints the rows for reading, with the filename, chunk (and page),
+/// date and ULID first, and then the text.
+pub fn print_rows(rows: &[Row]) {
+    for row in rows {
+        let (filename, chunk) = match row.get("title") {
+            Some(_) => (field(row, "title"), field(row, "chunk_number")),
+            None => (field(row, "filename"), field(row, "ccnt")),
+        };
+        let page = match field(row, "page").as_str() {
+            "" | "0" => match field(row, "page_number").as_str() {
+                "" | "0" => String::new(),
+                page => format!(" p.{}", page),
+            },
+            page => format!(" p.{}", page),
+        };
+        let id = match row.get("id") {
+            Some(id) => format!("{:5} | ", id),
+            None => String::new(),
+        };
+        let extra: Vec<String> = ["date", "ulid", "language"].iter().map(|f| field(row, f)).filter(|v| !v.is_empty()).collect();
+        println!("{}{}/{}{} | {}", id, filename, chunk, page, extra.join(" | "));
+        let text = if row.contains_key("title") { field(row, "body") } else { field(row, "text") };
+        println!("{:?}\n", text);
+    }
+}
+
+/// Writes the rows to a file, JSONL or CSV depending on the extension.
+pub fn export_rows(rows: &[Row], path: &Path) -> anyhow::Result<()> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    let mut out = BufWriter::new(File::create(path)?);
+    match extension.as_str() {
+        "jsonl" | "json" => {
+            for row in rows {
+                writeln!(out, "{}", serde_json::to_string(row)?)?;
+            }
+        }
+        "csv" => write_csv(rows, &mut out)?,
+        _ => anyhow::bail!("Cannot export to \"{}\", use a .jsonl or .csv file.", path.display()),
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// One column per field of any of the rows, "id" first.
+fn write_csv(rows: &[Row], out: &mut dyn Write) -> anyhow::Result<()> {
+    let names: BTreeSet<&String> = rows.iter().flat_map(|row| row.keys()).collect();
+    let mut columns: Vec<&String> = names.into_iter().collect();
+    columns.sort_by_key(|name| name.as_str() != "id");
+    writeln!(out, "{}", columns.iter().map(|c| csv_field(c)).collect::<Vec<_>>().join(","))?;
+    for row in rows {
+        writeln!(out, "{}", columns.iter().map(|c| csv_field(&field(row, c))).collect::<Vec<_>>().join(","))?;
+    }
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::data_to_record;
+
+    #[test]
+    fn vector_pages() {
+        let mut collection = Collection::new(&Config::default());
+        for (i, filename) in ["a.txt", "b.txt", "a.txt"].iter().enumerate() {
+            collection.insert(&data_to_record(&vec![i as f32, 1.0], filename, "text", i, None, None)).unwrap();
+        }
+        let all = Filter::default();
+        assert_eq!(vector_rows(&collection, &all, 0, None).unwrap().len(), 3);
+        let page = vector_rows(&collection, &all, 1, Some(1)).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0]["id"], Value::from(1));
+        let filter = Filter::new(&["a.txt".to_string()], None, None, &[]).unwrap();
+        let page = vector_rows(&collection, &filter, 1, Some(5)).unwrap();
+        assert_eq!(page.iter().map(|r| r["ccnt"].clone()).collect::<Vec<_>>(), vec![Value::from(2)]);
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let rows: Vec<Row> = vec![
+            serde_json::from_str(r#"{"text": "a, \"b\"", "id": 3}"#).unwrap(),
+            serde_json::from_str(r#"{"filename": "x.txt"}"#).unwrap(),
+        ];
+        let mut out = vec![];
+        write_csv(&rows, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "id,filename,text\n3,,\"a, \"\"b\"\"\"\n,x.txt,\n");
+    }
+}
@@ -1,30 +1,57 @@
 use oasysdb::prelude::*;
 use clap::{Parser, Subcommand};
 mod database;
-use database::{get_db, data_to_record};
+use database::{get_db, parse_distance, distance_name, similarity};
 mod embedder;
-use embedder::{chunk_string, embed_file_txt, embed_file_pdf, embeddings, read_dir_contents, get_embedding_dim};
+mod extract;
+use embedder::{get_embedding_dim, set_batch_size,
+               set_embedding_model, get_embedding_model_name};
+mod settings;
+mod kb;
+use kb::{set_knowledge_base, kb_exists, kb_description, list_knowledge_bases};
+mod manifest;
+use manifest::{Manifest, load_manifest, save_manifest, delete_manifest, delete_text_manifest};
+mod chunker;
+use chunker::{Chunker, Strategy};
+mod ingest;
+use ingest::{FileReport, Ingested, Pipeline, Store, path_files};
+use std::collections::BTreeMap;
+use settings::{ChunkSettings, CollectionSettings, load_settings, save_settings, delete_settings};
 mod textgen;
 //use textgen::{load_model, generate_answer};
 use std::path::Path;
+use std::io::Write;
 mod qmistral;
-use qmistral::run_qmistral;
-use std::collections::HashMap;
+use qmistral::QModelConfig;
+mod qgpt2;
+mod mistral;
+mod generator;
+use generator::{get_generator, GenOptions};
 mod tant;
-use tant::{search_documents, insert_file, get_index_schema,
-    get_num_documents, get_all, del_all, text_from_owned_value, u64_from_owned_value};
-use tantivy::schema::OwnedValue;
+use tant::{search_documents, get_index_schema, set_text_languages, rebuild_text_index, KeywordOptions,
+    doc_text, doc_u64, highlight, get_num_documents, del_all};
 mod genaigen;
 mod ollamagen;
-use ollamagen::ollama_generate;
+mod retriever;
+mod listing;
+use listing::{export_rows, print_rows, text_rows, vector_rows};
+mod snapshot;
+use snapshot::{export_snapshot, import_snapshot};
+mod filter;
+use filter::{parse_tags, Filter};
+mod reranker;
+use reranker::{num_candidates, rerank, set_reranker_model};
+use retriever::{expand_window, hybrid_search, vector_search, RetrievedChunk};
+mod rag;
+use rag::{assemble_prompt, Answer};
+mod server;
+mod chat;
 
 // =====================================================================
-// Store multiple sizes, eg 256 and 1024. Then search on the 256,
-// but return the longer 1024, so we get "more context", but also
-// more specific searching. (A poor man's version of returning the
-// chunks that come before and after the found chunk. (Or store
-// plus/minus chunks as well when creating chunks, there we have
-// all the info...
+// Small-to-big: store small chunks (eg 256) for more specific
+// searching, and return the chunks that come before and after the
+// found chunk (--window) so we get "more context". See
+// retriever::expand_window().
 // =====================================================================
 
 // =====================================================================
@@ -42,9 +69,33 @@ struct Args {
     #[arg(long, short = 'F', help = "The file to add to the text database.")]
     pub text_filename: Option<String>,
 
+    #[arg(long, env = "MINERVA_DATA_DIR", default_value = "db", help = "Directory with the databases.")]
+    pub data_dir: String,
+
+    #[arg(long, env = "MINERVA_KB", help = "Name of the knowledge base in the data directory. Without it, the default one.")]
+    pub kb: Option<String>,
+
+    #[arg(long, help = "Languages of the text database, e.g. sv,en, for stemming and stop words. New text databases use sv,en; use rebuild-text to change them.")]
+    pub languages: Option<String>,
+
     // Chunk size
-    #[clap(long, action, default_value_t = 1024, help = "Chunk size (characters) for vectors.")]
-    pub chunksize: usize,
+    #[clap(long, help = "Chunk size (characters, or tokens with --chunk-strategy tokens). Defaults to the one of the collection, or 1024.")]
+    pub chunksize: Option<usize>,
+
+    #[arg(long, help = "How to chunk: chars, tokens (of the embedding model), markdown (split at headings) or sentences. Defaults to the one of the collection, or chars.")]
+    pub chunk_strategy: Option<String>,
+
+    #[arg(long, help = "Overlap between consecutive chunks, in the unit of the chunk size. Defaults to the one of the collection, or 0.")]
+    pub overlap: Option<usize>,
+
+    #[arg(long, help = "Embedding model (fastembed name, e.g. MultilingualE5Small). Defaults to the model of the collection, or AllMiniLML6V2.")]
+    pub embedding_model: Option<String>,
+
+    #[arg(long, default_value_t = 256, help = "Number of chunks embedded at the same time.")]
+    pub batch_size: usize,
+
+    #[arg(long, default_value_t = 1000, help = "Commit the text database every this many files while ingesting, 0 only at the end.")]
+    pub commit_every: usize,
 
     // Name of the database (collection)
     #[arg(long, default_value = "vectors", help = "Name of the database collection.")]
@@ -56,13 +107,46 @@ struct Args {
     #[arg(short = 'D', long, help = "Directory with text files to add to the tantivy database.")]
     pub tantdirname: Option<String>,
 
-    #[arg(short, long, help = "Maximum distance between vectors.", default_value_t = 0.6500)]
-    pub maxdist: f32,
+    #[arg(short = 's', long, help = "Minimum similarity (0..1) between the question and the vectors.", default_value_t = 0.79)]
+    pub minsim: f32,
+
+    #[arg(short, long, conflicts_with = "minsim", help = "Deprecated, use --minsim. Maximum euclidean distance, converted to the minimum similarity.")]
+    pub maxdist: Option<f32>,
+
+    #[arg(long, help = "Distance metric for new collections: euclidean or cosine.")]
+    pub distance: Option<String>,
 
     // The k-nearest neighbours.
     #[clap(short, long, action, default_value_t = 3, help = "The k-nearest neighbours when retreiving vectors.")]
     pub nearest: usize,
 
+    #[arg(long, short = 'w', default_value_t = 0, help = "Number of neighbouring chunks before and after a retrieved chunk added to the context.")]
+    pub window: usize,
+
+    #[arg(long, help = "Tag for the ingested files, key=value, can be repeated.")]
+    pub tag: Vec<String>,
+
+    #[arg(long, help = "Only search in the files below this directory, or matching this glob (\"texts/**/*.pdf\"), can be repeated.")]
+    pub filter_path: Vec<String>,
+
+    #[arg(long, help = "Only search in the files ingested on or after this date (2024-05-01).")]
+    pub since: Option<String>,
+
+    #[arg(long, help = "Only search in the files ingested on or before this date (2024-05-31).")]
+    pub until: Option<String>,
+
+    #[arg(long, help = "Only search in the files with this tag, key=value, can be repeated.")]
+    pub filter_tag: Vec<String>,
+
+    #[arg(long, action, help = "Rerank the retrieved chunks with a cross-encoder, and keep the best --nearest.")]
+    pub rerank: bool,
+
+    #[arg(long, default_value = "BGERerankerBase", help = "Cross-encoder for --rerank (fastembed name, e.g. JINARerankerV2BaseMultiligual for Swedish).")]
+    pub rerank_model: String,
+
+    #[arg(long, default_value_t = 20, help = "Number of chunks retrieved for --rerank.")]
+    pub candidates: usize,
+
     // Query
     #[arg(short, long, help = "The question to answer by the system.")]
     pub query: Option<String>,
@@ -71,13 +155,58 @@ struct Args {
     #[arg(short, long, help = "Keyword to search for in the tantivy database.")]
     pub keyword: Option<String>,
 
-    #[arg(long, short, action, help = "Use Ollama for generation.")]
-    pub ollama: bool,
+    #[arg(long, default_value_t = 10, help = "Maximum number of keyword search results.")]
+    pub keyword_limit: usize,
+
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2), help = "Edit distance (0-2) for fuzzy keyword search.")]
+    pub fuzzy: u8,
+
+    #[arg(long, action, help = "Keyword search results must contain all terms, instead of any of them.")]
+    pub all_terms: bool,
 
-    #[arg(long, short = 'O', default_value = "mistral", help = "Ollama model to use.")]
-    pub ollama_model: String,
+    #[arg(long, default_value_t = 1.0, help = "Weight of matches in the filename in keyword search.")]
+    pub title_boost: f32,
+
+    #[arg(long, default_value_t = 1.0, help = "Weight of matches in the text in keyword search.")]
+    pub body_boost: f32,
+
+    #[arg(long, short = 'H', action, help = "Hybrid retrieval, combines the vector and the tantivy database.")]
+    pub hybrid: bool,
+
+    #[arg(long, short, default_value = "qmistral", help = "Backend for generation: qmistral (any GGUF model), mistral, phi, ollama or genai.")]
+    pub backend: String,
+
+    #[arg(long, short = 'O', default_value = "mistral", help = "Model to use with the ollama and genai backends.")]
+    pub model: String,
+
+    #[arg(long, default_value = "TheBloke/Mistral-7B-Instruct-v0.2-GGUF", help = "Huggingface repository with the GGUF model.")]
+    pub gguf_repo: String,
+
+    #[arg(long, default_value = "mistral-7b-instruct-v0.2.Q5_K_M.gguf", help = "GGUF file in the repository, or path to a local GGUF file.")]
+    pub gguf_file: String,
+
+    #[arg(long, default_value = "mistralai/Mistral-7B-v0.1", help = "Huggingface repository with tokenizer.json, or path to a local tokenizer.json.")]
+    pub tokenizer: String,
+
+    #[arg(long, help = "Prompt template for the GGUF model: mistral, chatml, llama3 or gpt-sw3. Guessed from the model if not given.")]
+    pub template: Option<String>,
+
+    #[arg(long, help = "Maximum number of tokens to generate.")]
+    pub max_tokens: Option<usize>,
+
+    #[arg(long, help = "Temperature for sampling, 0 is greedy.")]
+    pub temperature: Option<f64>,
+
+    #[arg(long, help = "Nucleus sampling probability cutoff.")]
+    pub top_p: Option<f64>,
+
+    #[arg(long, help = "Seed for sampling.")]
+    pub seed: Option<u64>,
 
     // Extra output
+    #[arg(long, default_value = "text", value_parser = ["text", "json"], help = "Output of the answer: text, or json with the sources and citations.")]
+    pub output: String,
+
     #[arg(long, short, action, help = "Produce superfluous output.")]
     pub verbose: bool,
 
@@ -93,10 +222,25 @@ struct Args {
 
 #[derive(Debug, Subcommand, Clone)]
 pub enum Commands {
-    /// List vector DB collection.
+    /// Lists the chunks in the vector or text database, a page at a
+    /// time, or exports them.
+    #[command(arg_required_else_help = true)]
     List {
-        /// The database to list.
+        /// The database to list, "vector" or "text".
         database: Option<String>,
+        /// Number of chunks to skip.
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Number of chunks to list, 20 by default, all when exporting.
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Only the chunks of the files below this directory, or
+        /// matching this glob, can be repeated.
+        #[arg(long)]
+        file: Vec<String>,
+        /// Write the chunks with all their fields to a .jsonl or .csv file.
+        #[arg(long)]
+        export: Option<String>,
     },
 
     /// Deletes the vector database.
@@ -105,41 +249,290 @@ pub enum Commands {
         /// The database to delete.
         database: Option<String>,
     },
+
+    /// Adds a file, or all files below a directory, to the databases.
+    #[command(arg_required_else_help = true)]
+    Ingest {
+        /// The file or directory.
+        path: String,
+        /// Add to "vector", "text" or "both".
+        #[arg(long, default_value = "both")]
+        store: String,
+    },
+
+    /// Removes a file, or all files below a directory, from the databases.
+    #[command(arg_required_else_help = true)]
+    Remove {
+        /// The file or directory, as it was added.
+        path: String,
+        /// Remove from "vector", "text" or "both".
+        #[arg(long, default_value = "both")]
+        store: String,
+    },
+
+    /// Removes a file, or all files below a directory, and adds them again.
+    #[command(arg_required_else_help = true)]
+    Reindex {
+        /// The file or directory, as it was added.
+        path: String,
+        /// Reindex in "vector", "text" or "both".
+        #[arg(long, default_value = "both")]
+        store: String,
+    },
+
+    /// Interactive chat, with follow-up questions.
+    Chat,
+
+    /// Lists the named knowledge bases in the data directory.
+    Bases,
+
+    /// Rebuilds the text database from its contents, with the current
+    /// schema and --languages.
+    RebuildText,
+
+    /// Writes the knowledge base (collections, their settings and
+    /// manifests, and the text database) to one archive.
+    #[command(arg_required_else_help = true)]
+    Export {
+        /// The archive to write, a .zip file.
+        path: String,
+    },
+
+    /// Restores an archive made by export into a new, or empty,
+    /// knowledge base.
+    #[command(arg_required_else_help = true)]
+    Import {
+        /// The archive to read.
+        path: String,
+    },
+
+    /// Runs an HTTP server with ingest, search and ask endpoints.
+    Serve {
+        /// Address to listen on.
+        #[arg(long, default_value = "127.0.0.1:3000")]
+        address: String,
+    },
 }
 
 // =====================================================================
 // Main.
 // =====================================================================
-// Function to convert Metadata of type Object back into a HashMap
 
-fn md_to_hashmap(metadata: &Metadata) -> Option<HashMap<String, Metadata>> {
-    match metadata {
-        Metadata::Object(hm) => Some(hm.clone()),
-        _ => None
+// One file should not stop the ingestion of the others.
+fn report_file(result: anyhow::Result<FileReport>) {
+    match result {
+        Ok(report) => {
+            match report.vector {
+                Some(Ingested::Unchanged) => print!(", unchanged"),
+                Some(Ingested::Added(num)) => print!(", Items {}", num),
+                Some(Ingested::Replaced(num)) => print!(", changed, Items {}", num),
+                None => {}
+            }
+            if let Some(num) = report.text {
+                print!(", text items {}", num);
+            }
+            println!();
+        }
+        Err(e) => println!(", error: {}", e),
+    }
+}
+
+fn report_removed((removed, num): (BTreeMap<String, usize>, u64)) {
+    for (filename, num) in &removed {
+        println!("Removed {}, Items {}", filename, num);
     }
+    println!("Removed {} vector items from {} files, {} text items.", removed.values().sum::<usize>(), removed.len(), num);
 }
 
-fn md_to_str(metadata: &Metadata) -> Option<String> {
-    match metadata {
-        Metadata::Text(txt) => Some(txt.to_string()),
-        Metadata::Integer(i) => Some(i.to_string()),
-        _ => None,
+// The chunking asked for, or else the one stored for the collection.
+fn chunking(args: &Args, stored: Option<&ChunkSettings>) -> anyhow::Result<ChunkSettings> {
+    let strategy: Strategy = match (&args.chunk_strategy, stored) {
+        (Some(strategy), _) => strategy.parse()?,
+        (None, Some(stored)) => stored.strategy.parse()?,
+        (None, None) => Strategy::Chars,
+    };
+    Ok(ChunkSettings {
+        strategy: strategy.to_string(),
+        size: args.chunksize.or(stored.map(|s| s.size)).unwrap_or(1024),
+        overlap: args.overlap.or(stored.map(|s| s.overlap)).unwrap_or(0),
+    })
+}
+
+fn chunker(chunking: &ChunkSettings) -> anyhow::Result<Chunker> {
+    Chunker::new(chunking.strategy.parse()?, chunking.size, chunking.overlap)
+}
+
+// Only these add to the databases, the rest needs an existing
+// knowledge base.
+fn creates_kb(args: &Args) -> bool {
+    args.dirname.is_some() || args.tantdirname.is_some() || args.filename.is_some() || args.text_filename.is_some()
+        || matches!(args.command, Some(Commands::Ingest { .. }) | Some(Commands::Reindex { .. })
+                            | Some(Commands::Import { .. }) | Some(Commands::Serve { .. }))
+}
+
+fn filter(args: &Args) -> anyhow::Result<Filter> {
+    Filter::new(&args.filter_path, args.since.as_deref(), args.until.as_deref(), &args.filter_tag)
+}
+
+fn pipeline<'a>(collection: &'a mut Collection, manifest: &'a mut Manifest, store: Store, chunker: Chunker,
+                tags: BTreeMap<String, String>, commit_every: usize) -> anyhow::Result<Pipeline<'a>> {
+    let mut pipeline = Pipeline::new(chunker).with_tags(tags).with_commit_every(commit_every);
+    if store.vector() {
+        pipeline = pipeline.with_vector(collection, manifest);
+    }
+    if store.text() {
+        let (index, _schema) = get_index_schema()?;
+        pipeline = pipeline.with_text(index)?;
+    }
+    Ok(pipeline)
+}
+
+// Adds a file, or the files below a directory, to the stores, and makes
+// it persistent. Files which have disappeared from the directory since
+// the last time are removed.
+fn ingest_path(db: &mut Database, args: &Args, collection: &mut Collection, manifest: &mut Manifest,
+               path: &Path, store: Store, chunker: Chunker) -> anyhow::Result<()> {
+    let name = &args.collection;
+    let filenames = path_files(path)?;
+    {
+        let mut pipeline = pipeline(collection, manifest, store, chunker, parse_tags(&args.tag)?, args.commit_every)?;
+        if path.is_dir() {
+            for (missing, num) in pipeline.remove_missing(path, &filenames)? {
+                println!("Removed {}, Items {}", missing, num);
+            }
+        }
+        let stats = pipeline.add_files(&filenames, &mut |filename, result| {
+            print!("Read {}", filename.display());
+            report_file(result);
+        })?;
+        println!("Ingested {}.", stats.summary());
+        pipeline.commit()?;
+    }
+    if store.vector() {
+        db.save_collection(name, collection)?;
+        save_manifest(name, manifest)?;
+    }
+    Ok(())
+}
+
+fn gguf_config(args: &Args) -> anyhow::Result<QModelConfig> {
+    Ok(QModelConfig {
+        repo: args.gguf_repo.clone(),
+        filename: args.gguf_file.clone(),
+        tokenizer: args.tokenizer.clone(),
+        template: args.template.as_deref().map(|t| t.parse()).transpose()?,
+    })
+}
+
+// With --output json stdout holds only the answer: everything printed
+// before it, by Minerva or by the libraries loading the models, goes to
+// stderr. Returns the real stdout, to write the answer to.
+#[cfg(unix)]
+fn stdout_to_stderr() -> anyhow::Result<Box<dyn Write>> {
+    use std::os::fd::{AsFd, AsRawFd};
+    std::io::stdout().flush()?;
+    let stdout = std::io::stdout().as_fd().try_clone_to_owned()?;
+    // SAFETY: both are open file descriptors of this process.
+    if unsafe { libc::dup2(std::io::stderr().as_raw_fd(), std::io::stdout().as_raw_fd()) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Box::new(std::fs::File::from(stdout)))
+}
+
+#[cfg(not(unix))]
+fn stdout_to_stderr() -> anyhow::Result<Box<dyn Write>> {
+    Ok(Box::new(std::io::stdout()))
+}
+
+fn gen_options(args: &Args) -> GenOptions {
+    GenOptions {
+        max_tokens: args.max_tokens,
+        temperature: args.temperature,
+        top_p: args.top_p,
+        seed: args.seed,
+        repeat_penalty: None,
     }
 }
 
 fn main() -> anyhow::Result<()> {
 
-    let args = Args::parse();
+    let mut args = Args::parse();
+    let mut json_output = match args.output.as_str() {
+        "json" => Some(stdout_to_stderr()?),
+        _ => None,
+    };
+    if let Some(maxdist) = args.maxdist {
+        args.minsim = similarity(maxdist, &Distance::Euclidean);
+        eprintln!("--maxdist is deprecated, use --minsim {:.2} instead.", args.minsim);
+    }
     if args.verbose {
         println!("{:?}", &args);
     }
-    println!("Embedding dim {}", get_embedding_dim().unwrap());
+
+    // Everything below is stored in the knowledge base, so choose it
+    // first. A wrong working directory used to silently give a new,
+    // empty, database.
+    set_knowledge_base(Path::new(&args.data_dir), args.kb.as_deref())?;
+    if let Some(Commands::Bases) = args.command {
+        for name in list_knowledge_bases()? {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+    if !kb_exists() {
+        if !creates_kb(&args) {
+            anyhow::bail!("There is no {}. Set --data-dir (or MINERVA_DATA_DIR) and --kb, or ingest files to create it.", kb_description());
+        }
+        println!("Creating a new {}.", kb_description());
+    } else {
+        println!("Using the {}.", kb_description());
+    }
+    if let Some(languages) = &args.languages {
+        set_text_languages(languages)?;
+    }
+    if let Some(Commands::RebuildText) = args.command {
+        let (settings, num) = rebuild_text_index()?;
+        println!("Rebuilt the text database, {} items, {}.", num, settings.describe());
+        return Ok(());
+    }
+
+    // Snapshots do not need the embedding model, the lab machine they
+    // are imported on may not have it yet.
+    match &args.command {
+        Some(Commands::Export { path }) => {
+            let info = export_snapshot(&get_db()?, Path::new(path))?;
+            println!("Exported {} to {}.", info.describe(), path);
+            return Ok(());
+        }
+        Some(Commands::Import { path }) => {
+            let info = import_snapshot(&mut get_db()?, Path::new(path), args.embedding_model.as_deref())?;
+            println!("Imported {} from {}.", info.describe(), path);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // The embedding model is stored with the collection, use that one
+    // unless another one is asked for.
+    let stored_settings = load_settings(&args.collection)?;
+    let embedding_model = match (&args.embedding_model, &stored_settings) {
+        (Some(model), _) => model.clone(),
+        (None, Some(settings)) => settings.embedding_model.clone(),
+        (None, None) => "AllMiniLML6V2".to_string(),
+    };
+    set_embedding_model(&embedding_model)?;
+    let embedding_dim = get_embedding_dim()?;
+    println!("Embedding model {}, dim {}", get_embedding_model_name(), embedding_dim);
+    set_batch_size(args.batch_size);
+    if args.rerank {
+        set_reranker_model(&args.rerank_model)?;
+    }
 
     // test
     //genai_generate("Why is the sky blue?");
 
     //_ = tanttest();
-    let (i, _s) = get_index_schema().unwrap();
+    let (i, _s) = get_index_schema()?;
     let num_docs = get_num_documents(&i)?;
     println!("Number of documents in the tantivy database: {}", num_docs);
 
@@ -152,159 +545,176 @@ fn main() -> anyhow::Result<()> {
     // _ = load_model();
 
     // This is the saved DB, containing different collections.
-    let mut db = get_db();
+    let mut db = get_db()?;
+    let distance = match &args.distance {
+        Some(name) => Some(parse_distance(name)?),
+        None => None,
+    };
     let mut collection = db.get_collection(&args.collection).unwrap_or_else(|_| {
         println!("Creating a new empty collection.");
-        let  config = Config::default();
-        //config.distance = Distance::Cosine;
+        let mut config = Config::default();
+        config.distance = distance.unwrap_or(Distance::Euclidean);
         //Collection::build(&config, &records).unwrap()
         let c = Collection::new(&config);
         db.save_collection(&args.collection, &c).unwrap(); // Save it so it exists on disk.
-        /*
-        match db.save_collection(&args.collection, &c) {
-            Ok(_) => c,
-            Err(e) => {
-                eprintln!("Failed to save the new collection: {}", e);
-                panic!("Critical error: could not save collection");
-            }
-        }
-        */
         c
     });
 
-    // This code can be simplified by putting a single file in a vector and
-    // also treating it as "-d".
-    // Separate functions for the tantivy database?
-    if let Some(dirname) = &args.dirname {
-        let mut records = vec![];
-        let filenames = read_dir_contents(dirname).unwrap();
-        for filename in filenames {
-            let filename_str = filename.clone().into_os_string().into_string().unwrap();
-            print!("Reading {}", filename_str); // Check extension here maybe...
-
-            // Should check extension...
-            let chunked_data = Some(embed_file_txt(filename, args.chunksize).expect("File does not exist?"));
-
-            if let Some(data) = chunked_data {
-                let vectors = embeddings(data.clone()).expect("Cannot create embeddings.");
-                let mut chunk_counter = 0usize;
-                for (chunk, vector) in data.iter().zip(vectors.iter()) {
-                let record = data_to_record(vector, &filename_str, chunk, chunk_counter);
-                    records.push(record);
-                    chunk_counter += 1;
-                }
-                println!(", Items {}", data.len());
-            }
+    // The metric is fixed when the collection is created.
+    if let Some(distance) = distance {
+        if distance != collection.config.distance {
+            anyhow::bail!("Collection \"{}\" uses the {} distance, not {}.",
+                          args.collection, distance_name(&collection.config.distance), distance_name(&distance));
         }
-        if records.len() > 0 {
-            let ids = collection.insert_many(&records).unwrap();
-            println!("Added {:?} items", ids.len());
-        } else {
-            println!("No items to add");
-        }
-
-        // And make it persistent.
-        db.save_collection(&args.collection, &collection).unwrap();
     }
-
+    println!("Distance {}", distance_name(&collection.config.distance));
+
+    // Refuse to mix embedding models in one collection. Collections
+    // from before the settings were stored used AllMiniLML6V2.
+    let mut collection_settings = match stored_settings {
+        Some(settings) => settings,
+        None if collection.len() > 0 => CollectionSettings::new("AllMiniLML6V2", 384, "euclidean"),
+        None => CollectionSettings::new(&get_embedding_model_name(), embedding_dim, &distance_name(&collection.config.distance)),
+    };
+    collection_settings.check_model(&args.collection, &get_embedding_model_name(), embedding_dim)?;
+    collection_settings.distance = distance_name(&collection.config.distance);
+    let chunking = chunking(&args, collection_settings.chunking.as_ref())?;
+    collection_settings.chunking = Some(chunking.clone());
+    save_settings(&args.collection, &collection_settings)?;
+
+    // The files in the collection, so unchanged files are skipped.
+    let mut manifest = match load_manifest(&args.collection)? {
+        Some(manifest) => manifest,
+        None => Manifest::from_collection(&collection)?,
+    };
+
+    // The -d and -f options add to the vector database, -D and -F to the
+    // text database; the ingest command can add to both at once.
+    if let Some(dirname) = &args.dirname {
+        ingest_path(&mut db, &args, &mut collection, &mut manifest, Path::new(dirname), Store::Vector, chunker(&chunking)?)?;
+    }
     if let Some(dirname) = &args.tantdirname {
-        let filenames = read_dir_contents(dirname).unwrap();
-        let (index, _schema) = get_index_schema().unwrap();
-        for filename in filenames {
-            let filename_str = filename.clone().into_os_string().into_string().unwrap();
-            print!("Reading {}...", filename_str); // Check extension here maybe...
-            let num = insert_file(&index, &filename, args.chunksize).unwrap();
-            println!("added {}.", num);
-        }
+        ingest_path(&mut db, &args, &mut collection, &mut manifest, Path::new(dirname), Store::Text, chunker(&chunking)?)?;
     }
-
-    if let Some(filename) = &args.filename { // Add to both oasys and tantivy?
-        let path = Path::new(filename);
-        let mut chunked_data: Option<Vec<String>> = None;
-        if path.is_file() {
-            if let Some(ext) = path.extension() {
-                if ext == "txt" {
-                    println!("Chunking text file.");
-                    chunked_data = Some(embed_file_txt(filename, args.chunksize).expect("File does not exist?"));
-                } else if ext == "pdf" {
-                    println!("Chunking PDF file.");
-                    chunked_data = Some(embed_file_pdf(filename, args.chunksize).expect("File does not exist?"));
-                }
-            }
-        }
-        if let Some(data) = chunked_data {
-            println!("Creating embeddings.");
-            let vectors = embeddings(data.clone()).expect("Cannot create embeddings.");
-            let mut records = vec![];
-            let mut chunk_counter = 0usize;
-            println!("Storing embeddings.");
-            for (chunk, vector) in data.iter().zip(vectors.iter()) {
-                // With custom InitOptions
-                let record = data_to_record(vector, filename, chunk, chunk_counter);
-                //println!("Record {:?}", record);
-                records.push(record);
-                chunk_counter += 1;
-            }
-
-            // Add it to the current collection.
-            // Should check if the vector is already in the DB... should be a function for it.
-            let ids = collection.insert_many(&records).unwrap();
-            println!("Added {:?} items", ids.len());
-
-            // And make it persistent.
-            db.save_collection(&args.collection, &collection).unwrap();
-        }
+    if let Some(filename) = &args.filename {
+        ingest_path(&mut db, &args, &mut collection, &mut manifest, Path::new(filename), Store::Vector, chunker(&chunking)?)?;
     }
-    println!("Size of vector database {}.", collection.len());
-
-    // Separate text database insert.
     if let Some(text_filename) = &args.text_filename {
-        let (index, _schema) = get_index_schema().unwrap();
-        let num = insert_file(&index, &text_filename, args.chunksize).unwrap();
-        println!("Added {} items.", num);
+        ingest_path(&mut db, &args, &mut collection, &mut manifest, Path::new(text_filename), Store::Text, chunker(&chunking)?)?;
     }
+    println!("Size of vector database {}.", collection.len());
 
     // Shouldn't really mix --parameters and commands...
-    match args.command {
-        Some(Commands::List { database }) => {
-            if database == Some("vector".to_string()) { // match database.as_deref() == "vector" ?
-                let list = collection.list().unwrap();
-                for (id, item) in list.iter() {
-                    //println!("{:5} | {:?}", id.0, item.data); // data = Metadata
-                    let hm = md_to_hashmap(&item.data).unwrap();
-                    println!("{:5}/{:?}/{:?}/{:?}/{:?}",
-                        id.0,
-                        md_to_str(hm.get("ulid").unwrap()).unwrap(),
-                        md_to_str(hm.get("date").unwrap()).unwrap(),
-                        md_to_str(hm.get("filename").unwrap()).unwrap(),
-                        md_to_str(hm.get("ccnt").unwrap()).unwrap()
-                    );
-                    println!("{:?}\n", md_to_str(
-                        hm.get("text").unwrap()
-                    ).unwrap());
+    match args.command.clone() {
+        Some(Commands::List { database, offset, limit, file, export }) => {
+            let filter = Filter::new(&file, None, None, &[])?;
+            let limit = if export.is_some() { limit } else { Some(limit.unwrap_or(20)) };
+            let rows = match database.as_deref() {
+                Some("vector") => vector_rows(&collection, &filter, offset, limit)?,
+                Some("text") => text_rows(&filter, offset, limit)?,
+                _ => anyhow::bail!("Choose the database to list, \"vector\" or \"text\"."),
+            };
+            match export {
+                Some(path) => {
+                    export_rows(&rows, Path::new(&path))?;
+                    println!("Exported {} chunks to {}.", rows.len(), path);
                 }
-            } // "vector"
-            if database == Some("text".to_string()) {
-                let x = get_all().unwrap();
-                for (_s, d, _snippet) in x {
-                    //println!("{}", snippet.expect("Empty snippet!").fragment());
-                    println!("{:?}/{:?}/{:?}",
-                        text_from_owned_value(&d.field_values()[0].value), // title/filename
-                        u64_from_owned_value(&d.field_values()[2].value), // page 
-                        u64_from_owned_value(&d.field_values()[2].value) // chunk
-                    );
-                    println!("{:?}\n", text_from_owned_value(&d.field_values()[1].value)); // contents
+                None => {
+                    print_rows(&rows);
+                    if limit.is_some_and(|limit| rows.len() == limit) {
+                        println!("More with --offset {}.", offset + rows.len());
+                    }
                 }
-            } // "text"
+            }
         },
         Some(Commands::Del { database }) => {
             if database == Some("vector".to_string()) { // match database.as_deref() == "vector" ?
                 let _ = db.delete_collection(&args.collection);
+                delete_settings(&args.collection)?;
+                delete_manifest(&args.collection)?;
                 println!("Deleted collection \"{}\"", &args.collection);
             }
             if database == Some("text".to_string()) {
                 let _ = del_all().unwrap();
+                delete_text_manifest()?;
+            }
+        },
+        Some(Commands::Ingest { path, store }) => {
+            let store: Store = store.parse()?;
+            ingest_path(&mut db, &args, &mut collection, &mut manifest, Path::new(&path), store, chunker(&chunking)?)?;
+            println!("Size of vector database {}.", collection.len());
+        },
+        Some(Commands::Remove { path, store }) => {
+            let store: Store = store.parse()?;
+            {
+                let mut pipeline = pipeline(&mut collection, &mut manifest, store, chunker(&chunking)?, BTreeMap::new(), args.commit_every)?;
+                report_removed(pipeline.remove_path(Path::new(&path))?);
+                pipeline.commit()?;
+            }
+            db.save_collection(&args.collection, &collection)?;
+            save_manifest(&args.collection, &manifest)?;
+            println!("Size of vector database {}.", collection.len());
+        },
+        Some(Commands::Reindex { path, store }) => {
+            let store: Store = store.parse()?;
+            let path = Path::new(&path);
+            let filenames = if path.exists() {
+                path_files(path)?
+            } else {
+                println!("{} does not exist, only removing it.", path.display());
+                vec![]
+            };
+            {
+                let mut pipeline = pipeline(&mut collection, &mut manifest, store, chunker(&chunking)?, parse_tags(&args.tag)?, args.commit_every)?;
+                report_removed(pipeline.remove_path(path)?);
+                pipeline.commit()?;
+                let stats = pipeline.add_files(&filenames, &mut |filename, result| {
+                    print!("Read {}", filename.display());
+                    report_file(result);
+                })?;
+                println!("Reindexed {}.", stats.summary());
+                pipeline.commit()?;
             }
+            db.save_collection(&args.collection, &collection)?;
+            save_manifest(&args.collection, &manifest)?;
+            println!("Size of vector database {}.", collection.len());
+        },
+        Some(Commands::Bases) | Some(Commands::RebuildText) | Some(Commands::Export { .. }) | Some(Commands::Import { .. }) => {},
+        Some(Commands::Chat) => {
+            let cfg = chat::ChatConfig {
+                nearest: args.nearest,
+                window: args.window,
+                rerank: args.rerank,
+                candidates: args.candidates,
+                filter: filter(&args)?,
+                minsim: args.minsim,
+                hybrid: args.hybrid,
+                showprompt: args.showprompt,
+                opts: gen_options(&args),
+            };
+            let mut generator = get_generator(&args.backend, &args.model, &gguf_config(&args)?)?;
+            return chat::chat(&cfg, &collection, &manifest, generator.as_mut());
+        },
+        Some(Commands::Serve { address }) => {
+            let cfg = server::ServerConfig {
+                address,
+                collection: args.collection.clone(),
+                chunksize: chunking.size,
+                chunk_strategy: chunking.strategy.parse()?,
+                overlap: chunking.overlap,
+                nearest: args.nearest,
+                window: args.window,
+                rerank: args.rerank,
+                candidates: args.candidates,
+                filter: filter(&args)?,
+                minsim: args.minsim,
+                hybrid: args.hybrid,
+                backend: args.backend.clone(),
+                model: args.model.clone(),
+                gguf: gguf_config(&args)?,
+                opts: gen_options(&args),
+            };
+            return server::serve(cfg, db, collection, manifest);
         },
         None => {}
     }
@@ -314,102 +724,129 @@ fn main() -> anyhow::Result<()> {
     if let Some(keyword) = &args.keyword {
         println!("Keyword {}", &keyword);
 
-        let x = search_documents(&keyword).unwrap();
-        for (_s, d, _snippet) in x {
-            //println!("{:?}", snippet.fragment());
-            //keyword_context += snippet.fragment()
-            //println!("{:?}", d.field_values()[1].value.as_str().unwrap());
-            //keyword_context += d.field_values()[1].value.as_text().unwrap_or(""); //.as_str().unwrap();
-            keyword_context += match &d.field_values()[1].value {
-                OwnedValue::Str(s) => s,
-                _ => {
-                    println!("Warning: Expected text field, found different type");
-                    ""
-                }
+        let opts = KeywordOptions {
+            limit: args.keyword_limit,
+            fuzzy: args.fuzzy,
+            all_terms: args.all_terms,
+            title_boost: args.title_boost,
+            body_boost: args.body_boost,
+        };
+        let (_index, schema) = get_index_schema()?;
+        let x = search_documents(keyword, &opts)?;
+        for (score, d, snippet) in &x {
+            let page = match doc_u64(&schema, d, "page_number") {
+                0 => String::new(),
+                page => format!(" p.{}", page),
             };
+            println!("{:.4} | {}/{}{}", score, doc_text(&schema, d, "title"), doc_u64(&schema, d, "chunk_number"), page);
+            if let Some(snippet) = snippet {
+                println!("         {}", highlight(snippet).replace('\n', " "));
+            }
+            keyword_context += doc_text(&schema, d, "body");
+        }
+        if x.is_empty() {
+            println!("Nothing found :-(");
         }
     }
-    println!("{}", keyword_context);
+    if args.showcontext && !keyword_context.is_empty() {
+        println!("{}", keyword_context);
+    }
 
     // Search for the nearest neighbours.
     if let Some(query) = &args.query {
         println!("Asking \"{}\"", &query);
 
-        let data = chunk_string(query, args.chunksize);
-        //println!("{:?}", data); // Only if verbose!
-        let vectors = embeddings(data).expect("Cannot create embeddings.");
-        let v = vectors.get(0).expect("uh");
-        let embedded_query = Vector((&v).to_vec());
-        //dbg!("{}", &embedded_query);
-        let result = collection.search(&embedded_query, args.nearest).unwrap();
-        //let result = collection.true_search(&embedded_query, args.nearest).unwrap();
-
-        for res in &result {
-            let hm = md_to_hashmap(&res.data).unwrap();
-            let filename = md_to_str(hm.get("filename").unwrap()).unwrap();
-            let chunk_nr = md_to_str(hm.get("ccnt").unwrap()).unwrap();
-            let dist = res.distance;
-            print!("{dist:.4} | {filename}/{chunk_nr}");
-            if dist < args.maxdist {
-                println!(" *");
-            } else {
-                println!(" | filtered");
+        let mut keyword_doc = String::new();
+        let k = num_candidates(args.nearest, args.rerank, args.candidates);
+        let filter = filter(&args)?;
+        let result = if args.hybrid {
+            // One ranked list from both the tantivy and the vector database.
+            let result = hybrid_search(&collection, &manifest, query, k, args.minsim, &filter)?;
+            for res in &result {
+                let sim = res.similarity.map_or("-".to_string(), |d| format!("{d:.4}"));
+                let bm25 = res.bm25.map_or("-".to_string(), |s| format!("{s:.4}"));
+                println!("{:.4} | {} | {:?} sim:{} bm25:{}", res.score, res.label(), res.source, sim, bm25);
+            }
+            if result.is_empty() {
+                println!("Nothing found :-(");
+            }
+            result
+        } else {
+            // Get them all, to show which ones are filtered.
+            let result = vector_search(&collection, &manifest, query, k, 0.0, &filter)?;
+            for res in &result {
+                let sim = res.similarity.unwrap_or(0.0);
+                let dist = res.distance.unwrap_or(0.0);
+                print!("{sim:.4} | {dist:.4} | {}", res.label());
+                if sim >= args.minsim {
+                    println!(" *");
+                } else {
+                    println!(" | filtered");
+                }
             }
-        }
-
-        let mut context_str = String::new();
-        let result: Vec<SearchResult> = result.into_iter().filter(|s| s.distance < args.maxdist).collect();
-        // FIXME logic in the next if/then is suspect.
-        if result.len() == 0 && keyword_context.len() == 0 {
-            println!("All results have been filtered :-(");
-            context_str = "Use any knowledge you have.".to_string();
-        } else if keyword_context.len() > 0 {
-            context_str += &("(document \"keywords\", with contents:".to_owned() + &keyword_context + ")");
-        }
-
-        // Double, cache the results in the first iteration.
-        let mut sep = "";
-        for res in &result {
-            let hm = md_to_hashmap(&res.data).unwrap();
-            let filename = md_to_str(hm.get("filename").unwrap()).unwrap();
-            let chunk_nr = md_to_str(hm.get("ccnt").unwrap()).unwrap();
-            let text = md_to_str(hm.get("text").unwrap()).unwrap();
 
-            if args.showcontext == true {
-                println!("  {}\n", text);
+            let result: Vec<RetrievedChunk> = result.into_iter().filter(|r| r.similarity.unwrap_or(0.0) >= args.minsim).collect();
+            if result.len() == 0 && keyword_context.len() == 0 {
+                println!("All results have been filtered :-(");
+            } else if keyword_context.len() > 0 {
+                keyword_doc = "(document \"keywords\", with contents:".to_owned() + &keyword_context + ")";
             }
-            context_str += &(sep.to_owned() + "\n(document:\"" + &filename + "/" + &chunk_nr + "\", with contents:" + &text + ")");
-            sep = ", ";
-        }
+            result
+        };
+        let result = if args.rerank {
+            let result = rerank(query, result, args.nearest)?;
+            for res in &result {
+                println!("{:.4} | {} reranked", res.rerank.unwrap_or(0.0), res.label());
+            }
+            result
+        } else {
+            result
+        };
+        let result = expand_window(&collection, &manifest, result, args.window)?;
 
         let _ts_start = chrono::Local::now();
 
-        if args.ollama == false {
-            let mut q = format!("You are a friendly and helpful AI assistant. Your answer should be to the point and use the context if possible. Do not make up facts. Print the name of document used from the context. Do not repeat the question or references. Do not invent answers or references. Today is {date}. Context: {context} \nQuestion: {question}", context=context_str, question=query, date=chrono::Local::now().format("%A, %B %e, %Y"));
-            if q.len() > 4096 { // Come to think of it, those might be tokens...
-                println!("Prompt longer than 4096, truncating.");
-                q = q[0..=4095].to_string();
-            }
+        let opts = gen_options(&args);
+        let mut generator = get_generator(&args.backend, &args.model, &gguf_config(&args)?)?;
 
-            //let q = format!("{question}", question=query);
-            //let q = format!("Du är en vänlig och hjälpsam AI-assistent. Ditt svar ska vara kortfattat och använda sammanhanget om möjligt. Skriv ut namnet på det dokument som används från sammanhanget. Upprepa inte frågan eller referenserna. Svara på Svenska! Idag är {date}. Sammanhang: {context}. Fråga: {question}.", context=context_str, question=query, date=chrono::Local::now().format("%A, %B %e, %Y"));
-            let ans = run_qmistral(&q);
-            let _ts_end = chrono::Local::now();
-            //println!("{:?}", ts_end - ts_start);
-            println!("\n{}", ans.unwrap().trim().to_string());
+        // Only the chunks which fit in the context of the model are used.
+        let prompt = assemble_prompt(generator.as_ref(), opts.max_tokens, result, &keyword_doc, &[], query);
+        let messages = prompt.messages;
+        if args.showcontext == true {
+            for (i, res) in prompt.sources.iter().enumerate() {
+                println!("  [{}] {}\n", i + 1, res.context_text());
+            }
+        }
+        if args.showprompt == true {
+            for message in &messages {
+                println!("\n{}", message.content);
+            }
+            println!();
+        }
+        println!("Generating with {}", generator.name());
+        let json = json_output.is_some();
+        if !json {
+            println!(" -- ");
+        }
+        let answer = generator.generate(&messages, &opts, &mut |token| {
+            if !json {
+                print!("{}", token);
+                let _ = std::io::stdout().flush();
+            }
+        })?;
+        let answer = Answer::new(query, &answer, prompt.sources);
+        if let Some(out) = &mut json_output {
+            writeln!(out, "{}", serde_json::to_string(&answer)?)?;
+            out.flush()?;
         } else {
-            // We create a system message and a qustion.
-            let sys_message = format!("You are a friendly and helpful AI assistant. Your answer should be to the point and use the context if possible. Do not make up facts. Print the name of document used from the context. Do not repeat the question or references. Do not invent answers or references. Today is {date}. Context: {context}", context=context_str, date=chrono::Local::now().format("%A, %B %e, %Y"));
-
-            let q = format!("Question: {question}", question=query);
-
-            if args.showprompt == true {
-                println!("\n{}\n", sys_message);
+            println!();
+            if answer.invalid_citations {
+                let invalid: Vec<&str> = answer.citations.iter().filter(|c| c.source.is_none()).map(|c| c.marker.as_str()).collect();
+                println!("Warning: the answer cites documents which were not retrieved: {:?}", invalid);
             }
-
-            //let _ = genai_generate(&sys_message, &q, &args.ollama_model);
-            let _ = ollama_generate(&sys_message, &q, &args.ollama_model);
         }
+        let _ts_end = chrono::Local::now();
+        //println!("{:?}", ts_end - ts_start);
     }
 
     Ok(())
@@ -0,0 +1,357 @@
+use oasysdb::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::database::{md_to_hashmap, md_to_str};
+use crate::kb::{manifest_dir, tantivy_manifest_path};
+
+// Keeps track of which files are in a collection, so files which are
+// ingested again are only embedded when they have changed. Stored as
+// JSON next to the collection settings. The text index has a manifest
+// of its own, whose entries have no IDs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// Modification time, nanoseconds since the epoch.
+    pub mtime: u64,
+    /// blake3 hash of the contents of the file.
+    pub hash: String,
+    /// IDs of the records of the file in the collection.
+    pub ids: Vec<u32>,
+    /// When the file was ingested, as the date of the records.
+    #[serde(default)]
+    pub date: String,
+    /// Tags given when the file was ingested, for filtering.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl FileEntry {
+    /// An entry for a file ingested now.
+    pub fn new(mtime: u64, hash: String, ids: Vec<u32>, tags: BTreeMap<String, String>) -> Self {
+        FileEntry { mtime, hash, ids, date: chrono::Local::now().format("%Y%m%dT%H%M").to_string(), tags }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Keyed on the filename as stored in the records.
+    pub files: BTreeMap<String, FileEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileStatus {
+    New,
+    Unchanged,
+    Changed,
+}
+
+pub fn file_mtime(path: &Path) -> anyhow::Result<u64> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0))
+}
+
+pub fn file_hash(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(fs::File::open(path)?)?;
+    Ok(hasher.finalize().to_string())
+}
+
+impl Manifest {
+    /// Builds a manifest for a collection filled before there were
+    /// manifests. The files get an empty hash, so they are replaced
+    /// (not duplicated) the next time they are ingested.
+    pub fn from_collection(collection: &Collection) -> anyhow::Result<Self> {
+        let mut manifest = Manifest::default();
+        for (id, record) in collection.list()? {
+            let Some(filename) = record_filename(&record) else {
+                continue;
+            };
+            let entry = manifest.files.entry(filename).or_default();
+            entry.ids.push(id.0);
+            let date = md_to_hashmap(&record.data).and_then(|hm| hm.get("date").and_then(md_to_str)).unwrap_or_default();
+            if entry.date.is_empty() || date < entry.date {
+                entry.date = date;
+            }
+        }
+        for entry in manifest.files.values_mut() {
+            entry.ids.sort();
+        }
+        Ok(manifest)
+    }
+
+    /// Builds a manifest for a text index filled before it had one, from
+    /// the filenames of its chunks. The files are replaced the next time
+    /// they are ingested, like in from_collection().
+    pub fn from_filenames(filenames: impl IntoIterator<Item = String>) -> Self {
+        Manifest { files: filenames.into_iter().map(|f| (f, FileEntry::default())).collect() }
+    }
+
+    /// Compares the file with what was ingested last time. The hash is
+    /// only computed when the modification time differs; returns the
+    /// current mtime and hash (if computed) to store afterwards.
+    pub fn status(&self, filename: &str, path: &Path) -> anyhow::Result<(FileStatus, u64, Option<String>)> {
+        let mtime = file_mtime(path)?;
+        let Some(entry) = self.files.get(filename) else {
+            return Ok((FileStatus::New, mtime, None));
+        };
+        if entry.mtime == mtime && !entry.hash.is_empty() {
+            return Ok((FileStatus::Unchanged, mtime, None));
+        }
+        let hash = file_hash(path)?;
+        let status = if hash == entry.hash { FileStatus::Unchanged } else { FileStatus::Changed };
+        Ok((status, mtime, Some(hash)))
+    }
+
+    /// Files in the manifest below dir (or equal to it) which do not
+    /// exist anymore, or are not in the list of current files.
+    pub fn missing_files(&self, dir: &Path, current: &[PathBuf]) -> Vec<String> {
+        self.files.keys()
+            .filter(|f| Path::new(f).starts_with(dir))
+            .filter(|f| !Path::new(f).exists() || !current.iter().any(|c| c == Path::new(f)))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Compares a file with the manifest. An unchanged file only gets its
+/// modification time updated; for a new or changed file the hash of the
+/// contents is returned, to store with its records.
+pub fn check_file(manifest: &mut Manifest, filename: &str, path: &Path) -> anyhow::Result<(FileStatus, u64, String)> {
+    let (status, mtime, hash) = manifest.status(filename, path)?;
+    if status == FileStatus::Unchanged {
+        let entry = manifest.files.get_mut(filename).expect("unchanged files are in the manifest");
+        entry.mtime = mtime;
+        if let Some(hash) = hash {
+            entry.hash = hash;
+        }
+        return Ok((status, mtime, entry.hash.clone()));
+    }
+    let hash = match hash {
+        Some(hash) => hash,
+        None => file_hash(path)?,
+    };
+    Ok((status, mtime, hash))
+}
+
+/// Replaces the records of a file in the collection with new ones,
+/// returns the number of records inserted. Old records which are still
+/// in the manifest cost a rebuild of the collection, the pipeline
+/// removes them for all files at once beforehand.
+pub fn replace_file_records(collection: &mut Collection, manifest: &mut Manifest, filename: &str,
+                            mtime: u64, hash: String, tags: BTreeMap<String, String>, records: &[Record]) -> anyhow::Result<usize> {
+    remove_file_records(collection, manifest, filename)?;
+    let ids = if records.is_empty() { vec![] } else { collection.insert_many(records)? };
+    manifest.files.insert(filename.to_string(), FileEntry::new(mtime, hash, ids.iter().map(|id| id.0).collect(), tags));
+    Ok(ids.len())
+}
+
+/// Deletes the records of a file from the collection and the manifest,
+/// returns the number of records deleted.
+pub fn remove_file_records(collection: &mut Collection, manifest: &mut Manifest, filename: &str) -> anyhow::Result<usize> {
+    let Some(entry) = manifest.files.remove(filename) else {
+        return Ok(0);
+    };
+    rebuild_without(collection, manifest, &entry.ids.into_iter().collect())
+}
+
+/// Deletes the records of a file, or of all the files below a directory.
+/// Records the manifest does not know about are found by their filename
+/// metadata. Returns the number of records deleted per file.
+pub fn remove_path_records(collection: &mut Collection, manifest: &mut Manifest, path: &Path) -> anyhow::Result<BTreeMap<String, usize>> {
+    manifest.files.retain(|f, _| !Path::new(f).starts_with(path));
+    let mut removed = BTreeMap::new();
+    let mut ids = HashSet::new();
+    for (id, record) in collection.list()? {
+        let Some(filename) = record_filename(&record) else {
+            continue;
+        };
+        if Path::new(&filename).starts_with(path) {
+            ids.insert(id.0);
+            *removed.entry(filename).or_insert(0) += 1;
+        }
+    }
+    rebuild_without(collection, manifest, &ids)?;
+    Ok(removed)
+}
+
+/// Deletes records from the collection, returns the number deleted.
+/// The collection is rebuilt, so delete all the records of a run at once.
+//
+// Collection::delete() in oasysdb 0.6 leaves the deleted vector in the
+// neighbour lists of the others, and the next search or insert panics on
+// it. So the collection is rebuilt from the records which are kept, and
+// the IDs in the manifest are renumbered.
+pub fn rebuild_without(collection: &mut Collection, manifest: &mut Manifest, ids: &HashSet<u32>) -> anyhow::Result<usize> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+    let mut kept: Vec<(VectorID, Record)> = collection.list()?
+        .into_iter()
+        .filter(|(id, _)| !ids.contains(&id.0))
+        .collect();
+    let num = collection.len() - kept.len();
+    if num == 0 {
+        return Ok(0);
+    }
+    kept.sort_by_key(|(id, _)| id.0);
+
+    let mut rebuilt = Collection::new(&collection.config);
+    let records: Vec<Record> = kept.iter().map(|(_, record)| record.clone()).collect();
+    let new_ids = if records.is_empty() { vec![] } else { rebuilt.insert_many(&records)? };
+    let renumber: HashMap<u32, u32> = kept.iter().map(|(id, _)| id.0).zip(new_ids.iter().map(|id| id.0)).collect();
+    for entry in manifest.files.values_mut() {
+        entry.ids = entry.ids.iter().filter_map(|id| renumber.get(id).copied()).collect();
+    }
+    *collection = rebuilt;
+    Ok(num)
+}
+
+fn record_filename(record: &Record) -> Option<String> {
+    md_to_hashmap(&record.data).and_then(|hm| hm.get("filename").and_then(md_to_str))
+}
+
+fn manifest_path(collection: &str) -> PathBuf {
+    manifest_dir().join(format!("{}.json", collection))
+}
+
+/// Returns None if the collection has no manifest (yet).
+pub fn load_manifest(collection: &str) -> anyhow::Result<Option<Manifest>> {
+    let path = manifest_path(collection);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+pub fn save_manifest(collection: &str, manifest: &Manifest) -> anyhow::Result<()> {
+    fs::create_dir_all(manifest_dir())?;
+    fs::write(manifest_path(collection), serde_json::to_string_pretty(manifest)?)?;
+    Ok(())
+}
+
+pub fn delete_manifest(collection: &str) -> anyhow::Result<()> {
+    let path = manifest_path(collection);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Returns None if the text index has no manifest (yet).
+pub fn load_text_manifest() -> anyhow::Result<Option<Manifest>> {
+    let path = tantivy_manifest_path();
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+pub fn save_text_manifest(manifest: &Manifest) -> anyhow::Result<()> {
+    fs::write(tantivy_manifest_path(), serde_json::to_string_pretty(manifest)?)?;
+    Ok(())
+}
+
+pub fn delete_text_manifest() -> anyhow::Result<()> {
+    let path = tantivy_manifest_path();
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::data_to_record;
+    use std::io::Write;
+
+    #[test]
+    fn status_new_unchanged_changed() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "We have a cat called Sirius.").unwrap();
+        let path = &file.path().to_path_buf();
+        let name = path.to_string_lossy().to_string();
+
+        let mut manifest = Manifest::default();
+        let (status, mtime, _) = manifest.status(&name, path).unwrap();
+        assert_eq!(status, FileStatus::New);
+
+        let hash = file_hash(path).unwrap();
+        manifest.files.insert(name.clone(), FileEntry { mtime, hash, ids: vec![0], ..Default::default() });
+        assert_eq!(manifest.status(&name, path).unwrap().0, FileStatus::Unchanged);
+
+        // Same contents, other mtime: still unchanged.
+        manifest.files.get_mut(&name).unwrap().mtime = 0;
+        assert_eq!(manifest.status(&name, path).unwrap().0, FileStatus::Unchanged);
+
+        write!(file, " And Maja.").unwrap();
+        manifest.files.get_mut(&name).unwrap().mtime = 0;
+        assert_eq!(manifest.status(&name, path).unwrap().0, FileStatus::Changed);
+    }
+
+    #[test]
+    fn remove_path_with_and_without_manifest() {
+        let emb = vec![1.0f32, 0.0];
+        let records = vec![
+            data_to_record(&emb, "texts/a.txt", "one", 0, None, None),
+            data_to_record(&emb, "texts/a.txt", "two", 1, None, None),
+            data_to_record(&emb, "texts/ab.txt", "three", 0, None, None),
+            data_to_record(&emb, "other/c.txt", "four", 0, None, None),
+        ];
+        let mut collection = Collection::new(&Config::default());
+        let ids = collection.insert_many(&records).unwrap();
+
+        // Only the first record is in the manifest.
+        let mut manifest = Manifest::default();
+        let entry = FileEntry { ids: vec![ids[0].0], ..Default::default() };
+        manifest.files.insert("texts/a.txt".to_string(), entry);
+
+        let removed = remove_path_records(&mut collection, &mut manifest, Path::new("texts/a.txt")).unwrap();
+        assert_eq!(removed.get("texts/a.txt"), Some(&2));
+        assert_eq!(collection.len(), 2);
+        assert!(manifest.files.is_empty());
+
+        let removed = remove_path_records(&mut collection, &mut manifest, Path::new("texts")).unwrap();
+        assert_eq!(removed.get("texts/ab.txt"), Some(&1));
+        assert_eq!(collection.len(), 1);
+
+        // Still searchable, and new records can be added.
+        collection.insert_many(&records[..2]).unwrap();
+        assert_eq!(collection.search(&Vector(emb), 3).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn remove_renumbers_manifest() {
+        let emb = vec![1.0f32, 0.0];
+        let records: Vec<Record> = (0..4).map(|i| data_to_record(&emb, &format!("{}.txt", i % 2), "text", i, None, None)).collect();
+        let mut collection = Collection::new(&Config::default());
+        let ids = collection.insert_many(&records).unwrap();
+        let mut manifest = Manifest::default();
+        for name in ["0.txt", "1.txt"] {
+            let ids = ids.iter().zip(&records).filter(|(_, r)| record_filename(r).as_deref() == Some(name)).map(|(id, _)| id.0).collect();
+            manifest.files.insert(name.to_string(), FileEntry { ids, ..Default::default() });
+        }
+
+        assert_eq!(remove_file_records(&mut collection, &mut manifest, "0.txt").unwrap(), 2);
+        let ids = &manifest.files["1.txt"].ids;
+        assert_eq!(ids.len(), 2);
+        for id in ids {
+            let record = collection.get(&VectorID(*id)).unwrap();
+            assert_eq!(record_filename(&record).as_deref(), Some("1.txt"));
+        }
+    }
+
+    #[test]
+    fn missing_files_below_dir() {
+        let mut manifest = Manifest::default();
+        let entry = FileEntry::default();
+        manifest.files.insert("/no/such/dir/a.txt".to_string(), entry.clone());
+        manifest.files.insert("/elsewhere/b.txt".to_string(), entry);
+        assert_eq!(manifest.missing_files(Path::new("/no/such/dir"), &[]), vec!["/no/such/dir/a.txt".to_string()]);
+    }
+}
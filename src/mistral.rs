@@ -8,8 +8,10 @@ use candle_transformers::{
 use hf_hub::{api::sync::Api, Repo};
 use tokenizers::Tokenizer;
 use anyhow::Error;
+use crate::generator::{Generator, GenOptions, Message, PromptTemplate, DEFAULT_MAX_TOKENS};
 use crate::textgen::device;
 
+#[allow(dead_code)]
 pub fn test_mistral() {
     let mut m = init().unwrap();
     println!("{:?}", m.prompt("## What is light?"));
@@ -38,7 +40,8 @@ pub fn init_with_opts(cfg: MistralConfig) -> anyhow::Result<Mistral> {
     let logits_processor = LogitsProcessor::new(cfg.seed, cfg.temperature, cfg.top_p);
     let weights_filename = repo_api.get("model-q4k.gguf")?;
     let mistral_cfg = QMistralCfg::config_7b_v0_1(true);
-    let weights = VarBuilder::from_gguf(&weights_filename, &device(false)?)?;
+    let device = device(false)?;
+    let weights = VarBuilder::from_gguf(&weights_filename, &device)?;
     let model = QMistral::new(&mistral_cfg, weights)?;
     println!("initialized the model in {:?}", start.elapsed());
 
@@ -47,6 +50,7 @@ pub fn init_with_opts(cfg: MistralConfig) -> anyhow::Result<Mistral> {
         logits_processor,
         cfg,
         tokenizer,
+        device,
         eos_token,
         history: String::new(),
         tokens: vec![],
@@ -79,6 +83,7 @@ pub struct Mistral {
     model: QMistral,
     logits_processor: LogitsProcessor,
     tokenizer: Tokenizer,
+    device: Device,
     cfg: MistralConfig,
     pub history: String,
     tokens: Vec<u32>,
@@ -103,6 +108,15 @@ impl Mistral {
         return next_token != self.eos_token;
     }
 
+    // Start from scratch, forget the history and the kv-cache.
+    pub fn reset(&mut self) {
+        self.model.clear_kv_cache();
+        self.history.clear();
+        self.tokens.clear();
+        self.current_ctx = 0;
+        self.processed = 0;
+    }
+
     // Predict the next token given the internal context.
     fn predict(&mut self) -> anyhow::Result<u32> {
         // Destructuring some fields for easy access.
@@ -113,7 +127,7 @@ impl Mistral {
             ..
         } = self;
 
-        let input = Tensor::new(&tokens[*current_ctx..], &Device::Cpu)?.unsqueeze(0)?;
+        let input = Tensor::new(&tokens[*current_ctx..], &self.device)?.unsqueeze(0)?;
         let logits = self.model.forward(&input, *current_ctx)?;
         let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
         let penalty_pos = tokens.len().saturating_sub(cfg.repeat_last_n);
@@ -157,3 +171,41 @@ impl Mistral {
     
 }
 
+impl Generator for Mistral {
+    fn name(&self) -> String {
+        "lmz/candle-mistral | model-q4k.gguf".to_string()
+    }
+
+    fn count_tokens(&self, messages: &[Message]) -> Option<usize> {
+        let prompt = PromptTemplate::Mistral.apply(messages);
+        self.tokenizer.encode(prompt, true).ok().map(|tokens| tokens.len())
+    }
+
+    // The sliding window of Mistral-7B.
+    fn context_size(&self) -> Option<usize> {
+        Some(4096)
+    }
+
+    fn generate(&mut self, messages: &[Message], opts: &GenOptions, on_token: &mut dyn FnMut(&str)) -> anyhow::Result<String> {
+        self.reset();
+        let seed = opts.seed.unwrap_or(self.cfg.seed);
+        let temperature = opts.temperature.or(self.cfg.temperature);
+        let top_p = opts.top_p.or(self.cfg.top_p);
+        self.logits_processor = LogitsProcessor::new(seed, temperature, top_p);
+        if let Some(repeat_penalty) = opts.repeat_penalty {
+            self.cfg.repeat_penalty = repeat_penalty;
+        }
+
+        let prompt = PromptTemplate::Mistral.apply(messages);
+        self.prompt(&prompt)?;
+        for _ in 0..opts.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS) {
+            let start = self.history.len();
+            let more = self.more();
+            on_token(&self.history[start..]);
+            if !more {
+                break;
+            }
+        }
+        Ok(self.history[prompt.len()..].trim().to_string())
+    }
+}
@@ -1,70 +1,91 @@
 use ollama_rs::{
-    generation::completion::{
-        request::GenerationRequest, GenerationContext, GenerationResponseStream,
-    },
+    generation::chat::{request::ChatMessageRequest, ChatMessage, ChatMessageResponseStream, MessageRole},
     Ollama,
 };
 use ollama_rs::generation::options::GenerationOptions;
-use tokio::io::{stdout, AsyncWriteExt};
 use tokio_stream::StreamExt;
 
-use ollama_rs::{
-    generation::chat::{ChatMessage},
-};
+use crate::generator::{Generator, GenOptions, Message, Role};
 
+// Context size asked for in the options.
+const NUM_CTX: u32 = 42000;
 
-#[tokio::main]
-pub async fn ollama_generate(sys_msg: &str, question: &str, model: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut ollama = Ollama::default();
-    let mut stdout = stdout();
-    let context: Option<GenerationContext> = None;
+pub fn to_chat_message(message: &Message) -> ChatMessage {
+    match message.role {
+        Role::System => ChatMessage::system(message.content.clone()),
+        Role::User => ChatMessage::user(message.content.clone()),
+        Role::Assistant => ChatMessage::assistant(message.content.clone()),
+    }
+}
 
-    ChatMessage::system(sys_msg.to_string());
-    //ChatMessage::assistant(sys_msg.to_string()); // ??
+/// Tool messages are not used by Minerva, they are read as user messages.
+pub fn from_chat_message(message: &ChatMessage) -> Message {
+    match message.role {
+        MessageRole::System => Message::system(&message.content),
+        MessageRole::Assistant => Message::assistant(&message.content),
+        MessageRole::User | MessageRole::Tool => Message::user(&message.content),
+    }
+}
 
-    ollama.set_system_response("0".to_string(), sys_msg.to_string()); // ?
+pub struct OllamaGenerator {
+    model: String,
+}
 
-    /*
-    let res = ollama.generate(GenerationRequest::new(model.to_string(), question.to_string())).await;
-    if let Ok(res) = res {
-        println!("{}", res.response);
+impl OllamaGenerator {
+    pub fn new(model: &str) -> Self {
+        OllamaGenerator { model: model.to_string() }
     }
-     */
-    let options = GenerationOptions::default()
-        .num_ctx(42000)
-        .temperature(0.9)
-        .repeat_penalty(1.5)
-        .repeat_last_n(-1)
-        .top_k(100)
-        .top_p(0.9);
 
-    // This is needed to get a correct response from the context?
-    let prompt = format!("System: {}\nUser: {}", sys_msg, question);
+    async fn generate_stream(&self, messages: &[Message], opts: &GenOptions, on_token: &mut dyn FnMut(&str)) -> anyhow::Result<String> {
+        let ollama = Ollama::default();
 
-    // Only way to get the context for the sys_msg in ollama? Different from
-    // Python interface?
-    //let mut request = GenerationRequest::new(model.into(), question.to_string()).options(options);
-    let mut request = GenerationRequest::new(model.into(), prompt.to_string()).options(options);
-    
-    if let Some(context) = context.clone() {
-        request = request.context(context);
-    }
-    let mut stream: GenerationResponseStream = ollama.generate_stream(request).await?;
+        let mut options = GenerationOptions::default()
+            .num_ctx(NUM_CTX)
+            .temperature(opts.temperature.unwrap_or(0.9) as f32)
+            .repeat_penalty(opts.repeat_penalty.unwrap_or(1.5))
+            .repeat_last_n(-1)
+            .top_k(100)
+            .top_p(opts.top_p.unwrap_or(0.9) as f32);
+        if let Some(seed) = opts.seed {
+            options = options.seed(seed as i32);
+        }
+        if let Some(max_tokens) = opts.max_tokens {
+            options = options.num_predict(max_tokens as i32);
+        }
+
+        // The chat endpoint takes the system message separately, so
+        // we do not need to glue "System: ...\nUser: ..." together.
+        let chat_messages = messages.iter().map(to_chat_message).collect();
+        let request = ChatMessageRequest::new(self.model.clone(), chat_messages).options(options);
 
-    println!(" -- ");
-    while let Some(Ok(res)) = stream.next().await {
-        for ele in res {
-            stdout.write_all(ele.response.as_bytes()).await?;
-            stdout.flush().await?;
-            
-            if ele.context.is_some() {
-                //context = ele.context;
-                _ = ele.context;
+        let mut stream: ChatMessageResponseStream = ollama.send_chat_messages_stream(request).await?;
+
+        let mut response = String::new();
+        while let Some(Ok(res)) = stream.next().await {
+            if let Some(message) = res.message {
+                on_token(&message.content);
+                response += &message.content;
+            }
+            if res.done {
+                break;
             }
         }
-    }
-    println!("");
 
-    Ok(())
+        Ok(response.trim().to_string())
+    }
 }
 
+impl Generator for OllamaGenerator {
+    fn name(&self) -> String {
+        format!("ollama | {}", self.model)
+    }
+
+    fn context_size(&self) -> Option<usize> {
+        Some(NUM_CTX as usize)
+    }
+
+    fn generate(&mut self, messages: &[Message], opts: &GenOptions, on_token: &mut dyn FnMut(&str)) -> anyhow::Result<String> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        rt.block_on(self.generate_stream(messages, opts, on_token))
+    }
+}
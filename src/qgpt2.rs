@@ -0,0 +1,194 @@
+// Quantised GPT-2 style model, read from a GGUF file with "gpt2" as
+// general.architecture. This is what the GPT-SW3 GGUF models from
+// AI-Sweden use, and which candle has no quantised version of.
+//
+// Tensor and metadata names follow the llama.cpp GGUF conversion, the
+// structure follows quantized_stable_lm.rs in candle-transformers.
+
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::LayerNorm;
+use candle_transformers::quantized_nn::{layer_norm, linear, Embedding, Linear};
+use candle_transformers::quantized_var_builder::VarBuilder;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub context_length: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_layers: usize,
+    pub num_heads: usize,
+    pub layer_norm_eps: f64,
+}
+
+impl Config {
+    pub fn from_gguf(ct: &gguf_file::Content) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle_core::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        let hidden_size = md_get("gpt2.embedding_length")?.to_u32()? as usize;
+        let vocab_size = match ct.tensor_infos.get("token_embd.weight") {
+            Some(info) => info.shape.dims()[0],
+            None => candle_core::bail!("cannot find token_embd.weight"),
+        };
+        Ok(Config {
+            vocab_size,
+            context_length: md_get("gpt2.context_length")?.to_u32()? as usize,
+            hidden_size,
+            intermediate_size: md_get("gpt2.feed_forward_length")
+                .and_then(|v| v.to_u32())
+                .map(|v| v as usize)
+                .unwrap_or(4 * hidden_size),
+            num_layers: md_get("gpt2.block_count")?.to_u32()? as usize,
+            num_heads: md_get("gpt2.attention.head_count")?.to_u32()? as usize,
+            layer_norm_eps: md_get("gpt2.attention.layer_norm_epsilon")
+                .and_then(|v| v.to_f32())
+                .unwrap_or(1e-5) as f64,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    c_attn: Linear,
+    c_proj: Linear,
+    num_heads: usize,
+    head_dim: usize,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let h = cfg.hidden_size;
+        Ok(Attention {
+            c_attn: linear(h, 3 * h, vb.pp("attn_qkv"))?,
+            c_proj: linear(h, h, vb.pp("attn_output"))?,
+            num_heads: cfg.num_heads,
+            head_dim: h / cfg.num_heads,
+            kv_cache: None,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, mask: Option<&Tensor>, index_pos: usize) -> Result<Tensor> {
+        let (b_sz, q_len, hidden_size) = xs.dims3()?;
+        let qkv = xs.apply(&self.c_attn)?;
+        let split = |i: usize| -> Result<Tensor> {
+            qkv.narrow(D::Minus1, i * hidden_size, hidden_size)?
+                .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let (q, k, v) = (split(0)?, split(1)?, split(2)?);
+
+        // Same as quantized_llama, index_pos 0 starts a new sequence.
+        let (k, v) = match &self.kv_cache {
+            Some((prev_k, prev_v)) if index_pos > 0 => {
+                (Tensor::cat(&[prev_k, &k], 2)?, Tensor::cat(&[prev_v, &v], 2)?)
+            }
+            _ => (k, v),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let att = (q.matmul(&k.t()?)? * scale)?;
+        let att = match mask {
+            None => att,
+            Some(mask) => att.broadcast_add(mask)?,
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        att.matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, hidden_size))?
+            .apply(&self.c_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    ln_1: LayerNorm,
+    attn: Attention,
+    ln_2: LayerNorm,
+    c_fc: Linear,
+    c_proj: Linear,
+}
+
+impl Block {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let h = cfg.hidden_size;
+        Ok(Block {
+            ln_1: layer_norm(h, cfg.layer_norm_eps, vb.pp("attn_norm"))?,
+            attn: Attention::new(cfg, vb.clone())?,
+            ln_2: layer_norm(h, cfg.layer_norm_eps, vb.pp("ffn_norm"))?,
+            c_fc: linear(h, cfg.intermediate_size, vb.pp("ffn_up"))?,
+            c_proj: linear(cfg.intermediate_size, h, vb.pp("ffn_down"))?,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, mask: Option<&Tensor>, index_pos: usize) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.attn.forward(&xs.apply(&self.ln_1)?, mask, index_pos)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let mlp = xs.apply(&self.ln_2)?.apply(&self.c_fc)?.gelu()?.apply(&self.c_proj)?;
+        residual + mlp
+    }
+}
+
+pub struct Model {
+    wte: Embedding,
+    wpe: Embedding,
+    blocks: Vec<Block>,
+    ln_f: LayerNorm,
+    lm_head: Linear,
+    device: Device,
+    pub config: Config,
+}
+
+impl Model {
+    pub fn from_gguf<P: AsRef<std::path::Path>>(ct: &gguf_file::Content, path: P, device: &Device) -> Result<Self> {
+        let cfg = Config::from_gguf(ct)?;
+        let vb = VarBuilder::from_gguf(path, device)?;
+        let wte = Embedding::new(cfg.vocab_size, cfg.hidden_size, vb.pp("token_embd"))?;
+        let wpe = Embedding::new(cfg.context_length, cfg.hidden_size, vb.pp("position_embd"))?;
+        let blocks = (0..cfg.num_layers)
+            .map(|i| Block::new(&cfg, vb.pp(format!("blk.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+        let ln_f = layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("output_norm"))?;
+        // Some conversions tie the output to the token embeddings.
+        let lm_head = if vb.contains_key("output.weight") {
+            Linear::from_arc(vb.get((cfg.vocab_size, cfg.hidden_size), "output.weight")?, None)?
+        } else {
+            Linear::from_arc(vb.get((cfg.vocab_size, cfg.hidden_size), "token_embd.weight")?, None)?
+        };
+        Ok(Model { wte, wpe, blocks, ln_f, lm_head, device: device.clone(), config: cfg })
+    }
+
+    fn mask(&self, t: usize, index_pos: usize) -> Result<Tensor> {
+        let mask: Vec<f32> = (0..t)
+            .flat_map(|i| (0..t + index_pos).map(move |j| if j > i + index_pos { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        Tensor::from_slice(&mask, (t, t + index_pos), &self.device)
+    }
+
+    /// Returns the logits for the last position, shape (batch, vocab).
+    pub fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = input_ids.dims2()?;
+        let positions = Tensor::arange(index_pos as u32, (index_pos + seq_len) as u32, &self.device)?;
+        let mut xs = (self.wte.forward(input_ids)?.broadcast_add(&self.wpe.forward(&positions)?))?;
+        let mask = if seq_len == 1 {
+            None
+        } else {
+            Some(self.mask(seq_len, index_pos)?)
+        };
+        for block in self.blocks.iter_mut() {
+            xs = block.forward(&xs, mask.as_ref(), index_pos)?;
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.ln_f)?
+            .apply(&self.lm_head)?
+            .squeeze(1)?
+            .to_dtype(DType::F32)
+    }
+}
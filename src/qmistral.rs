@@ -1,208 +1,352 @@
 use std::io::Write;
+use std::path::Path;
 use tokenizers::Tokenizer;
 
 use candle_core::quantized::gguf_file;
-use candle_core::{Tensor};
+use candle_core::{Device, Tensor};
 use candle_transformers::generation::LogitsProcessor;
 
-use candle_transformers::models::quantized_llama as model;
-use model::ModelWeights;
+use candle_transformers::models::quantized_llama;
 
 use anyhow::{Error as E, Result};
-use tqdm::pbar;
 
+use crate::generator::{Generator, GenOptions, Message, PromptTemplate, CONTEXT_RESERVE, DEFAULT_MAX_TOKENS};
+use crate::qgpt2;
 use crate::textgen::device;
 
-pub fn run_qmistral(prompt: &str) -> Result<String> {
+// /Users/pberck/.cache/huggingface/hub/models--TheBloke--Mistral-7B-Instruct-v0.2-GGUF
+// /Users/pberck/.cache/huggingface/hub/models--TheBloke--Mistral-7B-Instruct-v0.1-GGUF
+//
+// Other models which have been tried:
+//   TheBloke/Mistral-7B-v0.1-GGUF
+//   AI-Sweden-Models/gpt-sw3-6.7b-v2-instruct-gguf | gpt-sw3-6.7b-v2-instruct-Q4_K_M.gguf
+//   QuantFactory/Meta-Llama-3-8B-Instruct-GGUF | Meta-Llama-3-8B-Instruct.Q4_K_M.gguf
+//   MaziyarPanahi/Mistral-7B-Instruct-v0.3-GGUF | Mistral-7B-Instruct-v0.3.Q5_K_M.gguf
+//   mistral-7b-instruct-v0.2.Q6_K.gguf // Twice as slow as Q4_K_M
+//   mistral-7b-instruct-v0.1.Q4_K_S.gguf
+//   mistral-7b-instruct-v0.1.Q2_K.gguf
+// See list on https://huggingface.co/TheBloke/Mistral-7B-Instruct-v0.1-GGUF
+
+/// Where to find the GGUF model and its tokenizer.
+#[derive(Debug, Clone)]
+pub struct QModelConfig {
+    /// Huggingface repository with the GGUF file.
+    pub repo: String,
+    /// Name of the GGUF file in the repository, or a path to a local file.
+    pub filename: String,
+    /// Huggingface repository with a tokenizer.json, or a path to a local file.
+    pub tokenizer: String,
+    /// Prompt template, if None it is guessed from the model.
+    pub template: Option<PromptTemplate>,
+}
 
-    // The length of the sample to generate (in tokens).
-    let sample_len: usize = 1200;
+impl Default for QModelConfig {
+    fn default() -> Self {
+        Self {
+            repo: "TheBloke/Mistral-7B-Instruct-v0.2-GGUF".to_string(), // 0.1, 0.2
+            filename: "mistral-7b-instruct-v0.2.Q5_K_M.gguf".to_string(), // Twice as slow as Q4_K_M
+            tokenizer: "mistralai/Mistral-7B-v0.1".to_string(),
+            template: None,
+        }
+    }
+}
 
-    // The temperature used to generate samples, use 0 for greedy sampling.
-    let temperature: f64 = 0.8;
+// The GGUF architectures we can run.
+enum Weights {
+    Llama(quantized_llama::ModelWeights), // Mistral, Llama-2/3, ...
+    Gpt2(qgpt2::Model), // GPT-SW3
+}
 
-    // Nucleus sampling probability cutoff.
-    let top_p: Option<f64> = None;
+impl Weights {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        match self {
+            Weights::Llama(m) => m.forward(x, index_pos),
+            Weights::Gpt2(m) => m.forward(x, index_pos),
+        }
+    }
+}
 
-    // The seed to use when generating random samples.
-    let seed: u64 = 28;//299792458;
+pub struct QModel {
+    model: Weights,
+    tokenizer: Tokenizer,
+    device: Device,
+    template: PromptTemplate,
+    eos_tokens: Vec<u32>,
+    max_seq_len: usize,
+    name: String,
+}
 
-    // Display the token for the specified prompt.
-    let verbose_prompt: bool = false;
+// A local file, or a file from a huggingface repository.
+fn get_file(repo: &str, filename: &str) -> Result<std::path::PathBuf> {
+    if Path::new(filename).is_file() {
+        return Ok(Path::new(filename).to_path_buf());
+    }
+    let api = hf_hub::api::sync::Api::new()?;
+    let api = api.model(repo.to_string());
+    Ok(api.get(filename)?)
+}
 
-    // Penalty to be applied for repeating tokens, 1. means no penalty.
-    let repeat_penalty: f32 = 1.1;
+// Guess the prompt template from the architecture and filename.
+fn guess_template(architecture: &str, filename: &str) -> PromptTemplate {
+    let filename = filename.to_lowercase();
+    match architecture {
+        "gpt2" => PromptTemplate::GptSw3,
+        _ if filename.contains("llama-3") || filename.contains("llama3") => PromptTemplate::Llama3,
+        _ if filename.contains("chatml") || filename.contains("dolphin") => PromptTemplate::ChatML,
+        _ => PromptTemplate::Mistral,
+    }
+}
 
-    // The context size to consider for the repeat penalty.
-    let repeat_last_n: usize = 64;
+impl QModel {
+    pub fn load(cfg: &QModelConfig) -> Result<Self> {
+        println!("Model {} | {}", cfg.repo, cfg.filename);
 
-    let temperature = if temperature == 0. {
-        None
-    } else {
-        Some(temperature)
-    };
-
-    // /Users/pberck/.cache/huggingface/hub/models--TheBloke--Mistral-7B-Instruct-v0.2-GGUF
-    // /Users/pberck/.cache/huggingface/hub/models--TheBloke--Mistral-7B-Instruct-v0.1-GGUF
-
-    //let repo = "TheBloke/Mistral-7B-v0.1-GGUF";
-    let repo = "TheBloke/Mistral-7B-Instruct-v0.2-GGUF"; // 0.1, 0.2
-    //let repo = "AI-Sweden-Models/gpt-sw3-6.7b-v2-instruct-gguf";
-    //let repo = "AI-Sweden-Models/gpt-sw3-6.7b-v2-instruct-gguf";
-    //let repo = "QuantFactory/Meta-Llama-3-8B-Instruct-GGUF";
-    //let repo = "MaziyarPanahi/Mistral-7B-Instruct-v0.3-GGUF";
-    
-    // See list on https://huggingface.co/TheBloke/Mistral-7B-Instruct-v0.1-GGUF
-   
-    let filename = "mistral-7b-instruct-v0.2.Q5_K_M.gguf"; // Twice as slow as Q4_K_M
-    //let filename = "gpt-sw3-6.7b-v2-instruct-Q4_K_M.gguf"; // Error in attention.head_count
-    //let filename = "Meta-Llama-3-8B-Instruct.Q4_K_M.gguf";
-    //let filename = "Mistral-7B-Instruct-v0.3.Q5_K_M.gguf";
-    //let filename = "gpt-sw3-6.7b-v2-instruct-Q4_K_M.gguf";
-    //let filename = "mistral-7b-instruct-v0.2.Q6_K.gguf"; // Twice as slow as Q4_K_M
-    //let filename = "mistral-7b-instruct-v0.1.Q4_K_S.gguf";
-    //let filename = "mistral-7b-instruct-v0.1.Q2_K.gguf"; // 0.1, 0.2
-
-    println!("Model {} | {}", repo, filename);
-    
-    let api = hf_hub::api::sync::Api::new()?;
-    let api = api.model(repo.to_string());
-    let model_path = api.get(filename)?;
-    
-    let mut file = std::fs::File::open(model_path)?;
-    let start = std::time::Instant::now();
-    let device = device(false)?; //Device::Cpu;
-    println!("Device {:?}", device);
-    
-    let mut model = {
-        let model = gguf_file::Content::read(&mut file)?;
+        let model_path = get_file(&cfg.repo, &cfg.filename)?;
+
+        let mut file = std::fs::File::open(&model_path)?;
+        let start = std::time::Instant::now();
+        let device = device(false)?; //Device::Cpu;
+        println!("Device {:?}", device);
+
+        let content = gguf_file::Content::read(&mut file)?;
         let mut total_size_in_bytes = 0;
-        /*for (k, v) in model.metadata.iter() {
-            // llama.attention.head_count = U32(32)
-            println!("{:?}", k);
-        }*/
-        for (_, tensor) in model.tensor_infos.iter() {
+        for (_, tensor) in content.tensor_infos.iter() {
             let elem_count = tensor.shape.elem_count();
             total_size_in_bytes += elem_count
                 * tensor.ggml_dtype.type_size()
                 / tensor.ggml_dtype.block_size();
         }
-
         println!(
             "loaded {:?} tensors ({}) in {:.2}s",
-            model.tensor_infos.len(),
+            content.tensor_infos.len(),
             &format_size(total_size_in_bytes),
             start.elapsed().as_secs_f32(),
         );
-        
-        ModelWeights::from_gguf(model, &mut file, &device)?
-    };
-
-    println!("model built");
-    println!("model::MAX_SEQ_LEN {}", model::MAX_SEQ_LEN);
-    
-    let api = hf_hub::api::sync::Api::new().expect("api?");
-    let repo = "mistralai/Mistral-7B-v0.1";
-    let api = api.model(repo.to_string());
 
-    let tokenizer_path = api.get("tokenizer.json").expect("tokeniser?");
-    //println!("{:?}", tokenizer_path);
-    let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(E::msg)?;
-    
-    let pre_prompt_tokens = vec![]; // PJB remove
-    let mut response = String::new();
-    
-    let prompt = format!("[INST] {prompt} [/INST]");
-    //let prompt = format!("{prompt}");
-    //println!("{}", &prompt);
-    
-    let tokens = tokenizer.encode(prompt, true).map_err(E::msg)?;
-    println!("Prompt length {}, pre-processing...", tokens.len());
-        
-    if verbose_prompt {
-        for (token, id) in tokens.get_tokens().iter().zip(tokens.get_ids().iter()) {
-            let token = token.replace('▁', " ").replace("<0x0A>", "\n");
-            println!("{id:7} -> '{token}'");
+        // The architecture decides which model we build from the tensors.
+        let architecture = match content.metadata.get("general.architecture") {
+            Some(v) => v.to_string()?.clone(),
+            None => "llama".to_string(),
+        };
+        println!("Architecture {}", architecture);
+        let (model, max_seq_len) = match architecture.as_str() {
+            "llama" => (
+                Weights::Llama(quantized_llama::ModelWeights::from_gguf(content, &mut file, &device)?),
+                quantized_llama::MAX_SEQ_LEN
+            ),
+            "gpt2" => {
+                let m = qgpt2::Model::from_gguf(&content, &model_path, &device)?;
+                let max_seq_len = m.config.context_length;
+                (Weights::Gpt2(m), max_seq_len)
+            }
+            _ => anyhow::bail!("Architecture \"{}\" is not supported.", architecture),
+        };
+        println!("model built");
+        println!("MAX_SEQ_LEN {}", max_seq_len);
+
+        let tokenizer_path = if Path::new(&cfg.tokenizer).is_file() {
+            Path::new(&cfg.tokenizer).to_path_buf()
+        } else {
+            get_file(&cfg.tokenizer, "tokenizer.json")?
+        };
+        //println!("{:?}", tokenizer_path);
+        let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(E::msg)?;
+
+        let template = cfg.template.unwrap_or_else(|| guess_template(&architecture, &cfg.filename));
+        println!("Prompt template {}", template);
+        let vocab = tokenizer.get_vocab(true);
+        let eos_tokens: Vec<u32> = template.stop_tokens().iter().filter_map(|t| vocab.get(*t).copied()).collect();
+        if eos_tokens.is_empty() {
+            anyhow::bail!("The tokenizer has none of the stop tokens {:?}.", template.stop_tokens());
         }
+
+        Ok(QModel {
+            model,
+            tokenizer,
+            device,
+            template,
+            eos_tokens,
+            max_seq_len,
+            name: format!("{} | {}", cfg.repo, cfg.filename),
+        })
+    }
+}
+
+impl Generator for QModel {
+    fn name(&self) -> String {
+        self.name.clone()
     }
 
-    let prompt_tokens = [&pre_prompt_tokens, tokens.get_ids()].concat();
-    let to_sample = sample_len.saturating_sub(1);
+    fn count_tokens(&self, messages: &[Message]) -> Option<usize> {
+        let prompt = self.template.apply(messages);
+        self.tokenizer.encode(prompt, true).ok().map(|tokens| tokens.len())
+    }
 
-    let prompt_tokens = if prompt_tokens.len() + to_sample > model::MAX_SEQ_LEN - 10 {
-        let to_remove = prompt_tokens.len() + to_sample + 10 - model::MAX_SEQ_LEN;
-        prompt_tokens[prompt_tokens.len().saturating_sub(to_remove)..]
-            .to_vec()
-    } else {
-        prompt_tokens
-    };
-
-    let mut all_tokens = vec![];
-    let mut logits_processor = LogitsProcessor::new(seed, temperature, top_p);
-
-    let start_prompt_processing = std::time::Instant::now();
-    let mut next_token = {
-        let input = Tensor::new(prompt_tokens.as_slice(), &device)?
-            .unsqueeze(0)?;
-        let logits = model.forward(&input, 0)?;
-        let logits = logits.squeeze(0)?;
-        logits_processor.sample(&logits)?
-    };
-    
-    let _prompt_dt = start_prompt_processing.elapsed();
-    all_tokens.push(next_token);
-    //print_token(next_token, &tokenizer); // PJB if verbose?
-    if let Some(token) = get_token(next_token, &tokenizer)  {
-        response += &token; // first character
+    fn context_size(&self) -> Option<usize> {
+        Some(self.max_seq_len)
     }
 
-    let eos_token = *tokenizer.get_vocab(true).get("</s>").unwrap();
+    fn generate(&mut self, messages: &[Message], opts: &GenOptions, on_token: &mut dyn FnMut(&str)) -> Result<String> {
+        // The length of the sample to generate (in tokens).
+        let sample_len: usize = opts.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+
+        // The temperature used to generate samples, use 0 for greedy sampling.
+        let temperature: f64 = opts.temperature.unwrap_or(0.8);
+
+        // Nucleus sampling probability cutoff.
+        let top_p: Option<f64> = opts.top_p;
+
+        // The seed to use when generating random samples.
+        let seed: u64 = opts.seed.unwrap_or(28);//299792458;
 
-    let _start_post_prompt = std::time::Instant::now();
-    let mut pbar = pbar(Some(to_sample));
-    for index in 0..to_sample {
-        let input = Tensor::new(&[next_token], &device)?.unsqueeze(0)?;
-        let logits = model.forward(&input, prompt_tokens.len() + index)?;
-        let logits = logits.squeeze(0)?;
-        let logits = if repeat_penalty == 1. {
-            logits
+        // Display the token for the specified prompt.
+        let verbose_prompt: bool = false;
+
+        // Penalty to be applied for repeating tokens, 1. means no penalty.
+        let repeat_penalty: f32 = opts.repeat_penalty.unwrap_or(1.1);
+
+        // The context size to consider for the repeat penalty.
+        let repeat_last_n: usize = 64;
+
+        let temperature = if temperature == 0. {
+            None
         } else {
-            let start_at = all_tokens.len().saturating_sub(repeat_last_n);
-            candle_transformers::utils::apply_repeat_penalty(
-                &logits,
-                repeat_penalty,
-                &all_tokens[start_at..],
-            )?
+            Some(temperature)
         };
-        next_token = logits_processor.sample(&logits)?;
-        all_tokens.push(next_token);
-        
-        //print_token(next_token, &tokenizer); // PJB if verbose?
-        if next_token == eos_token {
-            break;
+
+        let prompt = self.template.apply(messages);
+        //println!("{}", &prompt);
+
+        let tokens = self.tokenizer.encode(prompt, true).map_err(E::msg)?;
+        println!("Prompt length {}, pre-processing...", tokens.len());
+
+        if verbose_prompt {
+            for (token, id) in tokens.get_tokens().iter().zip(tokens.get_ids().iter()) {
+                let token = token.replace('▁', " ").replace("<0x0A>", "\n");
+                println!("{id:7} -> '{token}'");
+            }
+        }
+
+        let prompt_tokens = tokens.get_ids().to_vec();
+
+        // assemble_prompt() leaves room for the answer, a prompt which is
+        // still too long is refused, cutting it would lose the template.
+        let room = self.max_seq_len.saturating_sub(CONTEXT_RESERVE);
+        if prompt_tokens.len() >= room {
+            anyhow::bail!("The prompt has {} tokens, the model takes at most {}.", prompt_tokens.len(), room);
+        }
+        let to_sample = sample_len.saturating_sub(1).min(room - prompt_tokens.len());
+
+        let mut all_tokens = vec![];
+        let mut logits_processor = LogitsProcessor::new(seed, temperature, top_p);
+        let mut token_stream = TokenStream::new(&self.tokenizer);
+
+        let start_prompt_processing = std::time::Instant::now();
+        let mut next_token = {
+            let input = Tensor::new(prompt_tokens.as_slice(), &self.device)?
+                .unsqueeze(0)?;
+            let logits = self.model.forward(&input, 0)?;
+            let logits = logits.squeeze(0)?;
+            logits_processor.sample(&logits)?
         };
-        if let Some(token) = get_token(next_token, &tokenizer)  {
-            response += &token;
-            pbar.update(1).unwrap();
+
+        let _prompt_dt = start_prompt_processing.elapsed();
+        all_tokens.push(next_token);
+
+        let _start_post_prompt = std::time::Instant::now();
+        for index in 0..to_sample {
+            if self.eos_tokens.contains(&next_token) {
+                break;
+            };
+            if let Some(token) = token_stream.next_token(next_token)? {
+                on_token(&token);
+            }
+
+            let input = Tensor::new(&[next_token], &self.device)?.unsqueeze(0)?;
+            let logits = self.model.forward(&input, prompt_tokens.len() + index)?;
+            let logits = logits.squeeze(0)?;
+            let logits = if repeat_penalty == 1. {
+                logits
+            } else {
+                let start_at = all_tokens.len().saturating_sub(repeat_last_n);
+                candle_transformers::utils::apply_repeat_penalty(
+                    &logits,
+                    repeat_penalty,
+                    &all_tokens[start_at..],
+                )?
+            };
+            next_token = logits_processor.sample(&logits)?;
+            all_tokens.push(next_token);
+        }
+        if let Some(rest) = token_stream.flush()? {
+            on_token(&rest);
+        }
+
+        /*
+        let dt = start_post_prompt.elapsed();
+        println!(
+            "\n\n{:4} prompt tokens processed: {:.2} token/s",
+            prompt_tokens.len(),
+            prompt_tokens.len() as f64 / prompt_dt.as_secs_f64(),
+        );
+
+        println!(
+            "{:4} tokens generated: {:.2} token/s",
+            all_tokens.len(),
+            all_tokens.len()  as f64 / dt.as_secs_f64(),
+        );
+        */
+
+        Ok(token_stream.text()?.trim().to_string())
+    }
+}
+
+// Incremental decoding, decoding single tokens does not work for
+// byte-level BPE (Llama-3) or multi-byte characters (å, ä, ö). After
+// TokenOutputStream in the candle examples.
+struct TokenStream<'a> {
+    tokenizer: &'a Tokenizer,
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl<'a> TokenStream<'a> {
+    fn new(tokenizer: &'a Tokenizer) -> Self {
+        Self { tokenizer, tokens: vec![], prev_index: 0, current_index: 0 }
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer.decode(tokens, true).map_err(E::msg)
+    }
+
+    fn next_token(&mut self, token: u32) -> Result<Option<String>> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..self.current_index])?;
+        self.tokens.push(token);
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len() && text.chars().last().map_or(false, |c| c.is_alphanumeric()) {
+            let new_text = text.split_at(prev_text.len()).1.to_string();
+            self.prev_index = self.current_index;
+            self.current_index = self.tokens.len();
+            Ok(Some(new_text))
+        } else {
+            Ok(None)
         }
-    } 
-
-    /*
-    let dt = start_post_prompt.elapsed();
-    println!(
-        "\n\n{:4} prompt tokens processed: {:.2} token/s",
-        prompt_tokens.len(),
-        prompt_tokens.len() as f64 / prompt_dt.as_secs_f64(),
-    );
-
-    println!(
-        "{:4} tokens generated: {:.2} token/s",
-        all_tokens.len(),
-        all_tokens.len()  as f64 / dt.as_secs_f64(),
-    );
-    */
-
-    Ok(response.trim().to_string())
+    }
+
+    fn flush(&self) -> Result<Option<String>> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..self.current_index])?;
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len() {
+            Ok(Some(text.split_at(prev_text.len()).1.to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn text(&self) -> Result<String> {
+        self.decode(&self.tokens)
+    }
 }
 
 #[allow(dead_code)]
@@ -229,29 +373,6 @@ fn print_token(next_token: u32, tokenizer: &Tokenizer) {
     }
 }
 
-fn get_token(next_token: u32, tokenizer: &Tokenizer) -> Option<String> {
-    if let Some(text) = tokenizer.id_to_token(next_token) {
-        let text = text.replace('▁', " ");
-        let ascii = text
-            .strip_prefix("<0x")
-            .and_then(|t| t.strip_suffix('>'))
-            .and_then(|t| u8::from_str_radix(t, 16).ok());
-        match ascii {
-            None => Some(text), // return string directly
-            Some(ascii) => {
-                if let Some(chr) = char::from_u32(ascii as u32) {
-                    if chr.is_ascii() {
-                        return Some(chr.to_string());
-                    }
-                }
-                None
-            }
-        }
-    } else {
-        None
-    }
-}
-
 fn format_size(size_in_bytes: usize) -> String {
     if size_in_bytes < 1_000 {
         format!("{}B", size_in_bytes)
@@ -0,0 +1,372 @@
+use serde::Serialize;
+use crate::generator::{Generator, Message, Role, CONTEXT_RESERVE};
+use crate::retriever::RetrievedChunk;
+
+// =====================================================================
+// Building the prompt from the retrieved chunks, shared between the
+// command line, the chat and the server.
+// =====================================================================
+
+/// Context used when nothing was retrieved.
+pub const NO_CONTEXT: &str = "Use any knowledge you have.";
+
+// The retrieved chunks in the same format as the vector database results,
+// numbered from 1 so the model can cite them as [1], [2], ...
+pub fn chunks_to_context(chunks: &[RetrievedChunk], showcontext: bool) -> String {
+    let mut context_str = String::new();
+    let mut sep = "";
+    for (i, res) in chunks.iter().enumerate() {
+        if showcontext {
+            println!("  [{}] {}\n", i + 1, res.context_text());
+        }
+        context_str += &(sep.to_owned() + "\n[" + &(i + 1).to_string() + "] (document:\"" + &res.label() + "\", with contents:" + res.context_text() + ")");
+        sep = ", ";
+    }
+    context_str
+}
+
+fn system_message(context_str: &str) -> String {
+    format!("You are a friendly and helpful AI assistant. Your answer should be to the point and use the context if possible. Do not make up facts. Cite the documents used from the context with their number in square brackets, for example [1]. Do not repeat the question or references. Do not invent answers or references. Today is {date}. Context: {context}", context=context_str, date=chrono::Local::now().format("%A, %B %e, %Y"))
+    //format!("Du är en vänlig och hjälpsam AI-assistent. Ditt svar ska vara kortfattat och använda sammanhanget om möjligt. Skriv ut namnet på det dokument som används från sammanhanget. Upprepa inte frågan eller referenserna. Svara på Svenska! Idag är {date}. Sammanhang: {context}.", context=context_str, date=chrono::Local::now().format("%A, %B %e, %Y"))
+}
+
+/// The system message with the context, the earlier turns of the
+/// conversation, and the new question.
+pub fn build_chat_messages(context_str: &str, history: &[Message], query: &str) -> Vec<Message> {
+    let q = format!("Question: {question}", question=query);
+    let mut messages = vec![Message::system(&system_message(context_str))];
+    messages.extend(history.iter().cloned());
+    messages.push(Message::user(&q));
+    messages
+}
+
+/// The messages for the model, and the chunks which were used.
+pub struct Prompt {
+    pub messages: Vec<Message>,
+    pub sources: Vec<RetrievedChunk>,
+    pub context: String,
+}
+
+// Rough count for the backends without a tokenizer, a token is around
+// three characters for Swedish and English text.
+fn estimate_tokens(messages: &[Message]) -> usize {
+    messages.iter().map(|m| m.content.chars().count() / 3 + 8).sum()
+}
+
+fn count_tokens(generator: &dyn Generator, messages: &[Message]) -> usize {
+    generator.count_tokens(messages).unwrap_or_else(|| estimate_tokens(messages))
+}
+
+/// Fits the prompt into the context of the model, leaving room for
+/// max_tokens of answer and the tokens the backends keep free. The
+/// ranked chunks are added as long as they fit, and the oldest turns of
+/// the history are dropped if needed. The question itself is never cut.
+/// The extra context (keyword results) is put before the chunks.
+pub fn assemble_prompt(generator: &dyn Generator, max_tokens: Option<usize>, chunks: Vec<RetrievedChunk>,
+                       extra_context: &str, history: &[Message], query: &str) -> Prompt {
+    let build = |sources: &[RetrievedChunk], history: &[Message]| -> (Vec<Message>, String) {
+        let mut context = extra_context.to_string() + &chunks_to_context(sources, false);
+        if context.is_empty() {
+            context = NO_CONTEXT.to_string();
+        }
+        (build_chat_messages(&context, history, query), context)
+    };
+
+    let budget = match generator.context_size() {
+        Some(size) => size.saturating_sub(max_tokens.unwrap_or(generator.default_max_tokens()) + CONTEXT_RESERVE),
+        None => {
+            let (messages, context) = build(&chunks, history);
+            return Prompt { messages, sources: chunks, context };
+        }
+    };
+
+    // Without any chunks, the conversation and the question need to fit.
+    let mut history = history;
+    while !history.is_empty() && count_tokens(generator, &build(&[], history).0) > budget {
+        history = &history[history.len().min(2)..];
+    }
+
+    let mut sources: Vec<RetrievedChunk> = vec![];
+    let mut dropped = 0;
+    for chunk in chunks {
+        sources.push(chunk);
+        if count_tokens(generator, &build(&sources, history).0) > budget {
+            sources.pop();
+            dropped += 1;
+        }
+    }
+    if dropped > 0 {
+        println!("Dropped {} chunks to fit the prompt in {} tokens.", dropped, budget);
+    }
+
+    let (messages, context) = build(&sources, history);
+    Prompt { messages, sources, context }
+}
+
+/// Messages asking the model to turn a follow-up question into a
+/// question which can be understood without the conversation, to
+/// use as retrieval query.
+pub fn rewrite_messages(history: &[Message], query: &str) -> Vec<Message> {
+    let mut conversation = String::new();
+    for message in history {
+        let who = match message.role {
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+            Role::System => continue,
+        };
+        conversation += &format!("{}: {}\n", who, message.content);
+    }
+    vec![
+        Message::system("Rewrite the follow-up question so it can be understood without the conversation. Replace pronouns and references with what they refer to. Only answer with the rewritten question, do not answer it."),
+        Message::user(&format!("Conversation:\n{}\nFollow-up question: {}", conversation, query)),
+    ]
+}
+
+/// Cleans up the rewritten question, falls back on the original.
+pub fn standalone_query(rewritten: &str, query: &str) -> String {
+    let rewritten = rewritten.lines().find(|l| !l.trim().is_empty()).unwrap_or("").trim();
+    let rewritten = rewritten.strip_prefix("Question:").unwrap_or(rewritten).trim().trim_matches('"');
+    if rewritten.is_empty() {
+        query.to_string()
+    } else {
+        rewritten.to_string()
+    }
+}
+
+// =====================================================================
+// Structured answers, with the citations checked against the chunks
+// which were retrieved.
+// =====================================================================
+
+/// A citation found in the answer, "[2]" or "[texts/facts.txt/0]".
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Citation {
+    pub marker: String,
+    /// Number (from 1) of the cited chunk in the sources, None if the
+    /// model cited something which was not retrieved.
+    pub source: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Answer {
+    pub question: String,
+    pub answer: String,
+    pub sources: Vec<RetrievedChunk>,
+    pub citations: Vec<Citation>,
+    /// True if the answer cites a document which was not retrieved.
+    pub invalid_citations: bool,
+}
+
+impl Answer {
+    pub fn new(question: &str, answer: &str, sources: Vec<RetrievedChunk>) -> Self {
+        let citations = extract_citations(answer, &sources);
+        let invalid_citations = citations.iter().any(|c| c.source.is_none());
+        Answer {
+            question: question.to_string(),
+            answer: answer.to_string(),
+            sources,
+            citations,
+            invalid_citations,
+        }
+    }
+}
+
+// Resolves one cited item, a number or a "filename/chunk" label. Returns
+// None if it does not look like a citation at all.
+fn resolve_citation(item: &str, sources: &[RetrievedChunk]) -> Option<Citation> {
+    let item = item.trim().trim_start_matches("document:").trim().trim_matches('"');
+    if let Ok(n) = item.parse::<usize>() {
+        let source = if n >= 1 && n <= sources.len() { Some(n) } else { None };
+        return Some(Citation { marker: item.to_string(), source });
+    }
+    // A label ends in "/<chunk number>", or " p.<page> #<chunk number>"
+    // for a PDF. The page alone will do if one chunk of it was retrieved.
+    let (filename, number) = item.rsplit_once(" #").or_else(|| item.rsplit_once(" p.")).or_else(|| item.rsplit_once('/'))?;
+    if filename.is_empty() || number.parse::<u64>().is_err() {
+        return None;
+    }
+    let on_page = |s: &RetrievedChunk| s.page.is_some_and(|page| format!("{} p.{}", s.filename, page) == item);
+    let source = match sources.iter().position(|s| s.label() == item) {
+        Some(i) => Some(i + 1),
+        None if sources.iter().filter(|s| on_page(s)).count() == 1 => sources.iter().position(on_page).map(|i| i + 1),
+        None => None,
+    };
+    Some(Citation { marker: item.to_string(), source })
+}
+
+/// Finds the citations in the answer: numbers or labels between square
+/// brackets ("[1]", "[1, 3]", "[texts/facts.txt/0]"), and quoted labels
+/// ("document: \"texts/facts.txt/0\"") as the model was asked before.
+pub fn extract_citations(answer: &str, sources: &[RetrievedChunk]) -> Vec<Citation> {
+    let mut citations: Vec<Citation> = vec![];
+    let mut add = |citation: Citation| {
+        if !citations.contains(&citation) {
+            citations.push(citation);
+        }
+    };
+
+    let mut rest = answer;
+    while let Some(start) = rest.find('[') {
+        let Some(len) = rest[start..].find(']') else { break };
+        for item in rest[start + 1..start + len].split([',', ';']) {
+            if let Some(citation) = resolve_citation(item, sources) {
+                add(citation);
+            }
+        }
+        rest = &rest[start + len + 1..];
+    }
+
+    for (i, quoted) in answer.split('"').enumerate() {
+        if i % 2 == 1 && (quoted.contains('/') || quoted.contains(" p.")) {
+            if let Some(citation) = resolve_citation(quoted, sources) {
+                add(citation);
+            }
+        }
+    }
+
+    citations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::GenOptions;
+
+    #[test]
+    fn chat_messages_order() {
+        let history = vec![Message::user("Question: Who has a cat?"), Message::assistant("Peter.")];
+        let messages = build_chat_messages("", &history, "How many?");
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].role, Role::System);
+        assert_eq!(messages[3].content, "Question: How many?");
+    }
+
+    fn source(filename: &str, chunk: u64) -> RetrievedChunk {
+        source_text(filename, chunk, "")
+    }
+
+    fn source_text(filename: &str, chunk: u64, text: &str) -> RetrievedChunk {
+        RetrievedChunk {
+            id: crate::ingest::chunk_id(filename, chunk),
+            filename: filename.to_string(),
+            chunk,
+            page: None,
+            section: None,
+            ulid: None,
+            text: text.to_string(),
+            context: None,
+            hash: String::new(),
+            distance: None,
+            similarity: None,
+            bm25: None,
+            rerank: None,
+            score: 0.0,
+            source: crate::retriever::Source::Vector,
+        }
+    }
+
+    // Counts words, with a small context.
+    struct WordCounter(usize);
+
+    impl Generator for WordCounter {
+        fn name(&self) -> String {
+            "words".to_string()
+        }
+
+        fn generate(&mut self, _messages: &[Message], _opts: &GenOptions, _on_token: &mut dyn FnMut(&str)) -> anyhow::Result<String> {
+            Ok(String::new())
+        }
+
+        fn count_tokens(&self, messages: &[Message]) -> Option<usize> {
+            Some(messages.iter().map(|m| m.content.split_whitespace().count()).sum())
+        }
+
+        fn context_size(&self) -> Option<usize> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn budget_drops_chunks_not_question() {
+        let base = build_chat_messages(NO_CONTEXT, &[], "Where do the cats live?");
+        let base_len = WordCounter(0).count_tokens(&base).unwrap();
+        let chunks = vec![
+            source_text("a.txt", 0, "Sirius lives in Rörums Holma."),
+            source_text("b.txt", 0, &"many words ".repeat(100)),
+            source_text("c.txt", 0, "Maja too."),
+        ];
+        // Room for the small chunks, not for the big one.
+        let generator = WordCounter(base_len + 40 + 10 + CONTEXT_RESERVE);
+        let prompt = assemble_prompt(&generator, Some(10), chunks, "", &[], "Where do the cats live?");
+        let labels: Vec<String> = prompt.sources.iter().map(|s| s.label()).collect();
+        assert_eq!(labels, vec!["a.txt/0", "c.txt/0"]);
+        assert!(prompt.messages.last().unwrap().content.ends_with("Where do the cats live?"));
+
+        // No room at all, the question stays.
+        let prompt = assemble_prompt(&WordCounter(5), Some(10), vec![source_text("a.txt", 0, "Sirius.")], "", &[], "Where?");
+        assert!(prompt.sources.is_empty());
+        assert_eq!(prompt.messages.last().unwrap().content, "Question: Where?");
+    }
+
+    #[test]
+    fn budget_boundary() {
+        let chunk = source_text("a.txt", 0, "Sirius lives in Rörums Holma.");
+        let context = chunks_to_context(std::slice::from_ref(&chunk), false);
+        let len = WordCounter(0).count_tokens(&build_chat_messages(&context, &[], "Where?")).unwrap();
+        // Exactly fits: prompt, answer and reserve.
+        let prompt = assemble_prompt(&WordCounter(len + 100 + CONTEXT_RESERVE), Some(100), vec![chunk.clone()], "", &[], "Where?");
+        assert_eq!(prompt.sources.len(), 1);
+        // One token short.
+        let prompt = assemble_prompt(&WordCounter(len + 100 + CONTEXT_RESERVE - 1), Some(100), vec![chunk], "", &[], "Where?");
+        assert!(prompt.sources.is_empty());
+    }
+
+    #[test]
+    fn citations_numbers() {
+        let sources = vec![source("a.txt", 0), source("b.txt", 3)];
+        let answer = Answer::new("?", "Peter has two cats [1, 2]. Sirius is old [1].", sources);
+        assert_eq!(answer.citations.len(), 2);
+        assert_eq!(answer.citations[1].source, Some(2));
+        assert!(!answer.invalid_citations);
+    }
+
+    #[test]
+    fn citations_invalid() {
+        let sources = vec![source("a.txt", 0)];
+        let answer = Answer::new("?", "See [3] and document \"c.txt/1\", and \"a.txt/0\".", sources);
+        assert_eq!(answer.citations, vec![
+            Citation { marker: "3".to_string(), source: None },
+            Citation { marker: "c.txt/1".to_string(), source: None },
+            Citation { marker: "a.txt/0".to_string(), source: Some(1) },
+        ]);
+        assert!(answer.invalid_citations);
+    }
+
+    #[test]
+    fn citations_pages() {
+        let mut pdf = source("report.pdf", 7);
+        pdf.page = Some(12);
+        let answer = Answer::new("?", "It grew [report.pdf p.12], not [report.pdf p.3].", vec![pdf.clone()]);
+        assert_eq!(answer.citations[0].source, Some(1));
+        assert_eq!(answer.citations[1].source, None);
+
+        // Two chunks of one page.
+        let mut next = source("report.pdf", 8);
+        next.page = Some(12);
+        let answer = Answer::new("?", "It grew [report.pdf p.12 #8], fast [report.pdf p.12].", vec![pdf, next]);
+        assert_eq!(answer.citations[0], Citation { marker: "report.pdf p.12 #8".to_string(), source: Some(2) });
+        assert_eq!(answer.citations[1].source, None);
+    }
+
+    #[test]
+    fn citations_ignore_other_brackets() {
+        let answer = Answer::new("?", "[INST] nothing here [/INST]", vec![]);
+        assert!(answer.citations.is_empty());
+    }
+
+    #[test]
+    fn standalone_cleanup() {
+        assert_eq!(standalone_query("Question: \"How many cats does Peter have?\"\n", "How many?"), "How many cats does Peter have?");
+        assert_eq!(standalone_query("  ", "How many?"), "How many?");
+    }
+}
@@ -0,0 +1,132 @@
+use fastembed::{RerankInitOptions, RerankerModel, TextRerank};
+use once_cell::sync::OnceCell;
+
+use crate::retriever::RetrievedChunk;
+
+// =====================================================================
+// Reranking. The retrievers compare the question and a chunk through
+// their embeddings (or words); a cross-encoder reads the question and
+// the chunk together and scores them more precisely, but is too slow
+// for more than a few dozen chunks. So the retrievers fetch a wider
+// set of candidates, and the cross-encoder picks the best k.
+// =====================================================================
+
+// The reranking model, chosen once before the first reranking.
+static RERANKER_MODEL: OnceCell<RerankerModel> = OnceCell::new();
+
+static RERANKER: OnceCell<TextRerank> = OnceCell::new();
+
+/// Selects the reranking model, by fastembed name ("BGERerankerBase")
+/// or model code ("BAAI/bge-reranker-base").
+pub fn set_reranker_model(name: &str) -> anyhow::Result<()> {
+    let model = parse_reranker_model(name)?;
+    if RERANKER_MODEL.set(model.clone()).is_err() && RERANKER_MODEL.get() != Some(&model) {
+        anyhow::bail!("The reranking model has already been chosen.");
+    }
+    Ok(())
+}
+
+pub fn parse_reranker_model(name: &str) -> anyhow::Result<RerankerModel> {
+    let wanted = name.to_lowercase();
+    TextRerank::list_supported_models()
+        .into_iter()
+        .find(|info| format!("{:?}", info.model).to_lowercase() == wanted || info.model_code.to_lowercase() == wanted)
+        .map(|info| info.model)
+        .ok_or_else(|| anyhow::anyhow!("Unknown reranking model \"{}\", choose from {:?}.", name, reranker_model_names()))
+}
+
+pub fn reranker_model_names() -> Vec<String> {
+    TextRerank::list_supported_models().iter().map(|info| format!("{:?}", info.model)).collect()
+}
+
+// The model is loaded the first time it is used, and then shared.
+fn reranker() -> anyhow::Result<&'static TextRerank> {
+    RERANKER.get_or_try_init(|| {
+        TextRerank::try_new(RerankInitOptions {
+            model_name: RERANKER_MODEL.get().cloned().unwrap_or(RerankerModel::BGERerankerBase),
+            show_download_progress: true,
+            ..Default::default()
+        })
+    })
+}
+
+/// Loads the model now, not on the first question.
+pub fn load_reranker() -> anyhow::Result<()> {
+    reranker().map(|_| ())
+}
+
+/// The number of chunks to retrieve to end up with k; with reranking
+/// more candidates are retrieved than are used.
+pub fn num_candidates(k: usize, rerank: bool, candidates: usize) -> usize {
+    if rerank {
+        candidates.max(k)
+    } else {
+        k
+    }
+}
+
+/// Scores the chunks against the query with the cross-encoder, and
+/// returns the best k, best first.
+pub fn rerank(query: &str, chunks: Vec<RetrievedChunk>, k: usize) -> anyhow::Result<Vec<RetrievedChunk>> {
+    if chunks.is_empty() {
+        return Ok(chunks);
+    }
+    let documents: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+    let results = reranker()?.rerank(query, documents, false, None)?;
+    let mut scores = vec![0.0; chunks.len()];
+    for result in results {
+        scores[result.index] = result.score;
+    }
+    Ok(order_by_scores(chunks, &scores, k))
+}
+
+// Sorts the chunks on their reranking scores and keeps the best k.
+fn order_by_scores(chunks: Vec<RetrievedChunk>, scores: &[f32], k: usize) -> Vec<RetrievedChunk> {
+    let mut reranked: Vec<RetrievedChunk> = chunks.into_iter()
+        .zip(scores.iter())
+        .map(|(chunk, score)| RetrievedChunk { rerank: Some(*score), ..chunk })
+        .collect();
+    reranked.sort_by(|a, b| b.rerank.partial_cmp(&a.rerank).unwrap_or(std::cmp::Ordering::Equal));
+    reranked.truncate(k);
+    reranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retriever::Source;
+
+    fn chunk(chunk: u64) -> RetrievedChunk {
+        RetrievedChunk {
+            id: crate::ingest::chunk_id("a.txt", chunk),
+            filename: "a.txt".to_string(),
+            chunk,
+            page: None,
+            section: None,
+            ulid: None,
+            text: String::new(),
+            context: None,
+            hash: String::new(),
+            distance: None,
+            similarity: None,
+            bm25: None,
+            rerank: None,
+            score: 0.0,
+            source: Source::Vector,
+        }
+    }
+
+    #[test]
+    fn best_scores_first() {
+        let reranked = order_by_scores(vec![chunk(0), chunk(1), chunk(2)], &[-2.0, 5.5, 0.3], 2);
+        assert_eq!(reranked.iter().map(|c| c.chunk).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(reranked[0].rerank, Some(5.5));
+    }
+
+    #[test]
+    fn parse_model_names() {
+        assert_eq!(parse_reranker_model("bgererankerbase").unwrap(), RerankerModel::BGERerankerBase);
+        assert_eq!(parse_reranker_model("BAAI/bge-reranker-base").unwrap(), RerankerModel::BGERerankerBase);
+        assert!(parse_reranker_model("colbert").is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use oasysdb::prelude::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use tantivy::schema::TantivyDocument;
+use crate::database::{md_to_hashmap, md_to_str, similarity};
+use crate::embedder::embeddings;
+use crate::filter::Filter;
+use crate::ingest::chunk_id;
+use crate::manifest::Manifest;
+use crate::tant::{search_documents_lenient, search_documents_filtered, get_file_chunks, get_index_schema, text_from_owned_value, u64_from_owned_value};
+
+// The "k" constant from the reciprocal rank fusion paper (Cormack et al.),
+// dampens the influence of the top ranks.
+const RRF_K: f32 = 60.0;
+
+/// Where a retrieved chunk was found.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Source {
+    Vector,
+    Keyword,
+    Both,
+}
+
+/// A chunk of text returned by one of the retrievers, with the
+/// scores from the stores it was found in.
+#[derive(Debug, Clone, Serialize)]
+pub struct RetrievedChunk {
+    pub id: String,            // Same chunk ID in both stores.
+    pub filename: String,
+    pub chunk: u64,
+    pub page: Option<u64>,     // Page of a PDF, counting from 1.
+    pub section: Option<String>, // Markdown headings, only in the vector database.
+    pub ulid: Option<String>,  // Only in the vector database.
+    pub text: String,
+    pub context: Option<String>, // The text with the neighbouring chunks, see expand_window().
+    pub hash: String,
+    pub distance: Option<f32>, // Vector distance, lower is better.
+    pub similarity: Option<f32>, // Normalised vector similarity, 0..1, higher is better.
+    pub bm25: Option<f32>,     // Tantivy score, higher is better.
+    pub rerank: Option<f32>,   // Cross-encoder score, higher is better.
+    pub score: f32,            // Fused score, higher is better.
+    pub source: Source,
+}
+
+impl RetrievedChunk {
+    /// The text for the prompt, with the neighbouring chunks if the
+    /// window was expanded.
+    pub fn context_text(&self) -> &str {
+        self.context.as_deref().unwrap_or(&self.text)
+    }
+
+    /// "filename/chunk", or "filename p.page #chunk" for a PDF, as used
+    /// in the context and the output. Unique, a page has more chunks.
+    pub fn label(&self) -> String {
+        match self.page {
+            Some(page) => format!("{} p.{} #{}", self.filename, page, self.chunk),
+            None => format!("{}/{}", self.filename, self.chunk),
+        }
+    }
+}
+
+// Same hash as used for the hash_body field in the tantivy database,
+// so identical chunks from the two stores end up with the same key.
+fn hash_text(text: &str) -> String {
+    blake3::hash(text.as_bytes()).to_string()
+}
+
+// The nearest neighbours among the records of the files which pass the
+// filter. The index cannot be restricted to some records, so all their
+// distances are computed.
+fn filtered_search(collection: &Collection, manifest: &Manifest, vector: &Vector, k: usize, filter: &Filter) -> anyhow::Result<Vec<SearchResult>> {
+    let mut result = vec![];
+    for (filename, entry) in &manifest.files {
+        if !filter.matches(filename, Some(entry)) {
+            continue;
+        }
+        for id in &entry.ids {
+            let record = collection.get(&VectorID(*id))?;
+            let distance = collection.config.distance.calculate(vector, &record.vector);
+            result.push(SearchResult { id: *id, distance, data: record.data });
+        }
+    }
+    result.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(std::cmp::Ordering::Equal));
+    result.truncate(k);
+    Ok(result)
+}
+
+/// Nearest neighbours from the vector database, filtered on minimum
+/// (normalised) similarity, and restricted to the files which pass
+/// the filter.
+pub fn vector_search(collection: &Collection, manifest: &Manifest, query: &str, k: usize, minsim: f32, filter: &Filter) -> anyhow::Result<Vec<RetrievedChunk>> {
+    if k == 0 {
+        return Ok(vec![]);
+    }
+    let vectors = embeddings(vec![query])?;
+    let v = vectors.first().ok_or_else(|| anyhow::anyhow!("No embedding for the query."))?;
+    let embedded_query = Vector(v.to_vec());
+    let result = if filter.is_empty() {
+        collection.search(&embedded_query, k)?
+    } else {
+        filtered_search(collection, manifest, &embedded_query, k, filter)?
+    };
+
+    let mut chunks = vec![];
+    let metric = collection.config.distance;
+    for res in result.into_iter().filter(|r| similarity(r.distance, &metric) >= minsim) {
+        let hm = md_to_hashmap(&res.data).unwrap_or_default();
+        let text = hm.get("text").and_then(md_to_str).unwrap_or_default();
+        let filename = hm.get("filename").and_then(md_to_str).unwrap_or_default();
+        let chunk = hm.get("ccnt").and_then(md_to_str).and_then(|s| s.parse().ok()).unwrap_or(0);
+        chunks.push(RetrievedChunk {
+            id: chunk_id(&filename, chunk),
+            filename,
+            chunk,
+            page: hm.get("page").and_then(md_to_str).and_then(|s| s.parse().ok()),
+            section: hm.get("section").and_then(md_to_str),
+            ulid: hm.get("ulid").and_then(md_to_str),
+            hash: hash_text(&text),
+            text,
+            context: None,
+            distance: Some(res.distance),
+            similarity: Some(similarity(res.distance, &metric)),
+            bm25: None,
+            rerank: None,
+            score: 0.0,
+            source: Source::Vector,
+        });
+    }
+    Ok(chunks)
+}
+
+/// Best matching chunks from the tantivy database. Without a filter the
+/// query is parsed leniently, so a plain question can be used as keyword
+/// query.
+pub fn keyword_search(query: &str, k: usize, filter: &Filter) -> anyhow::Result<Vec<RetrievedChunk>> {
+    if k == 0 {
+        return Ok(vec![]);
+    }
+    let (_index, schema) = get_index_schema()?;
+    let title = schema.get_field("title")?;
+    let body = schema.get_field("body")?;
+    let page_number = schema.get_field("page_number")?;
+    let chunk_number = schema.get_field("chunk_number")?;
+    let hash_body = schema.get_field("hash_body")?;
+
+    let field_str = |d: &TantivyDocument, f| d.get_first(f).map(text_from_owned_value).unwrap_or("").to_string();
+
+    let mut chunks = vec![];
+    let documents = if filter.is_empty() {
+        search_documents_lenient(query, k)?
+    } else {
+        search_documents_filtered(query, k, filter)?
+    };
+    for (score, d, _snippet) in documents {
+        let text = field_str(&d, body);
+        let hash = match field_str(&d, hash_body) {
+            h if h.is_empty() => hash_text(&text),
+            h => h,
+        };
+        let filename = field_str(&d, title);
+        let chunk = d.get_first(chunk_number).map(|v| *u64_from_owned_value(v)).unwrap_or(0);
+        chunks.push(RetrievedChunk {
+            id: chunk_id(&filename, chunk),
+            filename,
+            chunk,
+            page: d.get_first(page_number).map(|v| *u64_from_owned_value(v)).filter(|p| *p > 0),
+            section: None,
+            ulid: None,
+            text,
+            context: None,
+            hash,
+            distance: None,
+            similarity: None,
+            bm25: Some(score),
+            rerank: None,
+            score: 0.0,
+            source: Source::Keyword,
+        });
+    }
+    Ok(chunks)
+}
+
+/// Reciprocal rank fusion of two ranked lists. Chunks found in both lists
+/// (same text hash) are merged and get the sum of their reciprocal ranks.
+pub fn fuse_rrf(vector: Vec<RetrievedChunk>, keyword: Vec<RetrievedChunk>, k: usize) -> Vec<RetrievedChunk> {
+    let mut fused: Vec<RetrievedChunk> = vec![];
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for list in [vector, keyword] {
+        for (rank, chunk) in list.into_iter().enumerate() {
+            let rrf = 1.0 / (RRF_K + rank as f32 + 1.0);
+            match seen.get(&chunk.hash) {
+                Some(&i) => {
+                    let existing = &mut fused[i];
+                    if existing.source != chunk.source {
+                        existing.score += rrf;
+                        existing.source = Source::Both;
+                    }
+                    existing.distance = existing.distance.or(chunk.distance);
+                    existing.similarity = existing.similarity.or(chunk.similarity);
+                    existing.ulid = existing.ulid.take().or(chunk.ulid);
+                    existing.section = existing.section.take().or(chunk.section);
+                    existing.bm25 = existing.bm25.or(chunk.bm25);
+                }
+                None => {
+                    seen.insert(chunk.hash.clone(), fused.len());
+                    fused.push(RetrievedChunk { score: rrf, ..chunk });
+                }
+            }
+        }
+    }
+
+    fused.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    fused.truncate(k);
+    fused
+}
+
+/// Queries both the vector and the tantivy database with the same
+/// question and returns one ranked list of (at most) k chunks.
+pub fn hybrid_search(collection: &Collection, manifest: &Manifest, query: &str, k: usize, minsim: f32, filter: &Filter) -> anyhow::Result<Vec<RetrievedChunk>> {
+    // Take a few more candidates from each store, the overlap is removed
+    // in the fusion.
+    let candidates = k * 2;
+    let vector = vector_search(collection, manifest, query, candidates, minsim, filter)?;
+    let keyword = keyword_search(query, candidates, filter)?;
+    Ok(fuse_rrf(vector, keyword, k))
+}
+
+// ---------------------------------------------------------------------
+// Small-to-big. Small chunks are found more precisely, but the model
+// needs the text around them to answer, so the chunks next to a hit
+// (same file, chunk numbers window before and after) are sent along.
+// ---------------------------------------------------------------------
+
+// The chunks from..=to of a file in the vector database. The records of
+// a file are inserted in chunk order, so the IDs are tried at the same
+// positions first, and all IDs of the file are read if that fails.
+fn vector_neighbours(collection: &Collection, manifest: &Manifest, filename: &str, from: u64, to: u64) -> anyhow::Result<BTreeMap<u64, String>> {
+    let mut chunks = BTreeMap::new();
+    let Some(entry) = manifest.files.get(filename) else {
+        return Ok(chunks);
+    };
+    let read = |ids: &[u32], chunks: &mut BTreeMap<u64, String>| -> anyhow::Result<()> {
+        for id in ids {
+            let record = collection.get(&VectorID(*id))?;
+            let hm = md_to_hashmap(&record.data).unwrap_or_default();
+            let number = hm.get("ccnt").and_then(md_to_str).and_then(|s| s.parse::<u64>().ok());
+            if let Some(number) = number.filter(|n| (from..=to).contains(n)) {
+                chunks.insert(number, hm.get("text").and_then(md_to_str).unwrap_or_default());
+            }
+        }
+        Ok(())
+    };
+    let end = (to as usize + 1).min(entry.ids.len());
+    if (from as usize) < end {
+        read(&entry.ids[from as usize..end], &mut chunks)?;
+    }
+    if chunks.len() < end.saturating_sub(from as usize) {
+        read(&entry.ids, &mut chunks)?;
+    }
+    Ok(chunks)
+}
+
+// Joins consecutive chunks. Chunks made with overlap start with the end
+// of the previous chunk, that part is only included once. The overlap
+// must start and end at a word boundary.
+fn join_chunks<'a>(texts: impl IntoIterator<Item = &'a str>) -> String {
+    let mut joined = String::new();
+    for text in texts {
+        if joined.is_empty() {
+            joined.push_str(text);
+            continue;
+        }
+        let overlap = text.char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(text.len()))
+            .filter(|&i| i > 0 && (i == text.len() || text[i..].starts_with(char::is_whitespace)))
+            .filter(|&i| joined.ends_with(&text[..i]))
+            .rfind(|&i| joined[..joined.len() - i].ends_with(char::is_whitespace) || joined.len() == i)
+            .unwrap_or(0);
+        let rest = text[overlap..].trim_start();
+        if !rest.is_empty() {
+            if overlap == 0 {
+                joined.push('\n');
+            } else {
+                joined.push(' ');
+            }
+            joined.push_str(rest);
+        }
+    }
+    joined
+}
+
+/// Adds the window chunks before and after every chunk as its context.
+/// The neighbours are taken from the vector database, or from the text
+/// database for files which are only there. A chunk which is already
+/// in the window of a better ranked chunk is left out.
+pub fn expand_window(collection: &Collection, manifest: &Manifest, chunks: Vec<RetrievedChunk>, window: usize) -> anyhow::Result<Vec<RetrievedChunk>> {
+    if window == 0 {
+        return Ok(chunks);
+    }
+    let mut index = None;
+    let mut covered: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+    let mut expanded = vec![];
+    for mut chunk in chunks {
+        let ranges = covered.entry(chunk.filename.clone()).or_default();
+        if ranges.iter().any(|(from, to)| (*from..=*to).contains(&chunk.chunk)) {
+            continue;
+        }
+        let from = chunk.chunk.saturating_sub(window as u64);
+        let to = chunk.chunk + window as u64;
+
+        let mut texts = vector_neighbours(collection, manifest, &chunk.filename, from, to)?;
+        if texts.len() <= 1 {
+            if index.is_none() {
+                index = Some(get_index_schema()?.0);
+            }
+            if let Some(index) = &index {
+                texts.extend(get_file_chunks(index, &chunk.filename)?.into_iter().filter(|(n, _)| (from..=to).contains(n)));
+            }
+        }
+        texts.insert(chunk.chunk, chunk.text.clone());
+
+        let first = texts.keys().next().copied().unwrap_or(chunk.chunk);
+        let last = texts.keys().next_back().copied().unwrap_or(chunk.chunk);
+        ranges.push((first, last));
+        chunk.context = Some(join_chunks(texts.values().map(|t| t.as_str())));
+        expanded.push(chunk);
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::data_to_record;
+    use crate::manifest::FileEntry;
+
+    fn chunk(filename: &str, text: &str, source: Source) -> RetrievedChunk {
+        RetrievedChunk {
+            id: chunk_id(filename, 0),
+            filename: filename.to_string(),
+            chunk: 0,
+            page: None,
+            section: None,
+            ulid: None,
+            text: text.to_string(),
+            context: None,
+            hash: hash_text(text),
+            distance: None,
+            similarity: None,
+            bm25: None,
+            rerank: None,
+            score: 0.0,
+            source,
+        }
+    }
+
+    #[test]
+    fn rrf_merges_duplicates() {
+        let vector = vec![chunk("a.txt", "alpha", Source::Vector), chunk("b.txt", "beta", Source::Vector)];
+        let keyword = vec![chunk("b.txt", "beta", Source::Keyword), chunk("c.txt", "gamma", Source::Keyword)];
+        let fused = fuse_rrf(vector, keyword, 10);
+        assert_eq!(fused.len(), 3);
+        assert_eq!(fused[0].filename, "b.txt");
+        assert_eq!(fused[0].source, Source::Both);
+    }
+
+    #[test]
+    fn join_overlapping_chunks() {
+        assert_eq!(join_chunks(["the quick brown fox", "brown fox jumps"]), "the quick brown fox jumps");
+        assert_eq!(join_chunks(["one two", "three"]), "one two\nthree");
+        // Not a word boundary in the previous chunk.
+        assert_eq!(join_chunks(["a cat", "at home"]), "a cat\nat home");
+    }
+
+    #[test]
+    fn window_from_vector_database() {
+        let emb = vec![1.0f32, 0.0];
+        let records: Vec<_> = (0..5).map(|i| data_to_record(&emb, "a.txt", &format!("chunk {}.", i), i, None, None)).collect();
+        let mut collection = Collection::new(&Config::default());
+        let ids = collection.insert_many(&records).unwrap();
+        let mut manifest = Manifest::default();
+        manifest.files.insert("a.txt".to_string(), FileEntry { ids: ids.iter().map(|id| id.0).collect(), ..Default::default() });
+
+        let mut hit = chunk("a.txt", "chunk 2.", Source::Vector);
+        hit.chunk = 2;
+        let mut near = chunk("a.txt", "chunk 3.", Source::Vector);
+        near.chunk = 3;
+        let expanded = expand_window(&collection, &manifest, vec![hit, near], 1).unwrap();
+        assert_eq!(expanded.len(), 1);
+        assert_eq!(expanded[0].text, "chunk 2.");
+        assert_eq!(expanded[0].context_text(), "chunk 1.\nchunk 2.\nchunk 3.");
+    }
+
+    #[test]
+    fn filtered_search_only_in_matching_files() {
+        let records = vec![
+            data_to_record(&vec![1.0f32, 0.0], "team/a.txt", "far", 0, None, None),
+            data_to_record(&vec![0.0f32, 1.0], "other/b.txt", "near", 0, None, None),
+        ];
+        let mut collection = Collection::new(&Config::default());
+        collection.insert_many(&records).unwrap();
+        let manifest = Manifest::from_collection(&collection).unwrap();
+        let filter = Filter::new(&["team".to_string()], None, None, &[]).unwrap();
+        let result = filtered_search(&collection, &manifest, &Vector(vec![0.0, 1.0]), 3, &filter).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(md_to_hashmap(&result[0].data).unwrap().get("text").and_then(md_to_str).unwrap(), "far");
+    }
+
+    #[test]
+    fn rrf_truncates() {
+        let vector = vec![chunk("a.txt", "alpha", Source::Vector), chunk("b.txt", "beta", Source::Vector)];
+        let fused = fuse_rrf(vector, vec![], 1);
+        assert_eq!(fused.len(), 1);
+        assert_eq!(fused[0].filename, "a.txt");
+    }
+}
@@ -0,0 +1,368 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use oasysdb::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::chunker::{Chunker, Strategy};
+use crate::embedder::embeddings;
+use crate::ingest::{path_files, Ingested, Pipeline, Store};
+use crate::manifest::{save_manifest, Manifest};
+use crate::filter::Filter;
+use crate::generator::{get_generator, Generator, GenOptions};
+use crate::qmistral::QModelConfig;
+use crate::rag::{assemble_prompt, Answer};
+use crate::reranker::{load_reranker, num_candidates, rerank};
+use crate::retriever::{expand_window, hybrid_search, keyword_search, vector_search, RetrievedChunk};
+use crate::tant::get_index_schema;
+
+// =====================================================================
+// HTTP/JSON interface. The database, the index and the models are
+// loaded once and shared between the requests.
+//
+//   GET  /        Collection and backend information.
+//   POST /ingest  {"path": "texts/", "store": "vector"|"text"|"both"}
+//   POST /search  {"query": "...", "k": 3, "mode": "vector"|"keyword"|"hybrid"}
+//   POST /ask     {"query": "...", "k": 3, "hybrid": true}, answer as SSE.
+// =====================================================================
+
+/// Settings from the command line, used when a request does not
+/// specify them.
+pub struct ServerConfig {
+    pub address: String,
+    pub collection: String,
+    pub chunksize: usize,
+    pub chunk_strategy: Strategy,
+    pub overlap: usize,
+    pub nearest: usize,
+    pub window: usize,
+    pub rerank: bool,
+    pub candidates: usize,
+    pub filter: Filter,
+    pub minsim: f32,
+    pub hybrid: bool,
+    pub backend: String,
+    pub model: String,
+    pub gguf: QModelConfig,
+    pub opts: GenOptions,
+}
+
+struct AppState {
+    cfg: ServerConfig,
+    db: Mutex<Database>,
+    collection: Mutex<Collection>,
+    manifest: Mutex<Manifest>,
+    generator: Arc<Mutex<Box<dyn Generator>>>,
+    /// Name of the generator, the generator itself is locked while it
+    /// answers.
+    generator_name: String,
+}
+
+// A panic in one request should not take the other requests down with it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Errors are returned as {"error": "..."}.
+struct ApiError(StatusCode, anyhow::Error);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1.to_string() }))).into_response()
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(err: E) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, err.into())
+    }
+}
+
+fn bad_request(msg: String) -> ApiError {
+    ApiError(StatusCode::BAD_REQUEST, anyhow::anyhow!(msg))
+}
+
+// Embedding and searching are blocking, keep them off the async workers.
+async fn blocking<T, F>(f: F) -> Result<T, ApiError>
+where
+    F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+// ---------------------------------------------------------------------
+
+// The collection is locked while files are ingested.
+async fn info(State(state): State<Arc<AppState>>) -> Result<Json<serde_json::Value>, ApiError> {
+    let size = {
+        let state = state.clone();
+        blocking(move || Ok(lock(&state.collection).len())).await?
+    };
+    Ok(Json(json!({
+        "collection": state.cfg.collection,
+        "size": size,
+        "generator": state.generator_name,
+    })))
+}
+
+// ---------------------------------------------------------------------
+
+#[derive(Debug, Deserialize)]
+struct IngestRequest {
+    path: String,
+    #[serde(default = "default_store")]
+    store: String,
+    chunksize: Option<usize>,
+    strategy: Option<String>,
+    overlap: Option<usize>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+}
+
+fn default_store() -> String {
+    "both".to_string()
+}
+
+#[derive(Debug, Serialize)]
+struct IngestResponse {
+    files: usize,
+    unchanged: usize,
+    removed: usize,
+    vector: usize,
+    text: usize,
+    /// Files which could not be read, with the reason.
+    errors: Vec<String>,
+}
+
+fn ingest(state: &AppState, req: &IngestRequest) -> Result<IngestResponse, ApiError> {
+    let store: Store = req.store.parse().map_err(|e: anyhow::Error| bad_request(e.to_string()))?;
+    let path = Path::new(&req.path);
+    let filenames = path_files(path).map_err(|e| bad_request(e.to_string()))?;
+    let strategy = match &req.strategy {
+        Some(strategy) => strategy.parse().map_err(|e: anyhow::Error| bad_request(e.to_string()))?,
+        None => state.cfg.chunk_strategy,
+    };
+    let chunker = Chunker::new(strategy,
+                               req.chunksize.unwrap_or(state.cfg.chunksize),
+                               req.overlap.unwrap_or(state.cfg.overlap))
+        .map_err(|e| bad_request(e.to_string()))?;
+
+    let mut response = IngestResponse { files: filenames.len(), unchanged: 0, removed: 0, vector: 0, text: 0, errors: vec![] };
+    let mut collection = lock(&state.collection);
+    let mut manifest = lock(&state.manifest);
+    {
+        let mut pipeline = Pipeline::new(chunker).with_tags(req.tags.clone());
+        if store.vector() {
+            pipeline = pipeline.with_vector(&mut collection, &mut manifest);
+        }
+        if store.text() {
+            let (index, _schema) = get_index_schema()?;
+            pipeline = pipeline.with_text(index)?;
+        }
+        if path.is_dir() {
+            response.removed = pipeline.remove_missing(path, &filenames)?.len();
+        }
+        pipeline.add_files(&filenames, &mut |filename, result| {
+            // One broken file should not stop the others.
+            let report = match result {
+                Ok(report) => report,
+                Err(e) => {
+                    response.errors.push(format!("{}: {}", filename.display(), e));
+                    return;
+                }
+            };
+            match report.vector {
+                Some(Ingested::Unchanged) => response.unchanged += 1,
+                Some(Ingested::Added(num)) | Some(Ingested::Replaced(num)) => response.vector += num,
+                None => {}
+            }
+            response.text += report.text.unwrap_or(0) as usize;
+        })?;
+        pipeline.commit()?;
+    }
+    if store.vector() {
+        lock(&state.db).save_collection(&state.cfg.collection, &collection)?;
+        save_manifest(&state.cfg.collection, &manifest)?;
+    }
+    println!("Ingested {:?}: {:?}", req.path, response);
+    Ok(response)
+}
+
+async fn ingest_handler(State(state): State<Arc<AppState>>, Json(req): Json<IngestRequest>) -> Result<Json<IngestResponse>, ApiError> {
+    let response = blocking(move || ingest(&state, &req)).await?;
+    Ok(Json(response))
+}
+
+// ---------------------------------------------------------------------
+
+#[derive(Debug, Clone, Deserialize)]
+struct SearchRequest {
+    query: String,
+    k: Option<usize>,
+    mode: Option<String>,
+    minsim: Option<f32>,
+    window: Option<usize>,
+    rerank: Option<bool>,
+    filter: Option<FilterRequest>,
+}
+
+// Like the --filter-path, --since, --until and --filter-tag options.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct FilterRequest {
+    paths: Vec<String>,
+    since: Option<String>,
+    until: Option<String>,
+    tags: Vec<String>,
+}
+
+fn search(state: &AppState, req: &SearchRequest) -> Result<Vec<RetrievedChunk>, ApiError> {
+    let k = req.k.unwrap_or(state.cfg.nearest);
+    let minsim = req.minsim.unwrap_or(state.cfg.minsim);
+    let reranked = req.rerank.unwrap_or(state.cfg.rerank);
+    let n = num_candidates(k, reranked, state.cfg.candidates);
+    let filter = match &req.filter {
+        Some(f) => Filter::new(&f.paths, f.since.as_deref(), f.until.as_deref(), &f.tags).map_err(|e| bad_request(e.to_string()))?,
+        None => state.cfg.filter.clone(),
+    };
+    let default_mode = if state.cfg.hybrid { "hybrid" } else { "vector" };
+    let chunks = match req.mode.as_deref().unwrap_or(default_mode) {
+        "vector" => vector_search(&lock(&state.collection), &lock(&state.manifest), &req.query, n, minsim, &filter)?,
+        "keyword" => keyword_search(&req.query, n, &filter)?,
+        "hybrid" => hybrid_search(&lock(&state.collection), &lock(&state.manifest), &req.query, n, minsim, &filter)?,
+        mode => return Err(bad_request(format!("Unknown mode \"{}\", choose from vector, keyword or hybrid.", mode))),
+    };
+    let chunks = if reranked { rerank(&req.query, chunks, k)? } else { chunks };
+    let window = req.window.unwrap_or(state.cfg.window);
+    let chunks = expand_window(&lock(&state.collection), &lock(&state.manifest), chunks, window)?;
+    Ok(chunks)
+}
+
+async fn search_handler(State(state): State<Arc<AppState>>, Json(req): Json<SearchRequest>) -> Result<Json<Vec<RetrievedChunk>>, ApiError> {
+    let chunks = blocking(move || search(&state, &req)).await?;
+    Ok(Json(chunks))
+}
+
+// ---------------------------------------------------------------------
+
+#[derive(Debug, Clone, Deserialize)]
+struct AskRequest {
+    query: String,
+    k: Option<usize>,
+    hybrid: Option<bool>,
+    minsim: Option<f32>,
+    window: Option<usize>,
+    rerank: Option<bool>,
+    filter: Option<FilterRequest>,
+    max_tokens: Option<usize>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    seed: Option<u64>,
+}
+
+// The answer is streamed as server-sent events: one "sources" event with
+// the retrieved chunks, "token" events with the text, and at the end a
+// "done" event with the whole answer, the sources and the citations
+// (or an "error" event).
+async fn ask_handler(State(state): State<Arc<AppState>>, Json(req): Json<AskRequest>)
+                     -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let search_req = SearchRequest {
+        query: req.query.clone(),
+        k: req.k,
+        mode: req.hybrid.map(|h| if h { "hybrid" } else { "vector" }.to_string()),
+        minsim: req.minsim,
+        window: req.window,
+        rerank: req.rerank,
+        filter: req.filter.clone(),
+    };
+    let search_state = state.clone();
+    let chunks = blocking(move || search(&search_state, &search_req)).await?;
+
+    let defaults = &state.cfg.opts;
+    let opts = GenOptions {
+        max_tokens: req.max_tokens.or(defaults.max_tokens),
+        temperature: req.temperature.or(defaults.temperature),
+        top_p: req.top_p.or(defaults.top_p),
+        seed: req.seed.or(defaults.seed),
+        repeat_penalty: defaults.repeat_penalty,
+    };
+
+    // The ollama and genai backends start their own runtime, so the
+    // generation runs on a plain thread, not inside tokio.
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+    let generator = state.generator.clone();
+    let question = req.query.clone();
+    std::thread::spawn(move || {
+        let mut generator = lock(&generator);
+
+        // The prompt is fitted with the tokenizer of the model, so only
+        // the chunks which fit are sent as sources.
+        let prompt = assemble_prompt(&**generator, opts.max_tokens, chunks, "", &[], &question);
+        if let Ok(event) = Event::default().event("sources").json_data(&prompt.sources) {
+            let _ = tx.send(event);
+        }
+
+        let token_tx = tx.clone();
+        let result = generator.generate(&prompt.messages, &opts, &mut |token| {
+            let _ = token_tx.send(Event::default().event("token").data(token));
+        });
+        let event = match result {
+            Ok(answer) => Event::default().event("done").json_data(Answer::new(&question, &answer, prompt.sources)),
+            Err(e) => Ok(Event::default().event("error").data(e.to_string())),
+        };
+        if let Ok(event) = event {
+            let _ = tx.send(event);
+        }
+    });
+
+    let stream = UnboundedReceiverStream::new(rx).map(Ok);
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+// ---------------------------------------------------------------------
+
+pub fn serve(cfg: ServerConfig, db: Database, collection: Collection, manifest: Manifest) -> anyhow::Result<()> {
+    // Load everything now, not on the first request.
+    let generator = get_generator(&cfg.backend, &cfg.model, &cfg.gguf)?;
+    let _ = embeddings(vec!["Minerva"])?;
+    if cfg.rerank {
+        load_reranker()?;
+    }
+    let address = cfg.address.clone();
+    let generator_name = generator.name();
+
+    let state = Arc::new(AppState {
+        cfg,
+        db: Mutex::new(db),
+        collection: Mutex::new(collection),
+        manifest: Mutex::new(manifest),
+        generator: Arc::new(Mutex::new(generator)),
+        generator_name,
+    });
+
+    let app = Router::new()
+        .route("/", get(info))
+        .route("/ingest", post(ingest_handler))
+        .route("/search", post(search_handler))
+        .route("/ask", post(ask_handler))
+        .with_state(state);
+
+    let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    rt.block_on(async {
+        let listener = tokio::net::TcpListener::bind(&address).await?;
+        println!("Listening on http://{}", address);
+        axum::serve(listener, app).await?;
+        Ok(())
+    })
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::kb::settings_dir;
+
+// Settings which belong to an oasysdb collection, but which oasysdb
+// does not store itself. They are kept in a small JSON file next to
+// the database, one per collection.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionSettings {
+    /// Name of the fastembed model used to create the vectors.
+    pub embedding_model: String,
+    /// Dimension of the vectors.
+    pub dim: usize,
+    /// Distance metric of the collection, "euclidean" or "cosine".
+    #[serde(default = "default_distance")]
+    pub distance: String,
+    /// How the files are chunked, unless asked otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunking: Option<ChunkSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkSettings {
+    /// "chars", "tokens", "markdown" or "sentences".
+    pub strategy: String,
+    pub size: usize,
+    pub overlap: usize,
+}
+
+// Collections from before the metric was configurable.
+fn default_distance() -> String {
+    "euclidean".to_string()
+}
+
+impl CollectionSettings {
+    pub fn new(embedding_model: &str, dim: usize, distance: &str) -> Self {
+        CollectionSettings {
+            embedding_model: embedding_model.to_string(),
+            dim,
+            distance: distance.to_string(),
+            chunking: None,
+        }
+    }
+
+    /// Refuses an embedding model which is not the one the collection
+    /// was created with; the distances would be meaningless.
+    pub fn check_model(&self, collection: &str, embedding_model: &str, dim: usize) -> anyhow::Result<()> {
+        if self.embedding_model != embedding_model || self.dim != dim {
+            anyhow::bail!(
+                "Collection \"{}\" was created with {} (dim {}), not with {} (dim {}).",
+                collection, self.embedding_model, self.dim, embedding_model, dim
+            );
+        }
+        Ok(())
+    }
+}
+
+fn settings_path(collection: &str) -> PathBuf {
+    settings_dir().join(format!("{}.json", collection))
+}
+
+/// Returns None if the collection has no settings (yet).
+pub fn load_settings(collection: &str) -> anyhow::Result<Option<CollectionSettings>> {
+    let path = settings_path(collection);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+pub fn save_settings(collection: &str, settings: &CollectionSettings) -> anyhow::Result<()> {
+    fs::create_dir_all(settings_dir())?;
+    fs::write(settings_path(collection), serde_json::to_string_pretty(settings)?)?;
+    Ok(())
+}
+
+/// The names of the collections with settings, sorted.
+pub fn list_collections() -> anyhow::Result<Vec<String>> {
+    let dir = settings_dir();
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "json") {
+            if let Some(name) = path.file_stem() {
+                names.push(name.to_string_lossy().to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub fn delete_settings(collection: &str) -> anyhow::Result<()> {
+    let path = settings_path(collection);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_mismatch() {
+        let settings = CollectionSettings::new("AllMiniLML6V2", 384, "euclidean");
+        assert!(settings.check_model("vectors", "AllMiniLML6V2", 384).is_ok());
+        assert!(settings.check_model("vectors", "MultilingualE5Small", 384).is_err());
+    }
+
+    #[test]
+    fn old_settings_are_euclidean() {
+        let settings: CollectionSettings = serde_json::from_str(r#"{"embedding_model": "AllMiniLML6V2", "dim": 384}"#).unwrap();
+        assert_eq!(settings.distance, "euclidean");
+    }
+}
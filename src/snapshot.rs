@@ -0,0 +1,332 @@
+use oasysdb::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::database::{json_to_md, md_to_json, parse_distance};
+use crate::embedder::parse_embedding_model;
+use crate::ingest::Chunk;
+use crate::manifest::{delete_manifest, delete_text_manifest, load_manifest, save_manifest, FileEntry, Manifest};
+use crate::settings::{delete_settings, list_collections, load_settings, save_settings, CollectionSettings};
+use crate::tant::{create_text_index, doc_tags, doc_text, doc_u64, get_index_schema, get_num_documents, iter_documents,
+                  text_index_settings, BulkWriter, TextIndexSettings};
+
+// =====================================================================
+// Snapshots. A whole knowledge base in one zip file, to back it up or
+// to move it to another machine:
+//
+//   snapshot.json                   format, the collections with their
+//                                   settings, the text index settings
+//   collections/<name>/vectors.jsonl  one record per line, in ID order
+//   collections/<name>/manifest.json
+//   text.jsonl                      the stored fields of the chunks
+//
+// The collections are those with settings. Records get new IDs when
+// they are imported, the manifests are renumbered to match. The whole
+// archive is read before anything is written, and what was written is
+// removed again if the import fails halfway.
+// =====================================================================
+
+const SNAPSHOT_FORMAT: u32 = 1;
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const TEXT_FILE: &str = "text.jsonl";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    /// Version of the layout of the archive.
+    pub format: u32,
+    /// When the snapshot was made, local time, "%Y%m%dT%H%M".
+    pub created: String,
+    pub collections: Vec<CollectionInfo>,
+    pub text_index: TextIndexSettings,
+    /// Number of chunks in the text database.
+    pub documents: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectionInfo {
+    pub name: String,
+    pub settings: CollectionSettings,
+    /// Number of records.
+    pub vectors: usize,
+}
+
+impl SnapshotInfo {
+    pub fn describe(&self) -> String {
+        let collections: Vec<String> = self.collections.iter()
+            .map(|c| format!("\"{}\" ({} items, {})", c.name, c.vectors, c.settings.embedding_model))
+            .collect();
+        format!("collections {}, {} text items, {}", collections.join(", "), self.documents, self.text_index.describe())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct VectorLine {
+    id: u32,
+    vector: Vec<f32>,
+    data: serde_json::Value,
+}
+
+fn vectors_file(collection: &str) -> String {
+    format!("collections/{}/vectors.jsonl", collection)
+}
+
+fn manifest_file(collection: &str) -> String {
+    format!("collections/{}/manifest.json", collection)
+}
+
+/// Writes the collections and the text database of the knowledge base
+/// to a snapshot.
+pub fn export_snapshot(db: &Database, path: &Path) -> anyhow::Result<SnapshotInfo> {
+    let names = list_collections()?;
+    if names.is_empty() {
+        anyhow::bail!("There are no collections with settings to export.");
+    }
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated).large_file(true);
+
+    let mut collections = vec![];
+    for name in names {
+        let settings = load_settings(&name)?.expect("listed collections have settings");
+        let collection = db.get_collection(&name)
+            .map_err(|e| anyhow::anyhow!("Cannot read collection \"{}\": {}", name, e))?;
+        let manifest = match load_manifest(&name)? {
+            Some(manifest) => manifest,
+            None => Manifest::from_collection(&collection)?,
+        };
+        let mut records: Vec<(VectorID, Record)> = collection.list()?.into_iter().collect();
+        records.sort_by_key(|(id, _)| id.0);
+
+        zip.start_file(vectors_file(&name), options)?;
+        for (id, record) in &records {
+            let line = VectorLine { id: id.0, vector: record.vector.0.clone(), data: md_to_json(&record.data) };
+            writeln!(zip, "{}", serde_json::to_string(&line)?)?;
+        }
+        zip.start_file(manifest_file(&name), options)?;
+        serde_json::to_writer(&mut zip, &manifest)?;
+        collections.push(CollectionInfo { name, settings, vectors: records.len() });
+    }
+
+    let (index, schema) = get_index_schema()?;
+    let text_index = text_index_settings()?;
+    let mut documents = 0u64;
+    zip.start_file(TEXT_FILE, options)?;
+    for document in iter_documents(&index)? {
+        let document = document?;
+        let mut fields = serde_json::Map::new();
+        fields.insert("title".to_string(), doc_text(&schema, &document, "title").into());
+        fields.insert("body".to_string(), doc_text(&schema, &document, "body").into());
+        fields.insert("page_number".to_string(), doc_u64(&schema, &document, "page_number").into());
+        fields.insert("chunk_number".to_string(), doc_u64(&schema, &document, "chunk_number").into());
+        fields.insert("section".to_string(), doc_text(&schema, &document, "section").into());
+        fields.insert("date".to_string(), doc_text(&schema, &document, "date").into());
+        fields.insert("tags".to_string(), serde_json::to_value(doc_tags(&schema, &document))?);
+        writeln!(zip, "{}", serde_json::to_string(&fields)?)?;
+        documents += 1;
+    }
+
+    let info = SnapshotInfo {
+        format: SNAPSHOT_FORMAT,
+        created: chrono::Local::now().format("%Y%m%dT%H%M").to_string(),
+        collections,
+        text_index,
+        documents,
+    };
+    zip.start_file(SNAPSHOT_FILE, options)?;
+    serde_json::to_writer_pretty(&mut zip, &info)?;
+    zip.finish()?.flush()?;
+    Ok(info)
+}
+
+fn read_info(archive: &mut ZipArchive<File>) -> anyhow::Result<SnapshotInfo> {
+    let mut contents = String::new();
+    archive.by_name(SNAPSHOT_FILE)
+        .map_err(|_| anyhow::anyhow!("Not a Minerva snapshot, there is no {}.", SNAPSHOT_FILE))?
+        .read_to_string(&mut contents)?;
+    let info: SnapshotInfo = serde_json::from_str(&contents)?;
+    if info.format > SNAPSHOT_FORMAT {
+        anyhow::bail!("The snapshot has format {}, this version of Minerva reads up to {}.", info.format, SNAPSHOT_FORMAT);
+    }
+    Ok(info)
+}
+
+// The embedding model of a collection must be known here, be the one
+// asked for (if any), and be the one of the collection it replaces.
+fn check_collection(info: &CollectionInfo, embedding_model: Option<&str>, db: &Database) -> anyhow::Result<()> {
+    let model = parse_embedding_model(&info.settings.embedding_model)?;
+    if let Some(wanted) = embedding_model {
+        if parse_embedding_model(wanted)? != model {
+            anyhow::bail!("Collection \"{}\" in the snapshot was made with {}, not with {}.",
+                          info.name, info.settings.embedding_model, wanted);
+        }
+    }
+    if let Some(settings) = load_settings(&info.name)? {
+        settings.check_model(&info.name, &info.settings.embedding_model, info.settings.dim)?;
+    }
+    if db.get_collection(&info.name).is_ok_and(|c| !c.is_empty()) {
+        anyhow::bail!("Collection \"{}\" is not empty, import into a new knowledge base (--kb).", info.name);
+    }
+    Ok(())
+}
+
+// Reads a collection of the snapshot into memory, with its manifest
+// renumbered to the new IDs of the records.
+fn read_collection(archive: &mut ZipArchive<File>, info: &CollectionInfo) -> anyhow::Result<(Collection, Manifest)> {
+    let mut ids = vec![];
+    let mut records = vec![];
+    for line in BufReader::new(archive.by_name(&vectors_file(&info.name))?).lines() {
+        let line: VectorLine = serde_json::from_str(&line?)?;
+        if line.vector.len() != info.settings.dim {
+            anyhow::bail!("Record {} of \"{}\" has dim {}, not {}.", line.id, info.name, line.vector.len(), info.settings.dim);
+        }
+        ids.push(line.id);
+        records.push(Record::new(&Vector(line.vector), &json_to_md(&line.data)?));
+    }
+    let mut manifest: Manifest = serde_json::from_reader(archive.by_name(&manifest_file(&info.name))?)?;
+
+    let config = Config { distance: parse_distance(&info.settings.distance)?, ..Config::default() };
+    let mut collection = Collection::new(&config);
+    let new_ids = if records.is_empty() { vec![] } else { collection.insert_many(&records)? };
+    let renumber: HashMap<u32, u32> = ids.into_iter().zip(new_ids.iter().map(|id| id.0)).collect();
+    for entry in manifest.files.values_mut() {
+        entry.ids = entry.ids.iter().filter_map(|id| renumber.get(id).copied()).collect();
+    }
+    Ok((collection, manifest))
+}
+
+// A line of text.jsonl as a chunk, with the date and tags of its file.
+fn text_line(line: &str) -> anyhow::Result<(Chunk, FileEntry)> {
+    let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line)?;
+    let text = |name| fields.get(name).and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let number = |name| fields.get(name).and_then(|v| v.as_u64()).unwrap_or(0);
+    let chunk = Chunk {
+        filename: text("title"),
+        number: number("chunk_number"),
+        page: Some(number("page_number")).filter(|page| *page > 0),
+        section: Some(text("section")).filter(|section| !section.is_empty()),
+        text: text("body"),
+    };
+    let tags = match fields.get("tags") {
+        Some(tags) => serde_json::from_value(tags.clone())?,
+        None => Default::default(),
+    };
+    Ok((chunk, FileEntry { date: text("date"), tags, ..Default::default() }))
+}
+
+// Reads the text of the snapshot, a line at a time, and hands each chunk
+// to insert.
+fn read_text(archive: &mut ZipArchive<File>, insert: &mut dyn FnMut(Chunk, FileEntry) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    for (number, line) in BufReader::new(archive.by_name(TEXT_FILE)?).lines().enumerate() {
+        let (chunk, file) = text_line(&line?).map_err(|e| anyhow::anyhow!("Line {} of {}: {}", number + 1, TEXT_FILE, e))?;
+        insert(chunk, file)?;
+    }
+    Ok(())
+}
+
+// Replaces the (empty) text index with one with the settings of the
+// snapshot, the language of each chunk is detected again.
+fn import_text(archive: &mut ZipArchive<File>, settings: &TextIndexSettings) -> anyhow::Result<()> {
+    let mut writer = BulkWriter::new(create_text_index(settings)?)?;
+    delete_text_manifest()?;
+    read_text(archive, &mut |chunk, file| {
+        writer.insert_chunks(&[chunk], &file)?;
+        Ok(())
+    })?;
+    writer.commit()?;
+    Ok(())
+}
+
+/// Restores a snapshot into the knowledge base, whose collections and
+/// text database must be empty. Refuses a snapshot made with another
+/// embedding model than the one asked for. Nothing is written unless
+/// the whole snapshot can be read.
+pub fn import_snapshot(db: &mut Database, path: &Path, embedding_model: Option<&str>) -> anyhow::Result<SnapshotInfo> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let info = read_info(&mut archive)?;
+    for collection in &info.collections {
+        check_collection(collection, embedding_model, db)?;
+    }
+    let text_settings = {
+        let (index, _schema) = get_index_schema()?;
+        if get_num_documents(&index)? > 0 {
+            anyhow::bail!("The text database is not empty, import into a new knowledge base (--kb).");
+        }
+        text_index_settings()?
+    };
+
+    let mut collections = vec![];
+    for collection in &info.collections {
+        collections.push(read_collection(&mut archive, collection)?);
+    }
+    read_text(&mut archive, &mut |_, _| Ok(()))?;
+
+    let mut previous = vec![];
+    let written = (|| {
+        for (collection_info, (collection, manifest)) in info.collections.iter().zip(&collections) {
+            previous.push((&collection_info.name, load_settings(&collection_info.name)?));
+            db.save_collection(&collection_info.name, collection)?;
+            save_settings(&collection_info.name, &collection_info.settings)?;
+            save_manifest(&collection_info.name, manifest)?;
+        }
+        import_text(&mut archive, &info.text_index)
+    })();
+    if let Err(e) = written {
+        // Back to the empty knowledge base the import started with.
+        for (name, settings) in previous {
+            let _ = db.delete_collection(name);
+            let _ = delete_manifest(name);
+            let _ = match settings {
+                Some(settings) => save_settings(name, &settings),
+                None => delete_settings(name),
+            };
+        }
+        let _ = create_text_index(&text_settings);
+        return Err(e);
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::data_to_record;
+
+    #[test]
+    fn vector_lines_keep_metadata() {
+        let record = data_to_record(&vec![0.1, -2.5], "texts/a.pdf", "Katten är svart.", 3, Some(2), Some("# Katter"));
+        let line = VectorLine { id: 7, vector: record.vector.0.clone(), data: md_to_json(&record.data) };
+        let read: VectorLine = serde_json::from_str(&serde_json::to_string(&line).unwrap()).unwrap();
+        assert_eq!(read.vector, record.vector.0);
+        assert_eq!(json_to_md(&read.data).unwrap(), record.data);
+    }
+
+    #[test]
+    fn text_lines_keep_sections() {
+        let (chunk, file) = text_line(r##"{"title":"a.md","body":"Katten sover.","page_number":0,"chunk_number":2,"section":"# Katter","date":"20240512T1003","tags":{"team":"lab"}}"##).unwrap();
+        assert_eq!((chunk.number, chunk.page, chunk.section.as_deref()), (2, None, Some("# Katter")));
+        assert_eq!((file.date.as_str(), file.tags.get("team").map(|t| t.as_str())), ("20240512T1003", Some("lab")));
+        let (chunk, _) = text_line(r#"{"title":"b.pdf","body":"Sirius.","page_number":3,"chunk_number":0}"#).unwrap();
+        assert_eq!((chunk.page, chunk.section), (Some(3), None));
+        assert!(text_line(r#"{"title":"c.txt","tags":["team"]}"#).is_err());
+    }
+
+    #[test]
+    fn describe_snapshot() {
+        let info = SnapshotInfo {
+            format: SNAPSHOT_FORMAT,
+            created: "20241018T1200".to_string(),
+            collections: vec![CollectionInfo {
+                name: "vectors".to_string(),
+                settings: CollectionSettings::new("AllMiniLML6V2", 384, "cosine"),
+                vectors: 2,
+            }],
+            text_index: TextIndexSettings { schema_version: 2, languages: vec!["sv".to_string()] },
+            documents: 3,
+        };
+        assert_eq!(info.describe(), "collections \"vectors\" (2 items, AllMiniLML6V2), 3 text items, schema version 2, languages sv");
+    }
+}
@@ -1,24 +1,287 @@
-use tantivy::collector::{TopDocs, Count};
-use tantivy::query::{QueryParser, TermQuery, FuzzyTermQuery, AllQuery};
+use tantivy::collector::{TopDocs, Count, DocSetCollector};
+use tantivy::query::{QueryParser, TermQuery, AllQuery, PhraseQuery, Query, BooleanQuery, Occur, RangeQuery};
+use tantivy::tokenizer::{Language, LowerCaser, RemoveLongFilter, SimpleTokenizer, Stemmer, StopWordFilter, TextAnalyzer, TokenStream};
 use tantivy::schema::*;
-use tantivy::{doc, Index, IndexWriter, ReloadPolicy};
+use tantivy::{doc, Index, IndexReader, IndexWriter, ReloadPolicy, Searcher, TantivyError};
 use tantivy::directory::MmapDirectory;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Once;
 use tantivy::snippet::{Snippet, SnippetGenerator};
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use std::fs;
-use crate::embedder::{chunk_string};
+use crate::filter::{is_glob, Filter};
+use crate::ingest::Chunk;
+use crate::kb::{tantivy_path, tantivy_settings_path};
+use crate::manifest::{load_text_manifest, FileEntry};
 
-static SCHEMA: Lazy<Schema> = Lazy::new(|| {
+// =====================================================================
+// Schema versions. The analyzers are not stored in the index, so an
+// index has to be opened with the schema it was built with.
+//
+//   1  title and body with the default tokenizer; the indexes from
+//      before the version was stored.
+//   2  as 1, plus a "language" field, and a "body_<language>" field per
+//      language of the index, with stop words and stemming, so "katter"
+//      finds "katt". The body of a chunk also goes into the field of its
+//      language, which is detected if the index has more than one.
+//   3  as 2, plus a "path" field with the filename and the directories
+//      above it, not tokenized, so the chunks of a file or directory
+//      are deleted with one term.
+//   4  as 3, plus "date" (of ingestion, "20240501T1412") and "tag"
+//      ("key=value", one per tag) fields, not tokenized, so the filters
+//      on dates and tags are part of the query.
+//   5  as 4, plus "section" (the heading above the chunk), only stored,
+//      so a snapshot keeps the sections of the chunks.
+//
+// Another version, or other languages, needs a rebuild of the index
+// (rebuild-text), which reads the chunks from the old one.
+// =====================================================================
+
+pub const SCHEMA_VERSION: u32 = 5;
+
+// The languages with both stop words and a stemmer in tantivy.
+const LANGUAGES: &[(&str, Language)] = &[
+    ("da", Language::Danish),
+    ("de", Language::German),
+    ("en", Language::English),
+    ("es", Language::Spanish),
+    ("fi", Language::Finnish),
+    ("fr", Language::French),
+    ("it", Language::Italian),
+    ("nl", Language::Dutch),
+    ("no", Language::Norwegian),
+    ("pt", Language::Portuguese),
+    ("sv", Language::Swedish),
+];
+
+const DEFAULT_LANGUAGES: &[&str] = &["sv", "en"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextIndexSettings {
+    pub schema_version: u32,
+    /// Language codes, "sv", "en"; the first one is used when the
+    /// language of a chunk cannot be detected.
+    #[serde(default)]
+    pub languages: Vec<String>,
+}
+
+impl TextIndexSettings {
+    fn legacy() -> Self {
+        TextIndexSettings { schema_version: 1, languages: vec![] }
+    }
+
+    fn current(languages: &[String]) -> Self {
+        TextIndexSettings { schema_version: SCHEMA_VERSION, languages: languages.to_vec() }
+    }
+
+    pub fn describe(&self) -> String {
+        if self.languages.is_empty() {
+            format!("schema version {}, no languages", self.schema_version)
+        } else {
+            format!("schema version {}, languages {}", self.schema_version, self.languages.join(","))
+        }
+    }
+}
+
+// The languages asked for (--languages), chosen once before the index
+// is opened.
+static WANTED_LANGUAGES: OnceCell<Vec<String>> = OnceCell::new();
+
+/// Parses "sv,en".
+pub fn parse_languages(languages: &str) -> anyhow::Result<Vec<String>> {
+    let mut codes: Vec<String> = vec![];
+    for code in languages.split(',').map(|c| c.trim().to_lowercase()).filter(|c| !c.is_empty()) {
+        if language(&code).is_none() {
+            let known: Vec<&str> = LANGUAGES.iter().map(|(code, _)| *code).collect();
+            anyhow::bail!("Unknown language \"{}\", choose from {}.", code, known.join(", "));
+        }
+        if !codes.contains(&code) {
+            codes.push(code);
+        }
+    }
+    if codes.is_empty() {
+        anyhow::bail!("No languages in \"{}\".", languages);
+    }
+    Ok(codes)
+}
+
+pub fn set_text_languages(languages: &str) -> anyhow::Result<()> {
+    let codes = parse_languages(languages)?;
+    if WANTED_LANGUAGES.set(codes.clone()).is_err() && WANTED_LANGUAGES.get() != Some(&codes) {
+        anyhow::bail!("The languages of the text index have already been chosen.");
+    }
+    Ok(())
+}
+
+fn language(code: &str) -> Option<Language> {
+    LANGUAGES.iter().find(|(c, _)| *c == code).map(|(_, language)| *language)
+}
+
+fn analyzer_name(code: &str) -> String {
+    format!("minerva_{}", code)
+}
+
+fn stop_words(language: Language) -> StopWordFilter {
+    StopWordFilter::new(language).unwrap_or_else(|| StopWordFilter::remove(vec![]))
+}
+
+fn language_analyzer(language: Language) -> TextAnalyzer {
+    TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser)
+        .filter(stop_words(language))
+        .filter(Stemmer::new(language))
+        .build()
+}
+
+// The tokenizers of the body_<language> fields.
+fn register_analyzers(index: &Index, settings: &TextIndexSettings) {
+    for code in &settings.languages {
+        if let Some(language) = language(code) {
+            index.tokenizers().register(&analyzer_name(code), language_analyzer(language));
+        }
+    }
+}
+
+pub fn build_schema(settings: &TextIndexSettings) -> Schema {
     let mut schema_builder = Schema::builder();
     schema_builder.add_text_field("title", TEXT | STORED);
     schema_builder.add_text_field("body", TEXT | STORED);
     schema_builder.add_u64_field("page_number", STORED);
     schema_builder.add_u64_field("chunk_number", STORED);
     schema_builder.add_text_field("hash_body", STRING | STORED);
+    if settings.schema_version >= 2 {
+        schema_builder.add_text_field("language", STRING | STORED);
+        for code in &settings.languages {
+            let indexing = TextFieldIndexing::default()
+                .set_tokenizer(&analyzer_name(code))
+                .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+            schema_builder.add_text_field(&format!("body_{}", code), TextOptions::default().set_indexing_options(indexing));
+        }
+    }
+    if settings.schema_version >= 3 {
+        schema_builder.add_text_field("path", STRING);
+    }
+    if settings.schema_version >= 4 {
+        schema_builder.add_text_field("date", STRING | STORED);
+        schema_builder.add_text_field("tag", STRING | STORED);
+    }
+    if settings.schema_version >= 5 {
+        schema_builder.add_text_field("section", STORED);
+    }
     schema_builder.build()
+}
+
+// Lower cased words, and the words which are stop words, per language.
+static STOP_WORD_ANALYZERS: Lazy<Vec<(&str, TextAnalyzer)>> = Lazy::new(|| {
+    LANGUAGES.iter()
+        .map(|(code, language)| {
+            (*code, TextAnalyzer::builder(SimpleTokenizer::default()).filter(LowerCaser).filter(stop_words(*language)).build())
+        })
+        .collect()
 });
 
+fn count_tokens(analyzer: &mut TextAnalyzer, text: &str) -> usize {
+    let mut token_stream = analyzer.token_stream(text);
+    let mut num = 0;
+    while token_stream.advance() {
+        num += 1;
+    }
+    num
+}
+
+/// The language of a text, of the given ones: the one with the most
+/// stop words in the text. The first one if none of them has any.
+pub fn detect_language<'a>(text: &str, languages: &'a [String]) -> Option<&'a str> {
+    if languages.len() < 2 {
+        return languages.first().map(|c| c.as_str());
+    }
+    let total = count_tokens(&mut TextAnalyzer::from(SimpleTokenizer::default()), text);
+    let mut best: Option<(&str, usize)> = None;
+    for code in languages {
+        let Some((_, analyzer)) = STOP_WORD_ANALYZERS.iter().find(|(c, _)| c == code) else {
+            continue;
+        };
+        let num_stop_words = total - count_tokens(&mut analyzer.clone(), text);
+        if best.is_none_or(|(_, most)| num_stop_words > most) {
+            best = Some((code, num_stop_words));
+        }
+    }
+    best.map(|(code, _)| code)
+}
+
+// The body_<language> fields of the schema, with their language code.
+fn body_language_fields(schema: &Schema) -> Vec<(String, Field)> {
+    schema.fields()
+        .filter_map(|(field, entry)| entry.name().strip_prefix("body_").map(|code| (code.to_string(), field)))
+        .collect()
+}
+
+// The fields a query searches in.
+fn search_fields(schema: &Schema) -> Vec<Field> {
+    let mut fields = vec![schema.get_field("title").unwrap(), schema.get_field("body").unwrap()];
+    fields.extend(body_language_fields(schema).into_iter().map(|(_, field)| field));
+    fields
+}
+
+// The filename without "." and trailing slashes, as it is compared to
+// the path to delete.
+fn normalize_path(path: &Path) -> String {
+    path.components().filter(|c| *c != Component::CurDir).collect::<PathBuf>().to_string_lossy().to_string()
+}
+
+// The terms of the path field: the filename and the directories above it.
+fn path_terms(filename: &str) -> Vec<String> {
+    let path = PathBuf::from(normalize_path(Path::new(filename)));
+    path.ancestors().map(|p| p.to_string_lossy().to_string()).filter(|p| !p.is_empty()).collect()
+}
+
+fn tag_term(key: &str, value: &str) -> String {
+    format!("{}={}", key, value)
+}
+
+// A chunk as tantivy document, with the date and tags of its file. The
+// language, path, date, tag and section fields are only filled in if the
+// schema has them.
+fn chunk_document(schema: &Schema, chunk: &Chunk, hash_body: &str, file: &FileEntry) -> TantivyDocument {
+    let (title, body) = (chunk.filename.as_str(), chunk.text.as_str());
+    let mut document = doc!(
+        schema.get_field("title").unwrap() => title,
+        schema.get_field("body").unwrap() => body,
+        schema.get_field("page_number").unwrap() => chunk.page.unwrap_or(0),
+        schema.get_field("chunk_number").unwrap() => chunk.number,
+        schema.get_field("hash_body").unwrap() => hash_body
+    );
+    let language_fields = body_language_fields(schema);
+    let codes: Vec<String> = language_fields.iter().map(|(code, _)| code.clone()).collect();
+    if let (Ok(language_field), Some(code)) = (schema.get_field("language"), detect_language(body, &codes)) {
+        document.add_text(language_field, code);
+        if let Some((_, field)) = language_fields.iter().find(|(c, _)| c == code) {
+            document.add_text(*field, body);
+        }
+    }
+    if let Ok(path_field) = schema.get_field("path") {
+        for term in path_terms(title) {
+            document.add_text(path_field, term);
+        }
+    }
+    if let (Ok(date_field), Ok(tag_field)) = (schema.get_field("date"), schema.get_field("tag")) {
+        if !file.date.is_empty() {
+            document.add_text(date_field, &file.date);
+        }
+        for (key, value) in &file.tags {
+            document.add_text(tag_field, tag_term(key, value));
+        }
+    }
+    if let (Ok(section_field), Some(section)) = (schema.get_field("section"), &chunk.section) {
+        document.add_text(section_field, section);
+    }
+    document
+}
+
 pub fn text_from_owned_value(value: &OwnedValue) -> &str {
     match value {
         OwnedValue::Str(s) => s,
@@ -29,6 +292,28 @@ pub fn text_from_owned_value(value: &OwnedValue) -> &str {
     }
 }
 
+/// The text of a field of a document, by name, "" if it has none.
+pub fn doc_text<'a>(schema: &Schema, document: &'a TantivyDocument, name: &str) -> &'a str {
+    schema.get_field(name).ok().and_then(|field| document.get_first(field)).and_then(|v| v.as_str()).unwrap_or("")
+}
+
+/// The tags of a document, none if the schema has no tag field.
+pub fn doc_tags(schema: &Schema, document: &TantivyDocument) -> BTreeMap<String, String> {
+    let Ok(tag_field) = schema.get_field("tag") else {
+        return BTreeMap::new();
+    };
+    document.get_all(tag_field)
+        .filter_map(|v| v.as_str())
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// A number field of a document, by name, 0 if it has none.
+pub fn doc_u64(schema: &Schema, document: &TantivyDocument, name: &str) -> u64 {
+    schema.get_field(name).ok().and_then(|field| document.get_first(field)).and_then(|v| v.as_u64()).unwrap_or(0)
+}
+
 pub fn u64_from_owned_value(value: &OwnedValue) -> &u64 {
     match value {
         OwnedValue::U64(s) => s,
@@ -39,186 +324,281 @@ pub fn u64_from_owned_value(value: &OwnedValue) -> &u64 {
     }
 }
 
-// Insert the four main fields, and calculate the body_hash
-// from the body text.
-#[allow(dead_code)]
-pub fn insert_document(index: &Index, title: &str, body: &str, page_number: u64, chunk_number: u64) -> tantivy::Result<bool> {
-    let schema = index.schema();
-    let mut index_writer: IndexWriter = index.writer(50_000_000)?;
-    
-    let title_field = schema.get_field("title").unwrap();
-    let body_field = schema.get_field("body").unwrap();
-    let page_number_field = schema.get_field("page_number").unwrap();
-    let chunk_number_field = schema.get_field("chunk_number").unwrap();
-    let hash_body_field = schema.get_field("hash_body").unwrap();
-
-    //let hash_body_value = blake3::hash(body.as_bytes());
-    let hash_body_value_bytes = body.as_bytes();
-    //let hash_body_value_bytes = &hash_body_value_bytes[0..=255]; // Might be faster like this?
-    let hash_body_value = blake3::hash(hash_body_value_bytes);
-    
-    //println!("{}", hash_body_value);
-
-    match document_exists(&index, &hash_body_value.to_string()) {
-        Ok(exists) => {
-            if ! exists {
-                //println!("Adding document.");
-                let _ = index_writer.add_document(doc!(
-                    title_field => title,
-                    body_field => body,
-                    page_number_field => page_number,
-                    chunk_number_field => chunk_number,
-                    hash_body_field => hash_body_value.to_string()
-                ));
-                index_writer.commit()?;
-                return Ok(true);
-                //println!("Added.");
-            } else {
-                return Ok(false);
-            }
-        }
-        Err(e) => println!("Error occurred: {:?}", e)
+fn path_exists(path: &Path) -> bool {
+    fs::metadata(path).is_ok()
+}
+
+fn load_index_settings() -> tantivy::Result<Option<TextIndexSettings>> {
+    let path = tantivy_settings_path();
+    if !path.exists() {
+        return Ok(None);
     }
-        
-    Ok(false)
+    let contents = fs::read_to_string(path)?;
+    serde_json::from_str(&contents).map(Some).map_err(|e| TantivyError::SchemaError(e.to_string()))
 }
 
-pub fn insert_document_noc(index: &Index, index_writer: &IndexWriter, title: &str, body: &str, page_number: u64, chunk_number: u64) -> tantivy::Result<bool> {
-    let schema = index.schema();
-    
-    let title_field = schema.get_field("title").unwrap();
-    let body_field = schema.get_field("body").unwrap();
-    let page_number_field = schema.get_field("page_number").unwrap();
-    let chunk_number_field = schema.get_field("chunk_number").unwrap();
-    let hash_body_field = schema.get_field("hash_body").unwrap();
-
-    //let hash_body_value = blake3::hash(body.as_bytes());
-    let hash_body_value_bytes = body.as_bytes();
-    //let hash_body_value_bytes = &hash_body_value_bytes[0..=255]; // Might be faster like this?
-    let hash_body_value = blake3::hash(hash_body_value_bytes);
-    
-    //println!("{}", hash_body_value);
-
-    match document_exists(&index, &hash_body_value.to_string()) {
-        Ok(exists) => {
-            if ! exists {
-                //println!("Adding document.");
-                let _ = index_writer.add_document(doc!(
-                    title_field => title,
-                    body_field => body,
-                    page_number_field => page_number,
-                    chunk_number_field => chunk_number,
-                    hash_body_field => hash_body_value.to_string()
-                ));
-                return Ok(true);
-                //println!("Added.");
-            } else {
-                return Ok(false);
-            }
+fn save_index_settings(settings: &TextIndexSettings) -> tantivy::Result<()> {
+    let contents = serde_json::to_string_pretty(settings).map_err(|e| TantivyError::SchemaError(e.to_string()))?;
+    fs::write(tantivy_settings_path(), contents)?;
+    Ok(())
+}
+
+fn index_exists(index_path: &Path) -> bool {
+    index_path.join("meta.json").exists()
+}
+
+// The settings of the index; a new index gets the languages asked for,
+// or the default ones.
+fn index_settings(index_path: &Path) -> tantivy::Result<TextIndexSettings> {
+    let stored = load_index_settings()?;
+    let wanted = WANTED_LANGUAGES.get();
+    if !index_exists(index_path) {
+        let languages = match (wanted, stored) {
+            (Some(wanted), _) => wanted.clone(),
+            (None, Some(stored)) if !stored.languages.is_empty() => stored.languages,
+            _ => DEFAULT_LANGUAGES.iter().map(|c| c.to_string()).collect(),
+        };
+        return Ok(TextIndexSettings::current(&languages));
+    }
+    let settings = stored.unwrap_or_else(TextIndexSettings::legacy);
+    if let Some(wanted) = wanted {
+        if *wanted != settings.languages || settings.schema_version != SCHEMA_VERSION {
+            return Err(TantivyError::SchemaError(format!(
+                "The text index has {}. Rebuild it with: minerva --languages {} rebuild-text",
+                settings.describe(), wanted.join(",")
+            )));
         }
-        Err(e) => println!("Error occurred: {:?}", e)
+    } else if settings.schema_version != SCHEMA_VERSION {
+        static NOTICE: Once = Once::new();
+        NOTICE.call_once(|| {
+            println!("The text index has {}, not version {}. Rebuild it with: minerva rebuild-text", settings.describe(), SCHEMA_VERSION);
+        });
     }
-        
-    Ok(false)
+    Ok(settings)
 }
 
-// Takes ownership of the doc!
-#[allow(dead_code)]
-pub fn insert_doc(index: &Index, mut tdoc: TantivyDocument) -> tantivy::Result<()> {
-    let mut index_writer: IndexWriter = index.writer(50_000_000)?;
+// Opens, or creates, the index at the path with the schema of the
+// settings, and registers its analyzers.
+fn open_index(index_path: &Path, settings: &TextIndexSettings) -> tantivy::Result<Index> {
+    if ! path_exists(index_path) {
+        fs::create_dir_all(index_path)?;
+    }
+    let directory = MmapDirectory::open(index_path)?;
+    let index = Index::open_or_create(directory, build_schema(settings))?;
+    register_analyzers(&index, settings);
+    Ok(index)
+}
 
-    // Calculate the body_hash.
+pub fn get_index_schema() -> tantivy::Result<(Index, Schema)> {
+    let index_path = &tantivy_path();
+    let settings = index_settings(index_path)?;
+    let created = !index_exists(index_path);
+    let index = open_index(index_path, &settings)?;
+    if created {
+        save_index_settings(&settings)?;
+    }
     let schema = index.schema();
-    let body_field = schema.get_field("body").unwrap();
-    let hash_body_field = schema.get_field("hash_body").unwrap();
-    /*for x in tdoc.get_all(body_field) {
-        println!("{:?}", x);
-    }*/
-    if let Some(x) = tdoc.get_first(body_field) { // We only process the first one.
-        // https://github.com/quickwit-oss/tantivy/pull/2071 for as_str() info.
-        let hash_body_value = x.as_str().unwrap();
-        let hash_body_value_bytes = hash_body_value.as_bytes();
-        let hash_body_value_bytes = &hash_body_value_bytes[0..=255]; // Might be faster like this?
-        let hash_body_value = blake3::hash(hash_body_value_bytes);
-
-        match document_exists(&index, &hash_body_value.to_string()) {
-            Ok(exists) => {
-                if ! exists {
-                    println!("Adding document.");
-                    tdoc.add_text(hash_body_field, hash_body_value);
-                    index_writer.add_document(tdoc)?;
-                    index_writer.commit()?;
-                }
-            }
-            Err(e) => println!("Error occurred: {:?}", e)
-        }
-        
-    } // Else add hash 0 or something? Can this happen?
-    
-    Ok(())
+    Ok((index, schema))
 }
 
-fn path_exists(path: &Path) -> bool {
-    fs::metadata(path).is_ok()
+/// The settings of the text index, as it is (or would be) opened.
+pub fn text_index_settings() -> tantivy::Result<TextIndexSettings> {
+    index_settings(&tantivy_path())
 }
 
-// We need to be able to define the DB path as well...
-// Or have it as an argument here!
-pub fn get_index_schema() -> tantivy::Result<(Index, Schema)> {
-    let index_path = Path::new("db/tantivy");
-    if ! path_exists(index_path) {
-        fs::create_dir_all(index_path)?;
+/// Replaces the text index with a new, empty, one with the given
+/// settings, to import a snapshot into.
+pub fn create_text_index(settings: &TextIndexSettings) -> tantivy::Result<Index> {
+    if settings.schema_version == 0 || settings.schema_version > SCHEMA_VERSION || settings.languages.iter().any(|c| language(c).is_none()) {
+        return Err(TantivyError::SchemaError(format!("Cannot create a text index with {}.", settings.describe())));
+    }
+    let index_path = tantivy_path();
+    if index_path.exists() {
+        fs::remove_dir_all(&index_path)?;
     }
-    let schema = &*SCHEMA;
-    let directory = MmapDirectory::open(Path::new(index_path))?;
-    let index = Index::open_or_create(directory, schema.clone())?;
-    Ok((index, schema.clone()))
+    let index = open_index(&index_path, settings)?;
+    save_index_settings(settings)?;
+    Ok(index)
 }
 
-// index should be a parameter, because we want to know where
-// we store it the document.
-pub fn insert_file<P: AsRef<Path>>(index: &Index, path: P, chunk_size: usize) -> tantivy::Result<u64> {
-    let path_ref = path.as_ref();
-    let filename_str = path_ref.to_str().ok_or_else(|| {
-        tantivy::TantivyError::InvalidArgument(format!("Invalid path: {:?}", path_ref))
-    })?;
-    let contents = fs::read_to_string(path_ref).expect("Cannot read file.");
+/// Rebuilds the text index with the current schema, and the languages
+/// asked for (or those it had), from the chunks stored in it. Returns
+/// the settings of the new index and the number of chunks.
+pub fn rebuild_text_index() -> tantivy::Result<(TextIndexSettings, u64)> {
+    let index_path = tantivy_path();
+    let old_settings = match load_index_settings()? {
+        Some(settings) if index_exists(&index_path) => settings,
+        _ => TextIndexSettings::legacy(),
+    };
+    let languages = match WANTED_LANGUAGES.get() {
+        Some(wanted) => wanted.clone(),
+        None if !old_settings.languages.is_empty() => old_settings.languages.clone(),
+        None => DEFAULT_LANGUAGES.iter().map(|c| c.to_string()).collect(),
+    };
+    let new_settings = TextIndexSettings::current(&languages);
+    // Before version 4 the dates and tags were only in the manifest.
+    let files = load_text_manifest().map_err(|e| TantivyError::SystemError(e.to_string()))?.unwrap_or_default();
 
-    let chunks = chunk_string(&contents, chunk_size);
-    let mut chunk_counter = 0u64;
-    let mut index_writer: IndexWriter = index.writer(50_000_000)?;
-    for chunk in chunks {
-        let inserted = insert_document_noc(&index, &index_writer, filename_str, &chunk, 0, chunk_counter).unwrap();
-        //println!("Inserted chunk {}", chunk_counter);
-        if inserted {
-            chunk_counter += 1;
+    let new_path = index_path.with_file_name("tantivy.new");
+    if new_path.exists() {
+        fs::remove_dir_all(&new_path)?; // From a rebuild which did not finish.
+    }
+    let mut num = 0u64;
+    {
+        let old_index = open_index(&index_path, &old_settings)?;
+        let new_index = open_index(&new_path, &new_settings)?;
+        let old_schema = old_index.schema();
+        let new_schema = new_index.schema();
+        let field = |name| old_schema.get_field(name);
+        let (title, body, page_number, chunk_number, hash_body) =
+            (field("title")?, field("body")?, field("page_number")?, field("chunk_number")?, field("hash_body")?);
+
+        let searcher = old_index.reader()?.searcher();
+        let mut index_writer: IndexWriter = new_index.writer(50_000_000)?;
+        for doc_address in searcher.search(&AllQuery, &DocSetCollector)? {
+            let d: TantivyDocument = searcher.doc(doc_address)?;
+            let text = |f| d.get_first(f).and_then(|v| v.as_str()).unwrap_or("");
+            let number = |f| d.get_first(f).and_then(|v| v.as_u64()).unwrap_or(0);
+            let hash = match text(hash_body) {
+                "" => blake3::hash(text(body).as_bytes()).to_string(),
+                h => h.to_string(),
+            };
+            let file = match old_schema.get_field("date") {
+                Ok(date) => FileEntry { date: text(date).to_string(), tags: doc_tags(&old_schema, &d), ..Default::default() },
+                Err(_) => files.files.get(text(title)).cloned().unwrap_or_default(),
+            };
+            // Before version 5 the sections were only in the vector records.
+            let chunk = Chunk {
+                filename: text(title).to_string(),
+                number: number(chunk_number),
+                page: Some(number(page_number)).filter(|page| *page > 0),
+                section: Some(doc_text(&old_schema, &d, "section")).filter(|s| !s.is_empty()).map(|s| s.to_string()),
+                text: text(body).to_string(),
+            };
+            index_writer.add_document(chunk_document(&new_schema, &chunk, &hash, &file))?;
+            num += 1;
         }
+        index_writer.commit()?;
+        index_writer.wait_merging_threads()?;
     }
-    index_writer.commit()?;
-    
-    Ok(chunk_counter)
+
+    // Swap the indexes, the old one is only removed when the new one
+    // is in place. The settings are those of the new index before it is
+    // moved in, so the index is never opened with the old schema.
+    let old_path = index_path.with_file_name("tantivy.old");
+    if old_path.exists() {
+        fs::remove_dir_all(&old_path)?; // From a rebuild which did not finish.
+    }
+    fs::rename(&index_path, &old_path)?;
+    save_index_settings(&new_settings)?;
+    fs::rename(&new_path, &index_path)?;
+    fs::remove_dir_all(&old_path)?;
+    Ok((new_settings, num))
 }
 
-// Needs testing/work.
-// Probably not on an Index but on an IndexWriter so we check before
-// inserting in a loop?
-// unique_id needs to be a hash on the title+body, or something.
-// Adapted from https://github.com/quickwit-oss/tantivy/blob/main/examples/deleting_updating_documents.rs
-pub fn document_exists(index: &Index, hash_body: &str) -> tantivy::Result<bool> {
-    //let reader: IndexReader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
-    let reader = index.reader()?;
-    let schema = index.schema();
+// =====================================================================
+// Bulk ingestion. BulkWriter keeps one reader, reloaded after every
+// commit, and the files and hashes of the chunks added since, so
+// duplicates within a batch are skipped too. Duplicates are per file: a
+// text which is in several files (a licence, a header) is indexed for
+// each of them, so it stays when one of them is removed. The chunks of
+// a file which was deleted since the commit are not duplicates, a
+// delete only removes the documents added before it.
+// =====================================================================
 
-    let hash_body_field = schema.get_field("hash_body")?;
-    let hash_body_term = Term::from_field_text(hash_body_field, hash_body);
-    let term_query = TermQuery::new(hash_body_term, IndexRecordOption::Basic);
-    
-    let searcher = reader.searcher();
-    let top_docs = searcher.search(&term_query, &TopDocs::with_limit(1))?;
+pub struct BulkWriter {
+    index: Index,
+    index_writer: IndexWriter,
+    reader: IndexReader,
+    hash_body_field: Field,
+    path_field: Option<Field>,
+    pending: HashSet<(String, String)>,
+    deleted: HashSet<String>,
+}
 
-   // println!("{:?}", top_docs);
-    
-    Ok(!top_docs.is_empty())
+impl BulkWriter {
+    pub fn new(index: Index) -> tantivy::Result<Self> {
+        let index_writer = index.writer(50_000_000)?;
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
+        let schema = index.schema();
+        let hash_body_field = schema.get_field("hash_body")?;
+        let path_field = schema.get_field("path").ok();
+        Ok(BulkWriter { index, index_writer, reader, hash_body_field, path_field, pending: HashSet::new(), deleted: HashSet::new() })
+    }
+
+    // Deleted documents stay in the term dictionary until a merge, so
+    // they are counted with a query, not with doc_freq(). Without a path
+    // field (before version 3) the titles of the chunks with the hash are
+    // compared.
+    fn contains(&self, path: &str, hash_body: &str) -> tantivy::Result<bool> {
+        if self.pending.contains(&(path.to_string(), hash_body.to_string())) {
+            return Ok(true);
+        }
+        if path_terms(path).iter().any(|p| self.deleted.contains(p)) {
+            return Ok(false);
+        }
+        let searcher = self.reader.searcher();
+        let hash_query: Box<dyn Query> = Box::new(TermQuery::new(Term::from_field_text(self.hash_body_field, hash_body), IndexRecordOption::Basic));
+        match self.path_field {
+            Some(path_field) => {
+                let path_query: Box<dyn Query> = Box::new(TermQuery::new(Term::from_field_text(path_field, path), IndexRecordOption::Basic));
+                Ok(searcher.search(&BooleanQuery::new(vec![(Occur::Must, hash_query), (Occur::Must, path_query)]), &Count)? > 0)
+            }
+            None => {
+                let schema = self.index.schema();
+                for doc_address in searcher.search(&hash_query, &DocSetCollector)? {
+                    let document: TantivyDocument = searcher.doc(doc_address)?;
+                    if normalize_path(Path::new(doc_text(&schema, &document, "title"))) == path {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// Adds the chunks of a file, chunks which are already in the index
+    /// for the same file are skipped. The chunk numbers are the same as in the vector
+    /// database, the page number is 0 if the file has no pages. The
+    /// chunks get the date and tags of the file entry.
+    pub fn insert_chunks(&mut self, chunks: &[Chunk], file: &FileEntry) -> tantivy::Result<u64> {
+        let schema = self.index.schema();
+        let mut num = 0u64;
+        for chunk in chunks {
+            let path = normalize_path(Path::new(&chunk.filename));
+            let hash_body = blake3::hash(chunk.text.as_bytes()).to_string();
+            if self.contains(&path, &hash_body)? {
+                continue;
+            }
+            self.index_writer.add_document(chunk_document(&schema, chunk, &hash_body, file))?;
+            self.pending.insert((path, hash_body));
+            num += 1;
+        }
+        Ok(num)
+    }
+
+    /// Deletes the chunks of a file, or of all the files below a
+    /// directory, returns their number. Takes effect on the next commit.
+    pub fn delete_path(&mut self, path: &Path) -> tantivy::Result<u64> {
+        self.deleted.insert(normalize_path(path));
+        let schema = self.index.schema();
+        let searcher = self.reader.searcher();
+        let Ok(path_field) = schema.get_field("path") else {
+            return delete_documents_by_title(&schema, &searcher, &self.index_writer, path);
+        };
+        let term = Term::from_field_text(path_field, &normalize_path(path));
+        let num = searcher.search(&TermQuery::new(term.clone(), IndexRecordOption::Basic), &Count)? as u64;
+        self.index_writer.delete_term(term);
+        Ok(num)
+    }
+
+    pub fn commit(&mut self) -> tantivy::Result<()> {
+        self.index_writer.commit()?;
+        self.reader.reload()?;
+        self.pending.clear();
+        self.deleted.clear();
+        Ok(())
+    }
 }
 
 pub fn get_num_documents(index: &Index) -> tantivy::Result<u64> {
@@ -228,206 +608,259 @@ pub fn get_num_documents(index: &Index) -> tantivy::Result<u64> {
     Ok(searcher.segment_readers().iter().map(|segment_reader| segment_reader.num_docs() as u64).sum())
 }
 
-pub fn _tanttest() -> tantivy::Result<()> {
-    let index_path = Path::new("db/tantivy");
-    println!("Index path: {:?}", index_path);
-    
-    let schema = &*SCHEMA;
-    let directory = MmapDirectory::open(Path::new(index_path))?;
-    let index = Index::open_or_create(directory, schema.clone())?;
+/// Options of a keyword search.
+#[derive(Debug, Clone)]
+pub struct KeywordOptions {
+    pub limit: usize,
+    /// Edit distance (at most 2) for fuzzy matching of the terms, 0
+    /// only matches the terms themselves.
+    pub fuzzy: u8,
+    /// All terms must match, instead of any of them.
+    pub all_terms: bool,
+    pub title_boost: f32,
+    /// Also for the body_<language> fields.
+    pub body_boost: f32,
+}
 
-    let num_docs = get_num_documents(&index)?;
-    println!("Number of documents in the index: {}", num_docs);
-    
-    insert_document(&index, "Another title with Mice", "Example body text \n\
-Longer string.", 1, 1)?;
+impl Default for KeywordOptions {
+    fn default() -> Self {
+        KeywordOptions { limit: 10, fuzzy: 0, all_terms: false, title_boost: 1.0, body_boost: 1.0 }
+    }
+}
 
-    let title = schema.get_field("title").unwrap();
-    let body = schema.get_field("body").unwrap();
-    let page_number = schema.get_field("page_number").unwrap();
-    let chunk_number = schema.get_field("chunk_number").unwrap();
-    /*
-    println!("title: {:?}", title);
-    println!("body: {:?}", body);
-    println!("page_number: {:?}", page_number);
-    println!("chunk_number: {:?}", chunk_number);
-     */
-    
-    let mut old_man_doc = TantivyDocument::default();
-    old_man_doc.add_text(title, "The Old Man and the Sea");
-    old_man_doc.add_text(
-        body,
-        "He was an old man who fished alone in a skiff in the Gulf Stream and he had gone \
-         eighty-four days now without taking a fish.",
-    );
-    old_man_doc.add_u64(page_number, 28);
-    old_man_doc.add_u64(chunk_number, 8);
-    insert_doc(&index, old_man_doc).unwrap();
-
-    insert_document(&index, "Frankenstein",
-        "You will rejoice to hear that no disaster has accompanied the commencement of an \
-             enterprise which you have regarded with such evil forebodings.  I arrived here \
-             yesterday, and my first task is to assure my dear sister of my welfare and \
-             increasing confidence in the success of my undertaking.",
-        42, 128).unwrap();
-    
-    /*
-    let mut index_writer: IndexWriter = index.writer(50_000_000)?; // 50 MB buffer for indexing.
-    index_writer.add_document(doc!(
-        title => "Of Mice and Men",
-        body => "A few miles south of Soledad, the Salinas River drops in close to the hillside \
-            bank and runs deep and green. The water is warm too, for it has slipped twinkling \
-            over the yellow sands in the sunlight before reaching the narrow pool. On one \
-            side of the river the golden foothill slopes curve up to the strong and rocky \
-            Gabilan Mountains, but on the valley side the water is lined with trees—willows \
-            fresh and green with every spring, carrying in their lower leaf junctures the \
-            debris of the winter’s flooding; and sycamores with mottled, white, recumbent \
-            limbs and branches that arch over the pool",
-        page_number => 42u64
-    ))?;
-    */
-    /*
-    index_writer.add_document(doc!(
-        title => "Frankenstein",
-        title => "The Modern Prometheus",
-        body => "You will rejoice to hear that no disaster has accompanied the commencement of an \
-             enterprise which you have regarded with such evil forebodings.  I arrived here \
-             yesterday, and my first task is to assure my dear sister of my welfare and \
-             increasing confidence in the success of my undertaking.",
-        body => "Another body?",
-        page_number => 42u64,
-        chunk_number => 128u64
-    ))?;*/
-    
-    //index_writer.commit()?; // Finish processing the queue.
+/// Searches with the query syntax of tantivy: "phrases", AND, OR, NOT,
+/// +required and -excluded terms, and field:term. Syntax errors are
+/// returned. The snippets highlight the matching words, also the words
+/// which only match after stemming.
+pub fn search_documents(query_str: &str, opts: &KeywordOptions) -> tantivy::Result<Vec<(f32, TantivyDocument, Option<Snippet>)>> {
+    if opts.fuzzy > 2 {
+        return Err(TantivyError::InvalidArgument(format!("Fuzzy distance {} is larger than 2.", opts.fuzzy)));
+    }
+    let (index, schema) = get_index_schema()?;
 
-    /*
-    drop(index_writer);
-    insert_doc(&index, old_man_doc).unwrap();
-    */
-    
-    let _reader = index
-        .reader_builder()
-        .reload_policy(ReloadPolicy::OnCommitWithDelay)
-        .try_into()?;
+    let title = schema.get_field("title")?;
+    let body = schema.get_field("body")?;
+    let language_fields = body_language_fields(&schema);
 
-    let reader = index.reader()?;
+    let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
     let searcher = reader.searcher();
 
-    let query_parser = QueryParser::for_index(&index, vec![title, body]);
-
-    let query = query_parser.parse_query("sea whale")?;
-    //let query = query_parser.parse_query("prometheus")?;
+    let mut query_parser = QueryParser::for_index(&index, search_fields(&schema));
+    if opts.all_terms {
+        query_parser.set_conjunction_by_default();
+    }
+    query_parser.set_field_boost(title, opts.title_boost);
+    query_parser.set_field_boost(body, opts.body_boost);
+    for (_, field) in &language_fields {
+        query_parser.set_field_boost(*field, opts.body_boost);
+    }
+    if opts.fuzzy > 0 {
+        for field in search_fields(&schema) {
+            query_parser.set_field_fuzzy(field, false, opts.fuzzy, true);
+        }
+    }
+    let query = query_parser.parse_query(query_str)?;
 
-    let top_docs = searcher.search(&query, &TopDocs::with_limit(10))?;
+    let top_docs = searcher.search(&query, &TopDocs::with_limit(opts.limit.max(1)))?;
 
     let snippet_generator = SnippetGenerator::create(&searcher, &*query, body)?;
-
-    for (score, doc_address) in top_docs {
-        let retrieved_doc: TantivyDocument = searcher.doc(doc_address)?;
-        println!("retrieved {score}: {}", retrieved_doc.to_json(&schema));
-        
-        let snippet = snippet_generator.snippet_from_doc(&retrieved_doc);
-        println!("snippet: {:?}", snippet); //.to_html());
+    let mut language_generators = vec![];
+    for (code, field) in &language_fields {
+        language_generators.push((code.clone(), SnippetGenerator::create(&searcher, &*query, *field)?));
     }
-    let query = query_parser.parse_query("title:sea^20 body:whale^70")?;
-
-    let (_score, _doc_address) = searcher
-        .search(&query, &TopDocs::with_limit(1))?
-        .into_iter()
-        .next()
-        .unwrap();
-    //println!("{_score} {doc_address:?}"); ?
 
-    /*
-    let explanation = query.explain(&searcher, doc_address)?;
-    println!("explanation: {}", explanation.to_pretty_json());
-    */
-
-    let query = query_parser.parse_query("title:Mice")?;
-    let top_docs = searcher.search(&query, &TopDocs::with_limit(10))?;
+    let mut documents = Vec::new();
     for (score, doc_address) in top_docs {
         let retrieved_doc: TantivyDocument = searcher.doc(doc_address)?;
-        println!("retrieved {score}: {}", retrieved_doc.to_json(&schema));
+        let mut snippet = snippet_generator.snippet_from_doc(&retrieved_doc);
+        // The stemmed words are only in the field of the language.
+        if snippet.highlighted().is_empty() {
+            let code = doc_text(&schema, &retrieved_doc, "language");
+            if let Some((_, generator)) = language_generators.iter().find(|(c, _)| c == code) {
+                let stemmed = generator.snippet(doc_text(&schema, &retrieved_doc, "body"));
+                if !stemmed.highlighted().is_empty() {
+                    snippet = stemmed;
+                }
+            }
+        }
+        documents.push((score, retrieved_doc, Some(snippet)));
     }
 
-    let num_docs = get_num_documents(&index)?;
-    println!("Number of documents in the index: {}", num_docs);
-
-    Ok(())
+    Ok(documents)
 }
 
-pub fn search_documents(query_str: &str) -> tantivy::Result<Vec<(f32, TantivyDocument, Option<Snippet>)>> {
-    let index_path = Path::new("db/tantivy");
-    
-    let schema = &*SCHEMA;  // Ensure SCHEMA is defined and available in scope
-    let directory = MmapDirectory::open(index_path)?;
-    let index = Index::open_or_create(directory, schema.clone())?;
+// Like search_documents(), but does not fail on query syntax, so
+// a plain question can be used as query (e.g. "What's Peter's cat?").
+pub fn search_documents_lenient(query_str: &str, limit: usize) -> tantivy::Result<Vec<(f32, TantivyDocument, Option<Snippet>)>> {
+    let (index, schema) = get_index_schema()?;
     
-    let title = schema.get_field("title").unwrap();
     let body = schema.get_field("body").unwrap();
     
     let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
     let searcher = reader.searcher();
     
-    let query_parser = QueryParser::for_index(&index, vec![title, body]);
-    let query = query_parser.parse_query(query_str)?;
+    let query_parser = QueryParser::for_index(&index, search_fields(&schema));
+    let (query, _errors) = query_parser.parse_query_lenient(query_str);
     
-    let top_docs = searcher.search(&query, &TopDocs::with_limit(10))?;
+    let top_docs = searcher.search(&query, &TopDocs::with_limit(limit))?;
 
     let snippet_generator = SnippetGenerator::create(&searcher, &*query, body)?;
     
     let mut documents = Vec::new();
     for (score, doc_address) in top_docs {
         let retrieved_doc: TantivyDocument = searcher.doc(doc_address)?;
-        //println!("doc: {:?}", retrieved_doc);
         let snippet = snippet_generator.snippet_from_doc(&retrieved_doc);
-        //println!("snippet: {:?}", snippet.to_html());
-        //println!("custom highlighting: {}", highlight(&snippet));
         documents.push((score, retrieved_doc, Some(snippet)));
     }
     
     Ok(documents)
 }
 
-pub fn get_all() -> tantivy::Result<Vec<(f32, TantivyDocument, Option<Snippet>)>> {
-    let index_path = Path::new("db/tantivy");
-    
-    let schema = &*SCHEMA;  // Ensure SCHEMA is defined and available in scope
-    let directory = MmapDirectory::open(index_path)?;
-    let index = Index::open_or_create(directory, schema.clone())?;
-    
-    let body = schema.get_field("body").unwrap();
-    
+// The parts of the filter the index can check itself: the paths (unless
+// some are globs, or the index has no path field), the dates and the
+// tags. Returns the query, and whether the titles of the hits still have
+// to be checked against the paths.
+fn filter_query(schema: &Schema, query: Box<dyn Query>, filter: &Filter) -> tantivy::Result<(BooleanQuery, bool)> {
+    let term_query = |field, text: &str| -> Box<dyn Query> {
+        Box::new(TermQuery::new(Term::from_field_text(field, text), IndexRecordOption::Basic))
+    };
+    let mut clauses = vec![(Occur::Must, query)];
+    let mut check_paths = false;
+    if !filter.paths.is_empty() {
+        match schema.get_field("path") {
+            Ok(path_field) if !filter.paths.iter().any(|p| is_glob(p)) => {
+                let paths = filter.paths.iter().map(|p| (Occur::Should, term_query(path_field, &normalize_path(Path::new(p))))).collect();
+                clauses.push((Occur::Must, Box::new(BooleanQuery::new(paths))));
+            }
+            _ => check_paths = true,
+        }
+    }
+    if filter.since.is_some() || filter.until.is_some() || !filter.tags.is_empty() {
+        let (Ok(_), Ok(tag_field)) = (schema.get_field("date"), schema.get_field("tag")) else {
+            return Err(TantivyError::SchemaError(
+                "The text index has no dates or tags to filter on. Rebuild it with: minerva rebuild-text".to_string()
+            ));
+        };
+        let (since, until) = filter.date_bounds();
+        if since != Bound::Unbounded || until != Bound::Unbounded {
+            let range = RangeQuery::new_str_bounds("date".to_string(), since.as_ref().map(|d| d.as_str()), until.as_ref().map(|d| d.as_str()));
+            clauses.push((Occur::Must, Box::new(range)));
+        }
+        for (key, value) in &filter.tags {
+            clauses.push((Occur::Must, term_query(tag_field, &tag_term(key, value))));
+        }
+    }
+    Ok((BooleanQuery::new(clauses), check_paths))
+}
+
+/// Like search_documents_lenient(), but only returns documents which
+/// pass the filter. Path globs are checked on the hits, more of which are
+/// fetched until there are enough.
+pub fn search_documents_filtered(query_str: &str, limit: usize, filter: &Filter) -> tantivy::Result<Vec<(f32, TantivyDocument, Option<Snippet>)>> {
+    let (index, _schema) = get_index_schema()?;
+    search_index_filtered(&index, query_str, limit, filter)
+}
+
+// search_documents_filtered() on an index, so it can be tested in memory.
+fn search_index_filtered(index: &Index, query_str: &str, limit: usize, filter: &Filter) -> tantivy::Result<Vec<(f32, TantivyDocument, Option<Snippet>)>> {
+    let schema = index.schema();
+    let body = schema.get_field("body")?;
+
     let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
     let searcher = reader.searcher();
-    let query = AllQuery;
 
-    let num_docs = 10; // get_num_documents(&index)?; // Should probably be limited...
-    let top_docs = searcher.search(&query, &TopDocs::with_limit(num_docs.try_into().unwrap()))?;
+    let (query, _errors) = QueryParser::for_index(index, search_fields(&schema)).parse_query_lenient(query_str);
+    let snippet_generator = SnippetGenerator::create(&searcher, &*query, body)?;
+    let (query, check_paths) = filter_query(&schema, query, filter)?;
+
+    let mut fetch = if check_paths { limit.max(1) * 4 } else { limit.max(1) };
+    loop {
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(fetch))?;
+        let mut documents = Vec::new();
+        for &(score, doc_address) in &top_docs {
+            if documents.len() >= limit {
+                break;
+            }
+            let retrieved_doc: TantivyDocument = searcher.doc(doc_address)?;
+            if check_paths && !filter.matches_path(doc_text(&schema, &retrieved_doc, "title")) {
+                continue;
+            }
+            let snippet = snippet_generator.snippet_from_doc(&retrieved_doc);
+            documents.push((score, retrieved_doc, Some(snippet)));
+        }
+        if documents.len() >= limit || top_docs.len() < fetch {
+            return Ok(documents);
+        }
+        fetch *= 4;
+    }
+}
+
+/// The stored fields of a document, by name. The hash_body field is
+/// left out, it is the hash of the body. The tags are a list.
+pub fn doc_to_json(schema: &Schema, document: &TantivyDocument) -> serde_json::Map<String, serde_json::Value> {
+    let mut fields = serde_json::Map::new();
+    for (field, entry) in schema.fields() {
+        if !entry.is_stored() || entry.name() == "hash_body" {
+            continue;
+        }
+        if entry.name() == "tag" {
+            let tags: Vec<&str> = document.get_all(field).filter_map(|v| v.as_str()).collect();
+            if !tags.is_empty() {
+                fields.insert("tags".to_string(), tags.into());
+            }
+            continue;
+        }
+        let Some(value) = document.get_first(field) else {
+            continue;
+        };
+        let value = match (value.as_str(), value.as_u64()) {
+            (Some(text), _) => serde_json::Value::from(text),
+            (_, Some(number)) => serde_json::Value::from(number),
+            _ => continue,
+        };
+        fields.insert(entry.name().to_string(), value);
+    }
+    fields
+}
+
+/// The documents of the index, in the order they were added, read one at
+/// a time.
+pub fn iter_documents(index: &Index) -> tantivy::Result<impl Iterator<Item = tantivy::Result<TantivyDocument>>> {
+    let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
+    let searcher = reader.searcher();
+    let mut doc_addresses: Vec<_> = searcher.search(&AllQuery, &DocSetCollector)?.into_iter().collect();
+    doc_addresses.sort();
+    Ok(doc_addresses.into_iter().map(move |doc_address| searcher.doc(doc_address)))
+}
+
+/// The documents in the order they are stored, skipping the first
+/// offset documents whose title passes keep(), and then at most limit.
+pub fn get_documents(offset: usize, limit: Option<usize>, keep: &dyn Fn(&str) -> bool) -> tantivy::Result<Vec<TantivyDocument>> {
+    let (index, schema) = get_index_schema()?;
+    let title = schema.get_field("title")?;
 
-    let snippet_generator = SnippetGenerator::create(&searcher, &query, body)?;
-    
     let mut documents = Vec::new();
-    for (score, doc_address) in top_docs {
-        let retrieved_doc: TantivyDocument = searcher.doc(doc_address)?;
-        //println!("doc: {:?}", retrieved_doc);
-        //println!("{}", retrieved_doc.to_json(&schema));
-        let snippet = snippet_generator.snippet_from_doc(&retrieved_doc); // always ""?
-        //println!("snippet: {:?}", snippet.to_html());
-        //println!("custom highlighting: {}", highlight(&snippet));
-        documents.push((score, retrieved_doc, Some(snippet)));
+    let mut skipped = 0;
+    for retrieved_doc in iter_documents(&index)? {
+        if limit.is_some_and(|limit| documents.len() >= limit) {
+            break;
+        }
+        let retrieved_doc = retrieved_doc?;
+        if !keep(retrieved_doc.get_first(title).and_then(|v| v.as_str()).unwrap_or("")) {
+            continue;
+        }
+        if skipped < offset {
+            skipped += 1;
+            continue;
+        }
+        documents.push(retrieved_doc);
     }
-    
+
     Ok(documents)
 }
 
 pub fn del_all() -> tantivy::Result<()> {
-    let index_path = Path::new("db/tantivy");
-    
-    let schema = &*SCHEMA;  // Ensure SCHEMA is defined and available in scope
-    let directory = MmapDirectory::open(index_path)?;
-    let index = Index::open_or_create(directory, schema.clone())?;
+    let (index, _schema) = get_index_schema()?;
 
     let mut index_writer: IndexWriter = index.writer(50_000_000)?;
     let _clear = index_writer.delete_all_documents();
@@ -436,41 +869,302 @@ pub fn del_all() -> tantivy::Result<()> {
     Ok(())
 }
 
-#[allow(dead_code)]
-pub fn fuzzy_search_documents(query_str: &str) -> tantivy::Result<Vec<(f32, TantivyDocument, Option<Snippet>)>> {
-    let (index, schema) = get_index_schema().unwrap();
-    
-    let reader = index.reader()?;
-    let searcher = reader.searcher();
-    let body_field = schema.get_field("body").unwrap();
-    
-    let term = Term::from_field_text(body_field, query_str);
-    let query = FuzzyTermQuery::new(term, 2, true);
+// BulkWriter::delete_path() for indexes without a path field (before
+// version 3). The title is tokenized, so we compare the stored titles
+// and delete on the hash_body term instead.
+fn delete_documents_by_title(schema: &Schema, searcher: &Searcher, index_writer: &IndexWriter, path: &Path) -> tantivy::Result<u64> {
+    let title_field = schema.get_field("title")?;
+    let hash_body_field = schema.get_field("hash_body")?;
 
-    let (top_docs, count) = searcher.search(&query, &(TopDocs::with_limit(3), Count)).unwrap();
-    println!("Count {}", count);
-    let mut documents = Vec::new();
-    for (score, doc_address) in top_docs {
+    let doc_addresses = searcher.search(&AllQuery, &DocSetCollector)?;
+
+    let mut num = 0u64;
+    for doc_address in doc_addresses {
+        let retrieved_doc: TantivyDocument = searcher.doc(doc_address)?;
+        let title = retrieved_doc.get_first(title_field).and_then(|v| v.as_str()).unwrap_or("");
+        if !Path::new(title).starts_with(path) {
+            continue;
+        }
+        if let Some(hash_body) = retrieved_doc.get_first(hash_body_field).and_then(|v| v.as_str()) {
+            index_writer.delete_term(Term::from_field_text(hash_body_field, hash_body));
+            num += 1;
+        }
+    }
+
+    Ok(num)
+}
+
+/// The filenames of the chunks in the index.
+pub fn get_filenames(index: &Index) -> tantivy::Result<BTreeSet<String>> {
+    let title_field = index.schema().get_field("title")?;
+    let searcher = index.reader()?.searcher();
+    let mut filenames = BTreeSet::new();
+    for doc_address in searcher.search(&AllQuery, &DocSetCollector)? {
         let retrieved_doc: TantivyDocument = searcher.doc(doc_address)?;
-        documents.push((score, retrieved_doc, None)); // No snippets in fuzzy search.
+        if let Some(title) = retrieved_doc.get_first(title_field).and_then(|v| v.as_str()) {
+            filenames.insert(title.to_string());
+        }
     }
+    Ok(filenames)
+}
 
-    Ok(documents)
+// The chunks of one file, by chunk number. The title is tokenized, so
+// the documents are found with a phrase query on its tokens, and the
+// stored title is compared to leave out files with similar names.
+pub fn get_file_chunks(index: &Index, filename: &str) -> tantivy::Result<BTreeMap<u64, String>> {
+    let schema = index.schema();
+    let title_field = schema.get_field("title")?;
+    let body_field = schema.get_field("body")?;
+    let chunk_number_field = schema.get_field("chunk_number")?;
+
+    let mut tokenizer = index.tokenizer_for_field(title_field)?;
+    let mut token_stream = tokenizer.token_stream(filename);
+    let mut terms = vec![];
+    while let Some(token) = token_stream.next() {
+        terms.push((token.position, Term::from_field_text(title_field, &token.text)));
+    }
+    let query: Box<dyn Query> = match terms.len() {
+        0 => return Ok(BTreeMap::new()),
+        1 => Box::new(TermQuery::new(terms[0].1.clone(), IndexRecordOption::Basic)),
+        _ => Box::new(PhraseQuery::new_with_offset(terms)),
+    };
+
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+    let mut chunks = BTreeMap::new();
+    for doc_address in searcher.search(&query, &DocSetCollector)? {
+        let retrieved_doc: TantivyDocument = searcher.doc(doc_address)?;
+        if retrieved_doc.get_first(title_field).and_then(|v| v.as_str()) != Some(filename) {
+            continue;
+        }
+        let number = retrieved_doc.get_first(chunk_number_field).and_then(|v| v.as_u64()).unwrap_or(0);
+        let body = retrieved_doc.get_first(body_field).and_then(|v| v.as_str()).unwrap_or("");
+        chunks.insert(number, body.to_string());
+    }
+    Ok(chunks)
 }
 
-#[allow(dead_code)]
-fn highlight(snippet: &Snippet) -> String {
+/// The fragment of the snippet, with the matching words in **bold**.
+pub fn highlight(snippet: &Snippet) -> String {
     let mut result = String::new();
     let mut start_from = 0;
 
     for fragment_range in snippet.highlighted() {
         result.push_str(&snippet.fragment()[start_from..fragment_range.start]);
-        result.push_str(" --> ");
+        result.push_str("**");
         result.push_str(&snippet.fragment()[fragment_range.clone()]);
-        result.push_str(" <-- ");
+        result.push_str("**");
         start_from = fragment_range.end;
     }
 
     result.push_str(&snippet.fragment()[start_from..]);
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn languages(codes: &str) -> Vec<String> {
+        parse_languages(codes).unwrap()
+    }
+
+    fn chunk(filename: &str, page: Option<u64>, text: &str) -> Chunk {
+        Chunk { filename: filename.to_string(), number: 0, page, section: None, text: text.to_string() }
+    }
+
+    #[test]
+    fn detect_swedish_and_english() {
+        let both = languages("sv,en");
+        assert_eq!(detect_language("Katterna sover i soffan och de är svarta.", &both), Some("sv"));
+        assert_eq!(detect_language("The cats are sleeping on the sofa, and they are black.", &both), Some("en"));
+        assert_eq!(detect_language("Sirius", &both), Some("sv"));
+        assert_eq!(detect_language("The cats", &languages("sv")), Some("sv"));
+        assert!(parse_languages("sv,klingon").is_err());
+    }
+
+    #[test]
+    fn stemmed_body_fields() {
+        let settings = TextIndexSettings::current(&languages("sv,en"));
+        let index = Index::create_in_ram(build_schema(&settings));
+        register_analyzers(&index, &settings);
+        let schema = index.schema();
+        let mut index_writer: IndexWriter = index.writer(15_000_000).unwrap();
+        index_writer.add_document(chunk_document(&schema, &chunk("a.txt", None, "Katten är svart och den sover."), "h0", &FileEntry::default())).unwrap();
+        index_writer.add_document(chunk_document(&schema, &chunk("b.txt", None, "The cat is sleeping on the mat."), "h1", &FileEntry::default())).unwrap();
+        index_writer.commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let query_parser = QueryParser::for_index(&index, search_fields(&schema));
+        let hits = |q: &str| {
+            let query = query_parser.parse_query(q).unwrap();
+            let mut titles: Vec<String> = searcher.search(&query, &TopDocs::with_limit(10)).unwrap().into_iter()
+                .map(|(_, a)| searcher.doc::<TantivyDocument>(a).unwrap().get_first(schema.get_field("title").unwrap()).unwrap().as_str().unwrap().to_string())
+                .collect();
+            titles.sort();
+            titles
+        };
+        assert_eq!(hits("katter"), vec!["a.txt"]);
+        assert_eq!(hits("cats"), vec!["b.txt"]);
+        assert_eq!(hits("sleep"), vec!["b.txt"]);
+    }
+
+    #[test]
+    fn highlighted_stemmed_snippets() {
+        let settings = TextIndexSettings::current(&languages("sv"));
+        let index = Index::create_in_ram(build_schema(&settings));
+        register_analyzers(&index, &settings);
+        let schema = index.schema();
+        let body = "Katten är svart.";
+        let query = QueryParser::for_index(&index, search_fields(&schema)).parse_query("katter").unwrap();
+        let mut index_writer: IndexWriter = index.writer(15_000_000).unwrap();
+        index_writer.add_document(chunk_document(&schema, &chunk("a.txt", None, body), "h0", &FileEntry::default())).unwrap();
+        index_writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let generator = SnippetGenerator::create(&searcher, &*query, schema.get_field("body_sv").unwrap()).unwrap();
+        assert!(highlight(&generator.snippet(body)).starts_with("**Katten** är svart"));
+    }
+
+    #[test]
+    fn bulk_writer_skips_duplicates() {
+        let settings = TextIndexSettings::current(&languages("en"));
+        let index = Index::create_in_ram(build_schema(&settings));
+        register_analyzers(&index, &settings);
+        let chunk = |filename: &str, number, text: &str| Chunk {
+            filename: filename.to_string(), number, page: None, section: None, text: text.to_string(),
+        };
+        let mut writer = BulkWriter::new(index.clone()).unwrap();
+        let chunks = vec![chunk("a.txt", 0, "Sirius is black."), chunk("a.txt", 1, "Sirius is black.")];
+        assert_eq!(writer.insert_chunks(&chunks, &FileEntry::default()).unwrap(), 1);
+        assert_eq!(writer.insert_chunks(&[chunk("./a.txt", 2, "Sirius is black.")], &FileEntry::default()).unwrap(), 0);
+        assert_eq!(writer.insert_chunks(&[chunk("b.txt", 0, "Sirius is black.")], &FileEntry::default()).unwrap(), 1);
+        writer.commit().unwrap();
+        assert_eq!(writer.insert_chunks(&chunks, &FileEntry::default()).unwrap(), 0);
+        writer.delete_path(Path::new("a.txt")).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(get_filenames(&index).unwrap().into_iter().collect::<Vec<_>>(), vec!["b.txt"]);
+        assert_eq!(writer.insert_chunks(&chunks, &FileEntry::default()).unwrap(), 1);
+        writer.commit().unwrap();
+        assert_eq!(get_num_documents(&index).unwrap(), 2);
+        // Replaced without a commit in between.
+        writer.delete_path(Path::new("a.txt")).unwrap();
+        assert_eq!(writer.insert_chunks(&chunks, &FileEntry::default()).unwrap(), 1);
+        writer.commit().unwrap();
+        assert_eq!(get_num_documents(&index).unwrap(), 2);
+    }
+
+    #[test]
+    fn legacy_bulk_writer_skips_duplicates_per_file() {
+        let index = Index::create_in_ram(build_schema(&TextIndexSettings::legacy()));
+        let chunk = |filename: &str, text: &str| Chunk {
+            filename: filename.to_string(), number: 0, page: None, section: None, text: text.to_string(),
+        };
+        let mut writer = BulkWriter::new(index.clone()).unwrap();
+        assert_eq!(writer.insert_chunks(&[chunk("a.txt", "Sirius is black.")], &FileEntry::default()).unwrap(), 1);
+        writer.commit().unwrap();
+        assert_eq!(writer.insert_chunks(&[chunk("a.txt", "Sirius is black.")], &FileEntry::default()).unwrap(), 0);
+        assert_eq!(writer.insert_chunks(&[chunk("b.txt", "Sirius is black.")], &FileEntry::default()).unwrap(), 1);
+    }
+
+    #[test]
+    fn delete_file_or_directory() {
+        let settings = TextIndexSettings::current(&languages("en"));
+        let index = Index::create_in_ram(build_schema(&settings));
+        register_analyzers(&index, &settings);
+        let chunk = |filename: &str, text: &str| Chunk {
+            filename: filename.to_string(), number: 0, page: None, section: None, text: text.to_string(),
+        };
+        let mut writer = BulkWriter::new(index.clone()).unwrap();
+        writer.insert_chunks(&[chunk("texts/a.txt", "One."), chunk("texts/ab.txt", "Two."),
+                               chunk("texts/sub/c.txt", "Three."), chunk("other/d.txt", "Four.")], &FileEntry::default()).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(writer.delete_path(Path::new("texts/a.txt")).unwrap(), 1);
+        assert_eq!(writer.delete_path(Path::new("./texts/sub/")).unwrap(), 1);
+        writer.commit().unwrap();
+        assert_eq!(get_filenames(&index).unwrap().into_iter().collect::<Vec<_>>(), vec!["other/d.txt", "texts/ab.txt"]);
+        assert_eq!(writer.delete_path(Path::new("texts")).unwrap(), 1);
+    }
+
+    #[test]
+    fn filter_on_paths_dates_and_tags() {
+        let settings = TextIndexSettings::current(&languages("en"));
+        let index = Index::create_in_ram(build_schema(&settings));
+        register_analyzers(&index, &settings);
+        let schema = index.schema();
+        let file = |date: &str, tags: &[&str]| FileEntry {
+            date: date.to_string(),
+            tags: crate::filter::parse_tags(&tags.iter().map(|t| t.to_string()).collect::<Vec<_>>()).unwrap(),
+            ..Default::default()
+        };
+        let mut index_writer: IndexWriter = index.writer(15_000_000).unwrap();
+        index_writer.add_document(chunk_document(&schema, &chunk("lab/a.txt", None, "Sirius."), "h0", &file("20240512T1003", &["team=lab"]))).unwrap();
+        index_writer.add_document(chunk_document(&schema, &chunk("lab/b.pdf", Some(1), "Maja."), "h1", &file("20240531T2359", &["team=lab", "project=x"]))).unwrap();
+        index_writer.add_document(chunk_document(&schema, &chunk("other/c.txt", None, "Rörum."), "h2", &file("20240601T0000", &[]))).unwrap();
+        index_writer.commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let titles = |filter: Filter| {
+            let (query, check_paths) = filter_query(&schema, Box::new(AllQuery), &filter).unwrap();
+            let mut titles: Vec<String> = searcher.search(&query, &TopDocs::with_limit(10)).unwrap().into_iter()
+                .map(|(_, a)| doc_text(&schema, &searcher.doc::<TantivyDocument>(a).unwrap(), "title").to_string())
+                .filter(|title| !check_paths || filter.matches_path(title))
+                .collect();
+            titles.sort();
+            titles
+        };
+        let filter = |paths: &[&str], since, until, tags: &[&str]| {
+            let paths: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+            let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+            Filter::new(&paths, since, until, &tags).unwrap()
+        };
+        assert_eq!(titles(filter(&["lab/"], None, None, &[])), vec!["lab/a.txt", "lab/b.pdf"]);
+        assert_eq!(titles(filter(&["**/*.pdf", "other"], None, None, &[])), vec!["lab/b.pdf", "other/c.txt"]);
+        assert_eq!(titles(filter(&[], Some("2024-05-13"), Some("2024-05-31"), &[])), vec!["lab/b.pdf"]);
+        assert_eq!(titles(filter(&[], None, Some("2024-05-31"), &["team=lab"])), vec!["lab/a.txt", "lab/b.pdf"]);
+        assert_eq!(titles(filter(&["other"], None, None, &["project=x"])), Vec::<String>::new());
+        assert!(filter_query(&build_schema(&TextIndexSettings::legacy()), Box::new(AllQuery), &filter(&[], None, None, &["team=lab"])).is_err());
+    }
+
+    #[test]
+    fn filtered_search_is_lenient() {
+        let settings = TextIndexSettings::current(&languages("en"));
+        let index = Index::create_in_ram(build_schema(&settings));
+        register_analyzers(&index, &settings);
+        let schema = index.schema();
+        let file = FileEntry { tags: [("team".to_string(), "lab".to_string())].into(), ..Default::default() };
+        let mut index_writer: IndexWriter = index.writer(15_000_000).unwrap();
+        index_writer.add_document(chunk_document(&schema, &chunk("lab/a.txt", None, "What is on at 10.30, sic."), "h0", &file)).unwrap();
+        index_writer.add_document(chunk_document(&schema, &chunk("other/b.txt", None, "What is on at noon."), "h1", &FileEntry::default())).unwrap();
+        index_writer.commit().unwrap();
+        let filter = Filter::new(&[], None, None, &["team=lab".to_string()]).unwrap();
+        let hits = search_index_filtered(&index, "what is 10:30 (sic", 5, &filter).unwrap();
+        let titles: Vec<&str> = hits.iter().map(|(_, d, _)| doc_text(&schema, d, "title")).collect();
+        assert_eq!(titles, vec!["lab/a.txt"]);
+    }
+
+    #[test]
+    fn legacy_schema_has_no_language_fields() {
+        let schema = build_schema(&TextIndexSettings::legacy());
+        assert!(schema.get_field("language").is_err());
+        assert!(body_language_fields(&schema).is_empty());
+        let document = chunk_document(&schema, &chunk("a.txt", None, "Katten sover."), "h0", &FileEntry::default());
+        assert_eq!(document.field_values().len(), 5);
+    }
+
+    #[test]
+    fn sections_are_stored() {
+        let settings = TextIndexSettings::current(&languages("sv"));
+        let index = Index::create_in_ram(build_schema(&settings));
+        register_analyzers(&index, &settings);
+        let schema = index.schema();
+        let section = Chunk { section: Some("# Katter".to_string()), ..chunk("a.md", None, "Katten sover.") };
+        let mut index_writer: IndexWriter = index.writer(15_000_000).unwrap();
+        index_writer.add_document(chunk_document(&schema, &section, "h0", &FileEntry::default())).unwrap();
+        index_writer.add_document(chunk_document(&schema, &chunk("b.txt", None, "Sirius."), "h1", &FileEntry::default())).unwrap();
+        index_writer.commit().unwrap();
+        let sections: Vec<String> = iter_documents(&index).unwrap()
+            .map(|d| doc_text(&schema, &d.unwrap(), "section").to_string())
+            .collect();
+        assert_eq!(sections, vec!["# Katter", ""]);
+    }
+}
@@ -22,6 +22,7 @@ use lazy_static::lazy_static;
 use serde_json::json;
 use tokenizers::Tokenizer;
 use candle_core::utils::{cuda_is_available, metal_is_available};
+use crate::generator::{Generator, GenOptions, Message, PromptTemplate};
 
 // https://github.com/huggingface/candle/blob/main/candle-examples/src/lib.rs
 pub fn device(cpu: bool) -> Result<Device> {
@@ -136,7 +137,7 @@ impl TextGeneration {
         }
     }
 
-    fn run(&mut self, prompt: &str, sample_len: usize) -> Result<String> {
+    fn run(&mut self, prompt: &str, sample_len: usize, on_token: &mut dyn FnMut(&str)) -> Result<String> {
         //debug!(prompt = prompt, "starting the inference loop");
         let tokens = self.tokenizer.encode(prompt, true).map_err(E::msg)?;
         if tokens.is_empty() {
@@ -197,6 +198,7 @@ impl TextGeneration {
             }
             let token = self.tokenizer.decode(&[next_token], true).map_err(E::msg)?;
             //println!("{}", token); // PJB
+            on_token(&token);
             response += &token;
         }
         let dt = start_gen.elapsed();
@@ -253,7 +255,53 @@ pub fn generate_answer(query: &str, references: &Vec<String>) -> Result<String>
         64,
         &device(false)?,
     );
-    let response = pipeline.run(&prompt, 400)?; // 400...
+    let response = pipeline.run(&prompt, 400, &mut |_| {})?; // 400...
 
     Ok(response)
 }
+
+// The model is loaded once, in the PHI lazy static.
+#[derive(Default)]
+pub struct PhiGenerator;
+
+impl PhiGenerator {
+    pub fn new() -> Self {
+        PhiGenerator
+    }
+}
+
+impl Generator for PhiGenerator {
+    fn name(&self) -> String {
+        "AI-Sweden-Models/gpt-sw3-6.7b-v2-instruct-gguf | gpt-sw3-6.7b-v2-instruct-Q4_K_M.gguf".to_string()
+    }
+
+    fn count_tokens(&self, messages: &[Message]) -> Option<usize> {
+        let (_model, tokenizer) = &*PHI;
+        let prompt = PromptTemplate::ChatML.apply(messages);
+        tokenizer.encode(prompt, true).ok().map(|tokens| tokens.len())
+    }
+
+    fn context_size(&self) -> Option<usize> {
+        Some(2048)
+    }
+
+    fn default_max_tokens(&self) -> usize {
+        400
+    }
+
+    fn generate(&mut self, messages: &[Message], opts: &GenOptions, on_token: &mut dyn FnMut(&str)) -> Result<String> {
+        let (model, tokenizer) = &*PHI;
+        let prompt = PromptTemplate::ChatML.apply(messages);
+        let mut pipeline = TextGeneration::new(
+            model.clone(),
+            tokenizer.clone(),
+            opts.seed.unwrap_or(28),
+            Some(opts.temperature.unwrap_or(0.3)),
+            opts.top_p,
+            opts.repeat_penalty.unwrap_or(1.1),
+            64,
+            &device(false)?,
+        );
+        pipeline.run(&prompt, opts.max_tokens.unwrap_or(self.default_max_tokens()), on_token)
+    }
+}